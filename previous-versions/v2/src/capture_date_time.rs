@@ -0,0 +1,129 @@
+use anyhow::bail;
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+/// When a photo was taken: the local date-time of the camera's clock, and its offset from UTC when that is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureDateTime {
+    /// The local date-time where the photo was taken
+    pub local: NaiveDateTime,
+    /// The offset of `local` from UTC (e.g. from the EXIF `OffsetTimeOriginal`), if known
+    pub offset: Option<FixedOffset>,
+}
+
+impl CaptureDateTime {
+    /// A local date-time, with its offset from UTC if known.
+    pub fn new(local: NaiveDateTime, offset: Option<FixedOffset>) -> CaptureDateTime {
+        CaptureDateTime { local, offset }
+    }
+
+    /// An instant that was not recorded in the local time where the photo was taken (e.g. a file's
+    /// modified time). Its local date-time is in the timezone of this computer.
+    pub fn from_instant(instant: DateTime<Utc>) -> CaptureDateTime {
+        CaptureDateTime::from_instant_in(instant, &Local)
+    }
+
+    /// An instant, as the local date-time in a timezone (e.g. the timezone where the photo was taken).
+    pub fn from_instant_in<Z: TimeZone>(instant: DateTime<Utc>, time_zone: &Z) -> CaptureDateTime {
+        let local = instant.with_timezone(time_zone);
+        CaptureDateTime {
+            local: local.naive_local(),
+            offset: Some(local.offset().fix()),
+        }
+    }
+
+    /// The instant the photo was taken, if its offset is known.
+    pub fn to_utc(self) -> Option<DateTime<Utc>> {
+        let offset = self.offset?;
+        let utc = self.local - Duration::seconds(offset.local_minus_utc() as i64);
+        Some(Utc.from_utc_datetime(&utc))
+    }
+
+    /// The date-time in the timezone of organized paths. A date-time without an offset is assumed to be
+    /// in the home timezone, or in the timezone of this computer when converting to UTC.
+    pub fn in_time_zone(&self, time_zone: &PathTimeZone) -> NaiveDateTime {
+        match time_zone {
+            PathTimeZone::Capture => self.local,
+            PathTimeZone::Utc => self.to_instant(&Local).naive_utc(),
+            PathTimeZone::Home(home) => self.to_instant(home).with_timezone(home).naive_local(),
+        }
+    }
+
+    /// The instant the photo was taken, assuming `assumed_zone` when the offset is not known.
+    #[doc(hidden)]
+    fn to_instant<Z: TimeZone>(self, assumed_zone: &Z) -> DateTime<Utc> {
+        if let Some(utc) = self.to_utc() {
+            return utc;
+        }
+
+        // a local time that is skipped by a daylight saving change is kept as it is
+        match assumed_zone.from_local_datetime(&self.local).earliest() {
+            Some(date_time) => date_time.with_timezone(&Utc),
+            None => Utc.from_utc_datetime(&self.local),
+        }
+    }
+}
+
+impl fmt::Display for CaptureDateTime {
+    /// Formats as `2019-07-04 12:34:56.123 -07:00`, or without the offset when it is not known.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.local.format("%Y-%m-%d %H:%M:%S%.f"))?;
+        match self.offset {
+            Some(offset) => write!(f, " {}", offset),
+            None => write!(f, " (no offset)"),
+        }
+    }
+}
+
+/// The timezone of the date-times in organized paths.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum PathTimeZone {
+    /// The local time where each photo was taken
+    #[default]
+    Capture,
+    /// UTC
+    Utc,
+    /// A home timezone (e.g. `America/New_York`), so that photos taken while traveling are named in home time
+    Home(Tz),
+}
+
+impl FromStr for PathTimeZone {
+    type Err = anyhow::Error;
+
+    /// Parses `capture`, `utc`, or the IANA name of a home timezone (e.g. `Europe/Paris`).
+    fn from_str(text: &str) -> anyhow::Result<PathTimeZone> {
+        match text {
+            "capture" => Ok(PathTimeZone::Capture),
+            "utc" | "UTC" => Ok(PathTimeZone::Utc),
+            _ => match text.parse::<Tz>() {
+                Ok(home) => Ok(PathTimeZone::Home(home)),
+                Err(_) => bail!(
+                    "Unknown time zone '{}' (expected capture, utc, or a timezone name such as America/New_York).",
+                    text
+                ),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for PathTimeZone {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<PathTimeZone> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for PathTimeZone {
+    /// Formats the time zone as it is configured.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathTimeZone::Capture => write!(f, "capture"),
+            PathTimeZone::Utc => write!(f, "utc"),
+            PathTimeZone::Home(home) => write!(f, "{}", home.name()),
+        }
+    }
+}
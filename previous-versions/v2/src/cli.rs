@@ -0,0 +1,280 @@
+use crate::capture_date_time::*;
+use crate::config::*;
+use crate::file_transfer::*;
+use crate::name_pattern::*;
+use crate::path_template::*;
+use crate::photo_date_time::*;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Every file was handled.
+pub const EXIT_SUCCESS: u8 = 0;
+/// The command ran, but some files could not be handled (or it was stopped early).
+pub const EXIT_PARTIAL: u8 = 1;
+/// The command could not run (e.g. a bad config or bad arguments).
+pub const EXIT_FATAL: u8 = 2;
+
+/// A very fast photo organizer.
+#[derive(Parser, Debug)]
+#[command(name = "photiso", version)]
+pub struct Cli {
+    /// The config file to use instead of the nearest photiso.toml (also PHOTISO_CONFIG)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// The profiles to use, in order (e.g. --profile family,work); without one, [default] is used
+    #[arg(
+        long,
+        short = 'p',
+        global = true,
+        value_name = "NAME",
+        value_delimiter = ',',
+        conflicts_with = "all_profiles"
+    )]
+    pub profile: Vec<String>,
+
+    /// Use every profile, in order
+    #[arg(long, global = true)]
+    pub all_profiles: bool,
+
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Organize the unorganized directory (the default command)
+    Organize,
+    /// Show what organize would do, without moving any files
+    Plan,
+    /// Check that the files recorded in the journal are unchanged at their organized locations
+    Verify,
+    /// Move the files recorded in the journal back to where they came from
+    Undo,
+    /// Move the duplicate photos of the organized directory to the duplicates directory
+    Dedupe,
+    /// Show what is read from files: format, date-times, camera, and hash
+    Inspect {
+        /// The files to inspect
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Also print every EXIF field
+        #[arg(long)]
+        exif: bool,
+    },
+    /// Compute the clock correction of a camera from two photos taken at the same moment by two cameras
+    ClockOffset {
+        /// A photo from the camera whose clock is right
+        reference: PathBuf,
+        /// A photo from the camera whose clock is wrong, taken at the same moment
+        photo: PathBuf,
+    },
+    /// Work with the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Check the config (and flags) and print the resulting settings
+    Check,
+}
+
+/// Flags that override the config file. Each one matches a config field.
+#[derive(Args, Debug)]
+pub struct ConfigOverrides {
+    /// The directory of photos to organize
+    #[arg(long, global = true, value_name = "DIR")]
+    pub unorganized: Option<PathBuf>,
+    /// A directory of photos to organize, instead of the configured sources (can be repeated)
+    #[arg(long, global = true, value_name = "DIR")]
+    pub source: Vec<PathBuf>,
+    /// The directory photos are organized into
+    #[arg(long, global = true, value_name = "DIR")]
+    pub organized: Option<PathBuf>,
+    /// The directory duplicate photos are moved into
+    #[arg(long, global = true, value_name = "DIR")]
+    pub duplicates: Option<PathBuf>,
+
+    /// How progress is printed: default, compact, summary, or none
+    #[arg(long, global = true, value_name = "OUTPUT")]
+    pub output: Option<Output>,
+    /// Stop at the first file error
+    #[arg(
+        long,
+        global = true,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub stop_on_error: Option<bool>,
+    /// Show what would happen without moving any files
+    #[arg(
+        long,
+        global = true,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub plan: Option<bool>,
+    /// The journal of moves, used by undo and verify
+    #[arg(long, global = true, value_name = "PATH")]
+    pub journal: Option<PathBuf>,
+    /// The path template of organized photos
+    #[arg(
+        long,
+        global = true,
+        value_name = "TEMPLATE",
+        value_parser = PathTemplate::parse
+    )]
+    pub organized_template: Option<PathTemplate>,
+    /// The path template of duplicate photos
+    #[arg(
+        long,
+        global = true,
+        value_name = "TEMPLATE",
+        value_parser = PathTemplate::parse
+    )]
+    pub duplicates_template: Option<PathTemplate>,
+    /// The number of threads reading photos (0 uses every core)
+    #[arg(long, global = true, value_name = "N")]
+    pub threads: Option<usize>,
+    /// The hash index of the organized directory
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with = "no_hash_index"
+    )]
+    pub hash_index: Option<PathBuf>,
+    /// Do not use a hash index, even if the config has one
+    #[arg(long, global = true)]
+    pub no_hash_index: bool,
+    /// Give photos the extension of their contents
+    #[arg(
+        long,
+        global = true,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub fix_extensions: Option<bool>,
+    /// How photos get to their organized location: move, copy, hardLink, reflink, or symlink
+    #[arg(long, global = true, value_name = "MODE")]
+    pub mode: Option<TransferMode>,
+    /// In copy mode, delete each original once its copy is verified
+    #[arg(
+        long,
+        global = true,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub delete_source: Option<bool>,
+    /// A glob of the files to organize, instead of the configured ones (can be repeated)
+    #[arg(long, global = true, value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// A glob of the files and directories to skip, instead of the configured ones (can be repeated)
+    #[arg(long, global = true, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// The timezone of the date-times in organized paths: capture, utc, or a timezone name (e.g. Europe/Paris)
+    #[arg(long, global = true, value_name = "ZONE")]
+    pub time_zone: Option<PathTimeZone>,
+    /// A pattern of the date-time in file names, instead of the configured ones (can be repeated)
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATTERN",
+        value_parser = NamePattern::parse
+    )]
+    pub name_pattern: Vec<NamePattern>,
+    /// The sources of the date-times that organize photos, in order (e.g. exifOriginal,fileName,fileModified)
+    #[arg(long, global = true, value_name = "SOURCE", value_delimiter = ',')]
+    pub date_sources: Vec<DateSource>,
+}
+
+impl ConfigOverrides {
+    /// Replaces the config values that were given as flags.
+    pub fn apply(&self, config: &mut Config) {
+        let directories = &mut config.directories;
+        if !self.source.is_empty() {
+            directories.unorganized = PathBuf::new();
+            directories.sources = self
+                .source
+                .iter()
+                .map(|source| ConfigSource::new(source))
+                .collect();
+        }
+        if let Some(unorganized) = &self.unorganized {
+            directories.unorganized = unorganized.clone();
+        }
+        if let Some(organized) = &self.organized {
+            directories.organized = organized.clone();
+        }
+        if let Some(duplicates) = &self.duplicates {
+            directories.duplicates = duplicates.clone();
+        }
+
+        let options = &mut config.options;
+        if let Some(output) = self.output {
+            options.output = output;
+        }
+        if let Some(stop_on_error) = self.stop_on_error {
+            options.stop_on_error = stop_on_error;
+        }
+        if let Some(plan) = self.plan {
+            options.plan = plan;
+        }
+        if let Some(journal) = &self.journal {
+            options.journal = Some(journal.clone());
+        }
+        if let Some(organized_template) = &self.organized_template {
+            options.organized_template = organized_template.clone();
+        }
+        if let Some(duplicates_template) = &self.duplicates_template {
+            options.duplicates_template = duplicates_template.clone();
+        }
+        if let Some(threads) = self.threads {
+            options.threads = threads;
+        }
+        if let Some(hash_index) = &self.hash_index {
+            options.hash_index = Some(hash_index.clone());
+        }
+        if self.no_hash_index {
+            options.hash_index = None;
+        }
+        if let Some(fix_extensions) = self.fix_extensions {
+            options.fix_extensions = fix_extensions;
+        }
+        if let Some(mode) = self.mode {
+            options.mode = mode;
+        }
+        if let Some(delete_source) = self.delete_source {
+            options.delete_source = delete_source;
+        }
+        if let Some(time_zone) = self.time_zone {
+            options.time_zone = time_zone;
+        }
+        if !self.include.is_empty() {
+            options.include = self.include.clone();
+        }
+        if !self.exclude.is_empty() {
+            options.exclude = self.exclude.clone();
+        }
+        if !self.name_pattern.is_empty() {
+            options.name_patterns = self.name_pattern.clone();
+        }
+        if !self.date_sources.is_empty() {
+            options.date_sources = self.date_sources.clone();
+        }
+    }
+}
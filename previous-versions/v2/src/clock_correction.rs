@@ -0,0 +1,237 @@
+use anyhow::{bail, ensure};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+/// A correction of a camera whose clock was wrong (`[[options.clockCorrections]]`).
+/// The date-times from the clock of a matching camera are shifted by the offset before they name the photo.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ClockCorrection {
+    /// The camera make (e.g. `Canon`), if the rule only applies to it
+    #[serde(default)]
+    pub make: Option<String>,
+    /// The camera model (e.g. `Canon EOS R5`), if the rule only applies to it
+    #[serde(default)]
+    pub model: Option<String>,
+    /// The serial number of the camera body, if the rule only applies to it
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// The first date (or date-time) of the camera's clock the rule applies to
+    #[serde(default)]
+    pub from: Option<ClockDate>,
+    /// The last date (or date-time) of the camera's clock the rule applies to
+    #[serde(default)]
+    pub until: Option<ClockDate>,
+    /// What is added to the camera's clock (e.g. `+01:00` when it was an hour behind)
+    pub offset: ClockOffset,
+}
+
+/// What a clock correction is matched against.
+pub struct ClockCamera<'a> {
+    pub make: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub serial: Option<&'a str>,
+}
+
+impl ClockCorrection {
+    /// Tests if the rule applies to a camera at a date-time of its (wrong) clock.
+    /// Cameras are compared ignoring case and surrounding spaces.
+    pub fn matches(&self, camera: &ClockCamera, date_time: &NaiveDateTime) -> bool {
+        let is_match = |expected: &Option<String>, actual: Option<&str>| match (expected, actual) {
+            (None, _) => true,
+            (Some(expected), Some(actual)) => expected.trim().eq_ignore_ascii_case(actual.trim()),
+            (Some(_), None) => false,
+        };
+
+        is_match(&self.make, camera.make)
+            && is_match(&self.model, camera.model)
+            && is_match(&self.serial, camera.serial)
+            && self
+                .from
+                .as_ref()
+                .is_none_or(|from| *date_time >= from.start)
+            && self
+                .until
+                .as_ref()
+                .is_none_or(|until| *date_time < until.end)
+    }
+
+    /// Checks a rule that cannot be checked one value at a time.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let (Some(from), Some(until)) = (&self.from, &self.until) {
+            ensure!(
+                from.start < until.end,
+                "The clock correction '{}' ends before it starts.",
+                self
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ClockCorrection {
+    /// Formats as `Canon Canon EOS R5 (serial 012345) from 2023-05-01 until 2023-05-03: +01:00:00`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let camera: Vec<&str> = [&self.make, &self.model]
+            .iter()
+            .filter_map(|value| value.as_deref())
+            .collect();
+        if camera.is_empty() {
+            write!(f, "any camera")?;
+        } else {
+            write!(f, "{}", camera.join(" "))?;
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, " (serial {})", serial)?;
+        }
+        if let Some(from) = &self.from {
+            write!(f, " from {}", from)?;
+        }
+        if let Some(until) = &self.until {
+            write!(f, " until {}", until)?;
+        }
+        write!(f, ": {}", self.offset)
+    }
+}
+
+/// A date (e.g. `2023-05-01`, the whole day) or a date-time (e.g. `2023-05-01 14:30:00`) of a camera's clock.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ClockDate {
+    text: String,
+    /// The first date-time of the date (or the date-time)
+    start: NaiveDateTime,
+    /// The date-time just after the date (or the second of the date-time)
+    end: NaiveDateTime,
+}
+
+impl FromStr for ClockDate {
+    type Err = anyhow::Error;
+
+    /// Parses `YYYY-MM-DD`, `YYYY-MM-DD HH:MM`, or `YYYY-MM-DD HH:MM:SS` (a `T` may separate the date and time).
+    fn from_str(text: &str) -> anyhow::Result<ClockDate> {
+        let normalized = text.trim().replacen('T', " ", 1);
+
+        if let Ok(date) = NaiveDate::parse_from_str(&normalized, "%Y-%m-%d") {
+            if let (Some(start), Some(next)) = (date.and_hms_opt(0, 0, 0), date.succ_opt()) {
+                return Ok(ClockDate {
+                    text: text.to_string(),
+                    start,
+                    end: next.and_hms_opt(0, 0, 0).unwrap_or(start),
+                });
+            }
+        }
+
+        for (format, precision) in [
+            ("%Y-%m-%d %H:%M:%S", Duration::seconds(1)),
+            ("%Y-%m-%d %H:%M", Duration::minutes(1)),
+        ] {
+            if let Ok(start) = NaiveDateTime::parse_from_str(&normalized, format) {
+                return Ok(ClockDate {
+                    text: text.to_string(),
+                    start,
+                    end: start + precision,
+                });
+            }
+        }
+
+        bail!(
+            "Invalid date '{}' (expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS).",
+            text
+        )
+    }
+}
+
+impl TryFrom<String> for ClockDate {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<ClockDate> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for ClockDate {
+    /// Formats the date as it is configured.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// How far a camera's clock is off: `+HH:MM` or `+HH:MM:SS` (or `-`), where the hours may be more than 24.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ClockOffset(pub Duration);
+
+impl ClockOffset {
+    /// The offset between two readings of clocks taken at the same moment, to the nearest second.
+    pub fn between(wrong: &NaiveDateTime, right: &NaiveDateTime) -> ClockOffset {
+        let milliseconds = (*right - *wrong).num_milliseconds();
+        let seconds = (milliseconds as f64 / 1000.0).round() as i64;
+        ClockOffset(Duration::seconds(seconds))
+    }
+}
+
+impl FromStr for ClockOffset {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<ClockOffset> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid clock offset '{}' (expected +HH:MM or -HH:MM:SS, e.g. +01:00).",
+                text
+            )
+        };
+
+        let trimmed = text.trim();
+        let (sign, rest) = match trimmed.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+        };
+
+        let parts: Vec<i64> = rest
+            .split(':')
+            .map(|part| match part.len() {
+                1..=6 if part.bytes().all(|b| b.is_ascii_digit()) => part.parse().ok(),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+
+        let seconds = match parts.as_slice() {
+            [hours, minutes] if *minutes < 60 => hours * 3600 + minutes * 60,
+            [hours, minutes, seconds] if *minutes < 60 && *seconds < 60 => {
+                hours * 3600 + minutes * 60 + seconds
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(ClockOffset(Duration::seconds(sign * seconds)))
+    }
+}
+
+impl TryFrom<String> for ClockOffset {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<ClockOffset> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for ClockOffset {
+    /// Formats as `+01:00:00` or `-00:59:58`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0.num_seconds();
+        let sign = if seconds < 0 { '-' } else { '+' };
+        let seconds = seconds.abs();
+        write!(
+            f,
+            "{}{:02}:{:02}:{:02}",
+            sign,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
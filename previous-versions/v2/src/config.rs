@@ -1,54 +1,914 @@
+use crate::capture_date_time::*;
+use crate::clock_correction::*;
+use crate::file_transfer::*;
+use crate::name_pattern::*;
+use crate::path_template::*;
+use crate::photo_date_time::*;
+use crate::protection::*;
+use anyhow::{bail, ensure, Context};
+use globset::Glob;
 use serde::Deserialize;
-use std::{fs::File, io, io::Read, path::Path, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
-#[derive(Clone, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
+/// The name of config files.
+pub const CONFIG_FILE_NAME: &str = "photiso.toml";
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
 pub struct ConfigDirectories {
     pub unorganized: PathBuf,
     pub organized: PathBuf,
     pub duplicates: PathBuf,
+    /// More directories to organize, each with its own options (`[[directories.sources]]`).
+    pub sources: Vec<ConfigSource>,
 }
 
+impl ConfigDirectories {
+    /// Gets every directory to organize: the unorganized directory (when set) and then the sources.
+    pub fn get_sources(&self) -> Vec<ConfigSource> {
+        let mut sources = Vec::new();
+        if !self.unorganized.as_os_str().is_empty() {
+            sources.push(ConfigSource::new(&self.unorganized));
+        }
+        sources.extend(self.sources.iter().cloned());
+        sources
+    }
+}
+
+/// A directory to organize, and which of its photos are organized.
 #[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// When false, the subdirectories of the source are not organized.
+    pub recursive: bool,
+    /// Globs of the files to organize, relative to the source (every file when empty).
+    pub include: Vec<String>,
+    /// Globs of the files and directories to skip, relative to the source.
+    pub exclude: Vec<String>,
+}
+
+impl ConfigSource {
+    /// A source that organizes every photo of a directory and its subdirectories.
+    pub fn new(path: &Path) -> ConfigSource {
+        ConfigSource {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for ConfigSource {
+    fn default() -> Self {
+        ConfigSource {
+            path: PathBuf::new(),
+            recursive: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// How progress is printed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
+pub enum Output {
+    /// Every directory and file
+    #[default]
+    Default,
+    /// A character for each file
+    Compact,
+    /// Only the totals
+    Summary,
+    /// Nothing
+    None,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    /// Parses an output by its config name (e.g. "compact").
+    fn from_str(text: &str) -> anyhow::Result<Output> {
+        match text {
+            "default" => Ok(Output::Default),
+            "compact" => Ok(Output::Compact),
+            "summary" => Ok(Output::Summary),
+            "none" => Ok(Output::None),
+            _ => bail!(
+                "Unknown output '{}' (expected default, compact, summary, or none).",
+                text
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
 pub struct ConfigOptions {
-    pub output: String,
+    pub output: Output,
     pub stop_on_error: bool,
+    pub plan: bool,
+    /// The journal of every move, used by undo and verify. Moves are only journaled (and so hashed) when set.
+    pub journal: Option<PathBuf>,
+    pub organized_template: PathTemplate,
+    pub duplicates_template: PathTemplate,
+    pub threads: usize,
+    pub hash_index: Option<PathBuf>,
+    pub fix_extensions: bool,
+    pub mode: TransferMode,
+    pub delete_source: bool,
+    /// Globs of the files to organize in every source (a glob ending in `/` matches directories instead).
+    pub include: Vec<String>,
+    /// Globs of the files and directories to skip in every source (a glob ending in `/` only matches directories).
+    pub exclude: Vec<String>,
+    /// The rules of the files and directories that are left alone (`[options.protect]`).
+    pub protect: ProtectionRules,
+    /// The timezone of the date-times in organized paths: `capture`, `utc`, or a timezone name.
+    pub time_zone: PathTimeZone,
+    /// The corrections of cameras whose clock was wrong (`[[options.clockCorrections]]`), in order.
+    pub clock_corrections: Vec<ClockCorrection>,
+    /// The patterns of date-times in file (and directory) names, in order. Empty turns them off.
+    pub name_patterns: Vec<NamePattern>,
+    /// The sources of the date-times that organize photos, in order. The earliest file time is used
+    /// when none of them has a date-time.
+    pub date_sources: Vec<DateSource>,
 }
 
-#[derive(Clone, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
+impl Default for ConfigOptions {
+    fn default() -> ConfigOptions {
+        ConfigOptions {
+            output: Output::default(),
+            stop_on_error: false,
+            plan: false,
+            journal: None,
+            organized_template: PathTemplate::organized_default(),
+            duplicates_template: PathTemplate::duplicates_default(),
+            threads: 0,
+            hash_index: None,
+            fix_extensions: false,
+            mode: TransferMode::default(),
+            delete_source: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            protect: ProtectionRules::default(),
+            time_zone: PathTimeZone::default(),
+            clock_corrections: Vec::new(),
+            name_patterns: NamePattern::defaults(),
+            date_sources: DEFAULT_DATE_SOURCES.to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
 pub struct Config {
     pub directories: ConfigDirectories,
     pub options: ConfigOptions,
+    /// The profile the values are for, if any
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// Where the values came from, in the order they were applied (e.g. config files and environment variables)
+    #[serde(skip)]
+    pub sources: Vec<String>,
+    /// Problems with the sources that did not stop them from being read (e.g. unknown environment variables)
+    #[serde(skip)]
+    pub warnings: Vec<String>,
 }
 
-pub fn load_config() -> io::Result<Config> {
-    let path = Path::new("./photiso.toml");
+/// The directories and options of a profile. Only the values that differ from `[default]` need to be set.
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+struct ConfigProfile {
+    directories: ConfigDirectories,
+    options: ConfigOptions,
+}
+
+/// What a config file may hold: the top-level `[directories]` and `[options]`, a `[default]` profile that
+/// overrides them, and named profiles (e.g. `[profiles.family.directories]`) that inherit from both.
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+struct ConfigFile {
+    directories: ConfigDirectories,
+    options: ConfigOptions,
+    default: ConfigProfile,
+    profiles: BTreeMap<String, ConfigProfile>,
+}
+
+/// The config files and environment variables, before a profile is chosen.
+pub struct LoadedConfig {
+    /// The merged config files
+    file_values: toml::Value,
+    /// The environment variables, which override every profile
+    env_values: toml::Value,
+    sources: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl LoadedConfig {
+    /// The names of the profiles in the config files, in order.
+    pub fn profile_names(&self) -> Vec<String> {
+        match self.file_values.get("profiles") {
+            Some(toml::Value::Table(profiles)) => profiles.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets the config of a profile, or of `[default]` when there is no profile.
+    /// A profile inherits the values of `[default]` (and the top-level tables) that it does not set.
+    pub fn get_config(&self, profile: Option<&str>) -> anyhow::Result<Config> {
+        let mut values = toml::Value::Table(toml::value::Table::new());
+        for key in ["directories", "options"] {
+            if let Some(value) = self.file_values.get(key) {
+                merge_values(&mut values, table_of(key, value.clone()));
+            }
+        }
+        if let Some(default) = self.file_values.get("default") {
+            merge_profile(&mut values, default.clone());
+        }
 
-    let mut file = File::open(&path)?;
+        if let Some(profile) = profile {
+            match self
+                .file_values
+                .get("profiles")
+                .and_then(|profiles| profiles.get(profile))
+            {
+                Some(profile_values) => merge_profile(&mut values, profile_values.clone()),
+                None => {
+                    let profile_names = self.profile_names();
+                    if profile_names.is_empty() {
+                        bail!("Unknown profile '{}' (no profiles are defined).", profile);
+                    }
+                    bail!(
+                        "Unknown profile '{}' (expected one of {}).",
+                        profile,
+                        profile_names.join(", ")
+                    );
+                }
+            }
+        }
 
-    let mut s = String::new();
-    file.read_to_string(&mut s)?;
+        merge_values(&mut values, self.env_values.clone());
 
-    Ok(toml::from_str(&s)?)
+        let mut config: Config = values.try_into()?;
+        config.profile = profile.map(String::from);
+        config.sources = self.sources.clone();
+        config.warnings = self.warnings.clone();
+
+        Ok(config)
+    }
+}
+
+/// The config values that paths are, which are relative to the config file they are in.
+const PATH_KEYS: &[(&str, &str)] = &[
+    ("directories", "unorganized"),
+    ("directories", "organized"),
+    ("directories", "duplicates"),
+    ("options", "journal"),
+    ("options", "hashIndex"),
+];
+
+/// The type of the value of an environment variable.
+#[derive(Clone, Copy)]
+enum EnvValue {
+    Text,
+    Bool,
+    Number,
+}
+
+/// The environment variables that set config values: name, section, key, and type.
+const ENV_VARS: &[(&str, &str, &str, EnvValue)] = &[
+    (
+        "PHOTISO_UNORGANIZED",
+        "directories",
+        "unorganized",
+        EnvValue::Text,
+    ),
+    (
+        "PHOTISO_ORGANIZED",
+        "directories",
+        "organized",
+        EnvValue::Text,
+    ),
+    (
+        "PHOTISO_DUPLICATES",
+        "directories",
+        "duplicates",
+        EnvValue::Text,
+    ),
+    ("PHOTISO_OUTPUT", "options", "output", EnvValue::Text),
+    (
+        "PHOTISO_STOP_ON_ERROR",
+        "options",
+        "stopOnError",
+        EnvValue::Bool,
+    ),
+    ("PHOTISO_PLAN", "options", "plan", EnvValue::Bool),
+    ("PHOTISO_JOURNAL", "options", "journal", EnvValue::Text),
+    (
+        "PHOTISO_ORGANIZED_TEMPLATE",
+        "options",
+        "organizedTemplate",
+        EnvValue::Text,
+    ),
+    (
+        "PHOTISO_DUPLICATES_TEMPLATE",
+        "options",
+        "duplicatesTemplate",
+        EnvValue::Text,
+    ),
+    ("PHOTISO_THREADS", "options", "threads", EnvValue::Number),
+    ("PHOTISO_HASH_INDEX", "options", "hashIndex", EnvValue::Text),
+    (
+        "PHOTISO_FIX_EXTENSIONS",
+        "options",
+        "fixExtensions",
+        EnvValue::Bool,
+    ),
+    ("PHOTISO_MODE", "options", "mode", EnvValue::Text),
+    (
+        "PHOTISO_DELETE_SOURCE",
+        "options",
+        "deleteSource",
+        EnvValue::Bool,
+    ),
+    ("PHOTISO_TIME_ZONE", "options", "timeZone", EnvValue::Text),
+];
+
+/// The environment variable that names a config file, like --config.
+pub const CONFIG_ENV_VAR: &str = "PHOTISO_CONFIG";
+
+/// Loads the config from its layers. Each layer overrides the values of the layers before it:
+///
+/// 1. The user config file: `$XDG_CONFIG_HOME/photiso/photiso.toml` (or `~/.config/photiso/photiso.toml`).
+/// 2. The config file at `path` (from --config or PHOTISO_CONFIG), or else the project config file:
+///    the nearest `photiso.toml` in the current directory or its parents.
+/// 3. The PHOTISO_* environment variables.
+///
+/// Relative paths in a config file are relative to the directory of the file. Unknown keys and bad values
+/// are reported with the file (and line) or environment variable they are in.
+///
+/// Profiles are merged across the config files too, so `get_config` gives the config of any profile.
+pub fn load_config(path: Option<&Path>) -> anyhow::Result<LoadedConfig> {
+    let env_path = env::var_os(CONFIG_ENV_VAR).map(PathBuf::from);
+    let path = path.or(env_path.as_deref());
+
+    let mut files = Vec::new();
+    if let Some(user_path) = get_user_config_path() {
+        if user_path.is_file() {
+            files.push(user_path);
+        }
+    }
+    match path {
+        Some(path) => {
+            ensure!(path.is_file(), "The config file {:?} does not exist.", path);
+            files.push(path.to_path_buf());
+        }
+        None => {
+            if let Some(project_path) = find_project_config_path()? {
+                // the user config file is only read once, even when it is also the project config file
+                if !files.iter().any(|file| is_same_file(file, &project_path)) {
+                    files.push(project_path);
+                }
+            }
+        }
+    }
+
+    let mut sources = Vec::new();
+    let mut file_values = toml::Value::Table(toml::value::Table::new());
+    for file in &files {
+        merge_values(&mut file_values, read_config_file(file)?);
+        sources.push(format!("config file {:?}", file));
+    }
+    let mut warnings = Vec::new();
+    let env_values = read_env_vars(env::vars(), &mut sources, &mut warnings)?;
+
+    Ok(LoadedConfig {
+        file_values,
+        env_values,
+        sources,
+        warnings,
+    })
 }
 
-// impl Clone for ConfigOptions {
-//     fn clone(&self) -> ConfigOptions {
-//         ConfigOptions {
-//             output: self.output.clone(),
-//             stop_on_error: self.stop_on_error
-//         }
-//     }
-// }
-// impl Clone for Config {
-//     fn clone(&self) -> Config {
-//         unorganized: self.unorganized.clone(),
-//         organize(unorganized_dir: &Path, organized_dir: &Path, duplicates_dir: &Path, event_handler: F)
-//         ConfigOptions {
-//             output: self.output.clone(),
-//             stop_on_error: self.stop_on_error
-//         }
-//     }
-// }
+/// Checks the values of a config that cannot be checked one at a time.
+/// Returns warnings about values that are valid, but have no effect, after those of loading the config.
+pub fn validate_config(config: &Config) -> anyhow::Result<Vec<String>> {
+    let directories = &config.directories;
+    let sources = directories.get_sources();
+    ensure!(
+        !sources.is_empty(),
+        "The unorganized directory is not set. Set directories.unorganized (or directories.sources) in a config file, PHOTISO_UNORGANIZED, or --unorganized (or --source)."
+    );
+
+    for (dir, key) in [
+        (&directories.organized, "organized"),
+        (&directories.duplicates, "duplicates"),
+    ] {
+        ensure!(
+            !dir.as_os_str().is_empty(),
+            "The {} directory is not set. Set directories.{} in a config file, PHOTISO_{}, or --{}.",
+            key,
+            key,
+            key.to_uppercase(),
+            key
+        );
+    }
+
+    let organized = std::path::absolute(&directories.organized)?;
+    let duplicates = std::path::absolute(&directories.duplicates)?;
+    ensure!(
+        organized != duplicates,
+        "The organized directory and duplicates directory cannot be the the same directory."
+    );
+
+    let mut unorganized_dirs: Vec<PathBuf> = Vec::new();
+    for source in &sources {
+        ensure!(
+            !source.path.as_os_str().is_empty(),
+            "A source in directories.sources has no path."
+        );
+
+        let unorganized = std::path::absolute(&source.path)?;
+        ensure!(
+            unorganized != duplicates,
+            "The unorganized directory {:?} and duplicates directory cannot be the the same directory.",
+            source.path
+        );
+        ensure!(
+            config.options.mode != TransferMode::Symlink || unorganized != organized,
+            "The unorganized directory {:?} and organized directory cannot be the same directory in symlink mode.",
+            source.path
+        );
+        ensure!(
+            !unorganized_dirs.contains(&unorganized),
+            "The unorganized directory {:?} is given more than once.",
+            source.path
+        );
+
+        for pattern in source.include.iter().chain(&source.exclude) {
+            if let Err(err) = Glob::new(pattern.trim_end_matches('/')) {
+                bail!(
+                    "Invalid pattern '{}' of the source {:?}: {}",
+                    pattern,
+                    source.path,
+                    err.kind()
+                );
+            }
+        }
+        unorganized_dirs.push(unorganized);
+    }
+
+    for pattern in config.options.include.iter().chain(&config.options.exclude) {
+        if let Err(err) = Glob::new(pattern.trim_end_matches('/')) {
+            bail!("Invalid pattern '{}': {}", pattern, err.kind());
+        }
+    }
+
+    let date_sources = &config.options.date_sources;
+    for (i, date_source) in date_sources.iter().enumerate() {
+        ensure!(
+            !date_sources[..i].contains(date_source),
+            "The date source '{}' is in options.dateSources more than once.",
+            date_source
+        );
+    }
+
+    for clock_correction in &config.options.clock_corrections {
+        clock_correction.validate()?;
+    }
+
+    let mut warnings = config.warnings.clone();
+    if config.options.delete_source && config.options.mode != TransferMode::Copy {
+        warnings.push(format!(
+            "deleteSource only applies to the copy mode, not the {:?} mode.",
+            config.options.mode
+        ));
+    }
+
+    Ok(warnings)
+}
+
+/// The user config file, whether or not it exists.
+#[doc(hidden)]
+fn get_user_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("photiso").join(CONFIG_FILE_NAME))
+}
+
+/// Finds the nearest config file in the current directory or its parents.
+/// A config file in the current directory keeps its relative path (./photiso.toml).
+#[doc(hidden)]
+fn find_project_config_path() -> anyhow::Result<Option<PathBuf>> {
+    let current_dir = env::current_dir()?;
+
+    for (i, dir) in current_dir.ancestors().enumerate() {
+        if dir.join(CONFIG_FILE_NAME).is_file() {
+            return Ok(Some(if i == 0 {
+                Path::new(".").join(CONFIG_FILE_NAME)
+            } else {
+                dir.join(CONFIG_FILE_NAME)
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+#[doc(hidden)]
+fn is_same_file(x: &Path, y: &Path) -> bool {
+    match (fs::canonicalize(x), fs::canonicalize(y)) {
+        (Ok(x), Ok(y)) => x == y,
+        _ => false,
+    }
+}
+
+/// Reads a config file as a layer. The file is checked on its own first, so that
+/// errors point to the line in the file rather than to the merged config.
+#[doc(hidden)]
+fn read_config_file(path: &Path) -> anyhow::Result<toml::Value> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Could not read the config file {:?}", path))?;
+
+    if let Err(err) = toml::from_str::<ConfigFile>(&text) {
+        bail!("{}", describe_config_error(path, &text, &err));
+    }
+
+    let mut value: toml::Value = toml::from_str(&text)?;
+
+    // relative paths are relative to the config file, unless it is in the current directory
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if dir != Path::new("") && dir != Path::new(".") {
+        rebase_paths(&mut value, dir);
+        if let Some(default) = value.get_mut("default") {
+            rebase_paths(default, dir);
+        }
+        if let Some(toml::Value::Table(profiles)) = value.get_mut("profiles") {
+            for (_, profile) in profiles.iter_mut() {
+                rebase_paths(profile, dir);
+            }
+        }
+    }
+
+    Ok(value)
+}
+
+/// Makes the relative paths of a profile (or the top-level tables) relative to `dir` instead.
+#[doc(hidden)]
+fn rebase_paths(profile: &mut toml::Value, dir: &Path) {
+    for (section, key) in PATH_KEYS {
+        if let Some(toml::Value::String(path_value)) = profile
+            .get_mut(section)
+            .and_then(|section| section.get_mut(key))
+        {
+            rebase_path(path_value, dir);
+        }
+    }
+
+    if let Some(toml::Value::Array(sources)) = profile
+        .get_mut("directories")
+        .and_then(|directories| directories.get_mut("sources"))
+    {
+        for source in sources.iter_mut() {
+            if let Some(toml::Value::String(path_value)) = source.get_mut("path") {
+                rebase_path(path_value, dir);
+            }
+        }
+    }
+}
+
+/// Makes a relative path relative to `dir` instead.
+#[doc(hidden)]
+fn rebase_path(path_value: &mut String, dir: &Path) {
+    let relative_path = Path::new(path_value.as_str());
+    if relative_path.is_relative() {
+        let joined_path = relative_path
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .fold(dir.to_path_buf(), |joined_path, component| {
+                joined_path.join(component)
+            });
+        *path_value = joined_path.to_string_lossy().into_owned();
+    }
+}
+
+/// Wraps a value in a table with a single key.
+#[doc(hidden)]
+fn table_of(key: &str, value: toml::Value) -> toml::Value {
+    let mut table = toml::value::Table::new();
+    table.insert(key.to_string(), value);
+    toml::Value::Table(table)
+}
+
+/// Describes an error in a config file with the line (and key) it is on, e.g.
+/// `Invalid config file "photiso.toml" at line 8 (options.output): unknown variant ...`.
+#[doc(hidden)]
+fn describe_config_error(path: &Path, text: &str, err: &toml::de::Error) -> String {
+    // the position of some values (e.g. enums and templates) is always line 1,
+    // so the line of the key is found here instead
+    let (message, key) = split_toml_error(&err.to_string());
+    let line = key
+        .as_deref()
+        .and_then(|key| find_key_line(text, key))
+        .or_else(|| err.line_col().map(|(line, _)| line + 1));
+
+    match (line, key) {
+        (Some(line), Some(key)) => format!(
+            "Invalid config file {:?} at line {} ({}): {}",
+            path, line, key, message
+        ),
+        (Some(line), None) => format!(
+            "Invalid config file {:?} at line {}: {}",
+            path, line, message
+        ),
+        (None, _) => format!("Invalid config file {:?}: {}", path, message),
+    }
+}
+
+/// Splits the key and position that toml puts at the end of an error message from the message.
+#[doc(hidden)]
+fn split_toml_error(message: &str) -> (String, Option<String>) {
+    match message.find(" for key `") {
+        Some(i) => {
+            let key = message[i + 10..].split('`').next().unwrap_or_default();
+            (message[..i].to_string(), Some(key.to_string()))
+        }
+        None => match message.find(" at line ") {
+            Some(i) => (message[..i].to_string(), None),
+            None => (message.to_string(), None),
+        },
+    }
+}
+
+/// Finds the line (1-based) of a key such as `options.output` in a config file,
+/// or of its section for a key such as `options`.
+#[doc(hidden)]
+fn find_key_line(text: &str, key: &str) -> Option<usize> {
+    // the key is a section (e.g. `profiles.family.options`) when there is a header for it
+    let is_section = text.lines().any(|line| {
+        line.trim()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim()
+            == key
+    });
+    let (section, name) = match key.rsplit_once('.') {
+        Some((section, name)) if !is_section => (section, Some(name)),
+        _ => (key, None),
+    };
+
+    let mut in_section = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        // an array of tables (e.g. `[[options.clockCorrections]]`) is found like a table
+        if let Some(header) = line.strip_prefix('[') {
+            in_section = header.trim_matches(|c| c == '[' || c == ']').trim() == section;
+            if in_section && name.is_none() {
+                return Some(i + 1);
+            }
+        } else if in_section {
+            if let Some((line_key, _)) = line.split_once('=') {
+                if Some(line_key.trim()) == name {
+                    return Some(i + 1);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads the PHOTISO_* environment variables as a layer. Each one is checked on its own,
+/// so that errors name the variable. Unknown variables are ignored with a warning,
+/// since they may be meant for another version of Photiso (or another program).
+#[doc(hidden)]
+fn read_env_vars<I>(
+    env_vars: I,
+    sources: &mut Vec<String>,
+    warnings: &mut Vec<String>,
+) -> anyhow::Result<toml::Value>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut vars: Vec<(String, String)> = env_vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("PHOTISO_") && name != CONFIG_ENV_VAR)
+        .collect();
+    vars.sort();
+
+    let mut layer = toml::Value::Table(toml::value::Table::new());
+    for (name, text) in vars {
+        let (section, key, env_value) = match ENV_VARS.iter().find(|var| var.0 == name) {
+            Some((_, section, key, env_value)) => (section, key, env_value),
+            None => {
+                warnings.push(format!(
+                    "Unknown environment variable {} is ignored (expected {}, or one of {}).",
+                    name,
+                    CONFIG_ENV_VAR,
+                    ENV_VARS
+                        .iter()
+                        .map(|var| var.0)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+                continue;
+            }
+        };
+
+        let value = match env_value {
+            EnvValue::Text => toml::Value::String(text),
+            EnvValue::Bool => match text.parse() {
+                Ok(value) => toml::Value::Boolean(value),
+                Err(_) => bail!(
+                    "Invalid environment variable {}: expected true or false, not '{}'.",
+                    name,
+                    text
+                ),
+            },
+            EnvValue::Number => match text.parse() {
+                Ok(value) => toml::Value::Integer(value),
+                Err(_) => bail!(
+                    "Invalid environment variable {}: expected a number, not '{}'.",
+                    name,
+                    text
+                ),
+            },
+        };
+
+        let var_layer = table_of(section, table_of(key, value));
+
+        if let Err(err) = var_layer.clone().try_into::<Config>() {
+            let (message, _) = split_toml_error(&err.to_string());
+            bail!("Invalid environment variable {}: {}", name, message);
+        }
+
+        merge_values(&mut layer, var_layer);
+        sources.push(format!("environment variable {}", name));
+    }
+
+    Ok(layer)
+}
+
+/// Merges a profile into the config values it inherits. A profile that sets its sources
+/// replaces the inherited unorganized directory too (unless it sets that as well), like --source does.
+#[doc(hidden)]
+fn merge_profile(values: &mut toml::Value, profile: toml::Value) {
+    let directories = profile.get("directories");
+    let sets_sources = directories.is_some_and(|directories| directories.get("sources").is_some());
+    let sets_unorganized =
+        directories.is_some_and(|directories| directories.get("unorganized").is_some());
+    if sets_sources && !sets_unorganized {
+        if let Some(toml::Value::Table(directories)) = values.get_mut("directories") {
+            directories.remove("unorganized");
+        }
+    }
+
+    merge_values(values, profile);
+}
+
+/// Merges a layer into the config values, replacing values and merging tables.
+#[doc(hidden)]
+fn merge_values(values: &mut toml::Value, layer: toml::Value) {
+    match (values, layer) {
+        (toml::Value::Table(values), toml::Value::Table(layer)) => {
+            for (key, value) in layer {
+                match values.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        values.insert(key, value);
+                    }
+                }
+            }
+        }
+        (values, layer) => *values = layer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn read_vars(vars: &[(&str, &str)]) -> anyhow::Result<(toml::Value, Vec<String>)> {
+        let mut sources = Vec::new();
+        let mut warnings = Vec::new();
+        let layer = read_env_vars(
+            vars.iter()
+                .map(|(name, text)| (name.to_string(), text.to_string())),
+            &mut sources,
+            &mut warnings,
+        )?;
+        Ok((layer, warnings))
+    }
+
+    #[test]
+    fn environment_variables_set_config_values() {
+        let (layer, warnings) = read_vars(&[
+            ("PHOTISO_PLAN", "true"),
+            ("PHOTISO_THREADS", "4"),
+            ("PHOTISO_JOURNAL", "moves.jsonl"),
+            ("PHOTISO_CONFIG", "photiso.toml"),
+            ("HOME", "/home/photiso"),
+        ])
+        .unwrap();
+        let config: Config = layer.try_into().unwrap();
+
+        assert!(warnings.is_empty());
+        assert!(config.options.plan);
+        assert_eq!(config.options.threads, 4);
+        assert_eq!(config.options.journal, Some(PathBuf::from("moves.jsonl")));
+    }
+
+    #[test]
+    fn unknown_environment_variables_are_ignored_with_a_warning() {
+        let (layer, warnings) =
+            read_vars(&[("PHOTISO_FUTURE_OPTION", "1"), ("PHOTISO_PLAN", "true")]).unwrap();
+        let config: Config = layer.try_into().unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("PHOTISO_FUTURE_OPTION"));
+        assert!(config.options.plan);
+    }
+
+    #[test]
+    fn bad_environment_variable_values_are_errors() {
+        for vars in [
+            [("PHOTISO_PLAN", "yes")],
+            [("PHOTISO_THREADS", "many")],
+            [("PHOTISO_MODE", "teleport")],
+        ] {
+            let err = read_vars(&vars).unwrap_err();
+            assert!(err.to_string().contains(vars[0].0), "{}", err);
+        }
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_the_config_file() {
+        let dir = TestDir::new("config-relative-paths");
+        let path = dir.write(
+            "library/photiso.toml",
+            b"[directories]\nunorganized = \"in\"\norganized = \"/photos\"\n\n[options]\njournal = \"./photiso.journal.jsonl\"\n",
+        );
+
+        let config: Config = read_config_file(&path).unwrap().try_into().unwrap();
+
+        assert_eq!(config.directories.unorganized, dir.join("library/in"));
+        assert_eq!(config.directories.organized, PathBuf::from("/photos"));
+        assert_eq!(
+            config.options.journal,
+            Some(dir.join("library/photiso.journal.jsonl"))
+        );
+    }
+
+    #[test]
+    fn a_profile_with_sources_replaces_the_inherited_unorganized_directory() {
+        let file_values: toml::Value = toml::from_str(
+            r#"
+            [directories]
+            unorganized = "/inbox"
+            organized = "/photos"
+            duplicates = "/duplicates"
+
+            [profiles.camera.directories]
+            sources = [{ path = "/camera" }]
+
+            [profiles.both.directories]
+            unorganized = "/scans"
+            sources = [{ path = "/camera" }]
+            "#,
+        )
+        .unwrap();
+        let loaded_config = LoadedConfig {
+            file_values,
+            env_values: toml::Value::Table(toml::value::Table::new()),
+            sources: Vec::new(),
+            warnings: Vec::new(),
+        };
+        let get_source_paths = |profile| -> Vec<PathBuf> {
+            let config = loaded_config.get_config(profile).unwrap();
+            assert_eq!(config.directories.organized, PathBuf::from("/photos"));
+            config
+                .directories
+                .get_sources()
+                .into_iter()
+                .map(|source| source.path)
+                .collect()
+        };
+
+        assert_eq!(get_source_paths(None), [PathBuf::from("/inbox")]);
+        assert_eq!(get_source_paths(Some("camera")), [PathBuf::from("/camera")]);
+        assert_eq!(
+            get_source_paths(Some("both")),
+            [PathBuf::from("/scans"), PathBuf::from("/camera")]
+        );
+    }
+}
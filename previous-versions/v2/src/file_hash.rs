@@ -1,13 +1,15 @@
 use data_encoding::HEXUPPER;
 use ring::digest::{Context, SHA256};
-use std::{fs::File, io::Read, path::Path};
-
-#[doc(hidden)]
-pub use anyhow::*;
+use std::{
+    fs::File,
+    io,
+    io::{Read, Write},
+    path::Path,
+};
 
 /// Gets the SHA256 digest hash fo a file
 pub fn get_file_hash(file_path: &Path) -> anyhow::Result<String> {
-    let file = File::open(&file_path)?;
+    let file = File::open(file_path)?;
 
     let mut bufreader = std::io::BufReader::new(&file);
 
@@ -25,3 +27,26 @@ pub fn get_file_hash(file_path: &Path) -> anyhow::Result<String> {
     let hash = context.finish();
     Ok(HEXUPPER.encode(hash.as_ref()))
 }
+
+/// Copies everything from `reader` to `writer`, returning the SHA256 digest hash of what was copied
+pub fn copy_with_hash<R, W>(reader: &mut R, writer: &mut W) -> io::Result<String>
+where
+    R: Read,
+    W: Write,
+{
+    let mut context = Context::new(&SHA256);
+    let mut buffer = [0; 64 * 1024];
+
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+    }
+    writer.flush()?;
+
+    let hash = context.finish();
+    Ok(HEXUPPER.encode(hash.as_ref()))
+}
@@ -0,0 +1,597 @@
+use crate::file_hash::*;
+use anyhow::{bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    fs::{File, FileTimes},
+    io,
+    io::{BufReader, BufWriter},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+/// The extension of a partial copy, which is renamed to its destination once it is complete and verified.
+const PARTIAL_EXTENSION: &str = "photiso-partial";
+
+/// How a photo gets to its organized (or duplicates) location.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferMode {
+    /// The photo is moved, so it is no longer at its original location.
+    #[default]
+    Move,
+    /// The photo is copied and the copy is verified, so the original is left intact.
+    Copy,
+    /// A hard link to the photo is created, so the original is left intact without using more space.
+    HardLink,
+    /// A reflink (copy-on-write clone) of the photo is created, on file systems that support them
+    /// (e.g. btrfs, XFS). The original is left intact without using more space until either is changed.
+    Reflink,
+    /// A relative symbolic link to the photo is created, so the organized directory is a view of the originals.
+    Symlink,
+}
+
+impl TransferMode {
+    /// The past tense of the transfer, for messages (e.g. "moved").
+    pub fn as_past_tense(&self) -> &'static str {
+        match self {
+            TransferMode::Move => "moved",
+            TransferMode::Copy => "copied",
+            TransferMode::HardLink => "linked",
+            TransferMode::Reflink => "cloned",
+            TransferMode::Symlink => "symlinked",
+        }
+    }
+}
+
+impl FromStr for TransferMode {
+    type Err = anyhow::Error;
+
+    /// Parses a mode by its config name (e.g. "hardLink").
+    fn from_str(text: &str) -> anyhow::Result<TransferMode> {
+        match text {
+            "move" => Ok(TransferMode::Move),
+            "copy" => Ok(TransferMode::Copy),
+            "hardLink" => Ok(TransferMode::HardLink),
+            "reflink" => Ok(TransferMode::Reflink),
+            "symlink" => Ok(TransferMode::Symlink),
+            _ => bail!(
+                "Unknown mode '{}' (expected move, copy, hardLink, reflink, or symlink).",
+                text
+            ),
+        }
+    }
+}
+
+/// Moves a file. A rename cannot cross file systems, so a move to another file system copies
+/// the file (see `copy_file_verified`) and deletes the original once the copy is verified.
+pub fn move_file(from: &Path, to: &Path, hash: Option<&str>) -> anyhow::Result<()> {
+    if let Some(to_dir) = to.parent() {
+        fs::create_dir_all(to_dir)?;
+    }
+
+    match fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => move_by_copy(from, to, hash),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Moves a file like `move_file`, but never replaces a file at `to`, even one that appears while the file
+/// is moved. The move fails with `io::ErrorKind::AlreadyExists` when there is a file at `to`.
+pub fn move_file_no_clobber(from: &Path, to: &Path, hash: Option<&str>) -> anyhow::Result<()> {
+    if let Some(to_dir) = to.parent() {
+        fs::create_dir_all(to_dir)?;
+    }
+
+    match rename_no_clobber(from, to) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            move_by_copy_no_clobber(from, to, hash)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Moves a file by copying it (see `copy_file_verified`), for moves across file systems.
+/// The original is only deleted once its copy is verified.
+pub fn move_by_copy(from: &Path, to: &Path, hash: Option<&str>) -> anyhow::Result<()> {
+    copy_file_verified(from, to, hash)?;
+    fs::remove_file(from)?;
+
+    Ok(())
+}
+
+/// Moves a file by copying it like `move_by_copy`, but never replaces a file at `to`.
+#[doc(hidden)]
+fn move_by_copy_no_clobber(from: &Path, to: &Path, hash: Option<&str>) -> anyhow::Result<()> {
+    let partial_path = get_partial_path(to);
+    let copied = copy_to_partial(from, &partial_path, hash)
+        .and_then(|_| Ok(rename_no_clobber(&partial_path, to)?));
+    if let Err(err) = copied {
+        let _ = fs::remove_file(&partial_path);
+        return Err(err);
+    }
+
+    sync_dir(to)?;
+    fs::remove_file(from)?;
+
+    Ok(())
+}
+
+/// Copies a file, keeping its permissions and its accessed and modified times.
+///
+/// The file is streamed to a partial file next to `to` and synced to disk. The partial file is only
+/// renamed to `to` once its hash matches the original (`hash`, when it is already known),
+/// so an interrupted copy never leaves a half-written file at `to`.
+pub fn copy_file_verified(from: &Path, to: &Path, hash: Option<&str>) -> anyhow::Result<()> {
+    if let Some(to_dir) = to.parent() {
+        fs::create_dir_all(to_dir)?;
+    }
+
+    let partial_path = get_partial_path(to);
+    if let Err(err) = copy_to_partial(from, &partial_path, hash) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(err);
+    }
+
+    fs::rename(&partial_path, to)?;
+    sync_dir(to)?;
+
+    Ok(())
+}
+
+/// Hard links or reflinks a file, depending on `mode`.
+///
+/// When the file system cannot (e.g. the link would cross file systems, or the file system does not
+/// support reflinks), the file is copied instead (see `copy_file_verified`) and the reason is returned.
+pub fn link_file(
+    mode: TransferMode,
+    from: &Path,
+    to: &Path,
+    hash: Option<&str>,
+) -> anyhow::Result<Option<String>> {
+    if let Some(to_dir) = to.parent() {
+        fs::create_dir_all(to_dir)?;
+    }
+
+    let (linked, link_name) = match mode {
+        TransferMode::HardLink => (
+            fs::hard_link(from, to).map_err(get_hard_link_error),
+            "hard link",
+        ),
+        TransferMode::Reflink => (reflink_file(from, to), "reflink"),
+        TransferMode::Move | TransferMode::Copy | TransferMode::Symlink => {
+            bail!("The {:?} mode does not link files.", mode)
+        }
+    };
+
+    match linked {
+        Ok(_) => Ok(None),
+        Err(err) if is_unsupported(&err) => {
+            copy_file_verified(from, to, hash)?;
+            Ok(Some(format!(
+                "The {} could not be created ({}), so the file was copied instead.",
+                link_name, err
+            )))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Creates a symbolic link at `to` that points to `from`, relative to the directory of `to`.
+pub fn symlink_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let to_dir = match to.parent() {
+        Some(to_dir) => to_dir,
+        None => bail!("The link {:?} has no directory.", to),
+    };
+    fs::create_dir_all(to_dir)?;
+
+    let target = get_relative_path(&fs::canonicalize(to_dir)?, &fs::canonicalize(from)?);
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&target, to)?;
+
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(&target, to)?;
+
+    Ok(())
+}
+
+/// True if the file is a partial copy, left behind by an interrupted copy.
+pub fn is_partial_file(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new(PARTIAL_EXTENSION))
+}
+
+/// Copies a file to its partial path and verifies the copy.
+#[doc(hidden)]
+fn copy_to_partial(from: &Path, partial_path: &Path, hash: Option<&str>) -> anyhow::Result<()> {
+    let source = File::open(from)?;
+    let metadata = source.metadata()?;
+
+    let mut writer = BufWriter::new(File::create(partial_path)?);
+    let source_hash = copy_with_hash(&mut BufReader::new(source), &mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    if let Some(hash) = hash {
+        ensure!(source_hash == hash, "The file changed since it was hashed.");
+    }
+    ensure!(
+        get_file_hash(partial_path)? == source_hash,
+        "The copy of the file does not match the original."
+    );
+
+    let times = FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    File::options()
+        .write(true)
+        .open(partial_path)?
+        .set_times(times)?;
+    fs::set_permissions(partial_path, metadata.permissions())?;
+
+    Ok(())
+}
+
+/// True if an error means the file system does not support the link, rather than something went wrong.
+/// Other errors (e.g. a full disk or a missing permission) are not worked around by copying.
+#[doc(hidden)]
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported | io::ErrorKind::TooManyLinks
+    )
+}
+
+/// Marks the errors of a hard link that mean the file system cannot link the file as unsupported.
+/// Any other error is returned as it is.
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+fn get_hard_link_error(err: io::Error) -> io::Error {
+    match err.raw_os_error() {
+        // file systems without hard links (e.g. FAT, exFAT, some SMB shares) fail with EPERM
+        Some(libc::EPERM) => io::Error::new(io::ErrorKind::Unsupported, err),
+        _ => err,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[doc(hidden)]
+fn get_hard_link_error(err: io::Error) -> io::Error {
+    err
+}
+
+/// Clones a file with the FICLONE ioctl, keeping its permissions and its accessed and modified times.
+/// The clone is made at the partial path of `to` and renamed once complete, as copies are.
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+fn reflink_file(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = File::open(from)?;
+    let metadata = source.metadata()?;
+
+    let partial_path = get_partial_path(to);
+    let cloned = (|| {
+        let partial_file = File::create(&partial_path)?;
+
+        // safety: both file descriptors are open for the duration of the call
+        let result =
+            unsafe { libc::ioctl(partial_file.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+        if result != 0 {
+            return Err(get_reflink_error(io::Error::last_os_error()));
+        }
+
+        partial_file.set_times(
+            FileTimes::new()
+                .set_accessed(metadata.accessed()?)
+                .set_modified(metadata.modified()?),
+        )?;
+        partial_file.sync_all()?;
+        fs::set_permissions(&partial_path, metadata.permissions())
+    })();
+
+    if let Err(err) = cloned {
+        let _ = fs::remove_file(&partial_path);
+        return Err(err);
+    }
+
+    fs::rename(&partial_path, to)?;
+    sync_dir(to)
+}
+
+/// Marks the errors of the FICLONE ioctl that mean the file system cannot clone the file as unsupported.
+/// Any other error (e.g. EIO, ENOSPC) is returned as it is.
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+fn get_reflink_error(err: io::Error) -> io::Error {
+    match err.raw_os_error() {
+        // the file system does not support reflinks, the files are on different file systems,
+        // or the files cannot be cloned (e.g. a file system that clones whole blocks only)
+        Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::ENOTTY) => {
+            io::Error::new(io::ErrorKind::Unsupported, err)
+        }
+        _ => err,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[doc(hidden)]
+fn reflink_file(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+/// Renames a file unless there is a file at `to`, checking and renaming in one step.
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+fn rename_no_clobber(from: &Path, to: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from_path = CString::new(from.as_os_str().as_bytes())?;
+    let to_path = CString::new(to.as_os_str().as_bytes())?;
+
+    // safety: both paths are nul-terminated and live for the duration of the call
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_path.as_ptr(),
+            libc::AT_FDCWD,
+            to_path.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // the file system (or kernel) cannot rename without replacing
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => rename_by_link(from, to),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[doc(hidden)]
+fn rename_no_clobber(from: &Path, to: &Path) -> io::Result<()> {
+    rename_by_link(from, to)
+}
+
+/// Renames a file by hard linking it to `to` (which fails when there is a file at `to`) and removing `from`.
+#[doc(hidden)]
+fn rename_by_link(from: &Path, to: &Path) -> io::Result<()> {
+    fs::hard_link(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Gets the path of `to` relative to the directory `from_dir`. Both must be canonical.
+#[doc(hidden)]
+fn get_relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let from_components: Vec<Component> = from_dir.components().collect();
+    let to_components: Vec<Component> = to.components().collect();
+
+    let common = from_components
+        .iter()
+        .zip(to_components.iter())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut relative_path = PathBuf::new();
+    for _ in common..from_components.len() {
+        relative_path.push("..");
+    }
+    for component in &to_components[common..] {
+        relative_path.push(component);
+    }
+
+    relative_path
+}
+
+/// The partial path of a copy to `to`: a hidden file in the same directory, so the final rename is atomic.
+#[doc(hidden)]
+fn get_partial_path(to: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(to.file_name().unwrap_or_default());
+    file_name.push(".");
+    file_name.push(PARTIAL_EXTENSION);
+    to.with_file_name(file_name)
+}
+
+/// Syncs the directory of a file so that a rename into it survives a crash.
+#[doc(hidden)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn a_verified_copy_keeps_the_original_and_its_times() {
+        let dir = TestDir::new("transfer-copy");
+        let from = dir.write("in/a.jpg", b"photo");
+        let modified =
+            fs::metadata(&from).unwrap().modified().unwrap() - std::time::Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let to = dir.join("out/2021/a.jpg");
+
+        copy_file_verified(&from, &to, Some(&get_file_hash(&from).unwrap())).unwrap();
+
+        assert_eq!(fs::read(&from).unwrap(), b"photo");
+        assert_eq!(fs::read(&to).unwrap(), b"photo");
+        assert_eq!(fs::metadata(&to).unwrap().modified().unwrap(), modified);
+        assert_eq!(
+            fs::metadata(&to).unwrap().permissions(),
+            fs::metadata(&from).unwrap().permissions()
+        );
+        assert!(!get_partial_path(&to).exists());
+    }
+
+    #[test]
+    fn a_move_by_copy_deletes_the_original_once_verified() {
+        let dir = TestDir::new("transfer-move-by-copy");
+        let from = dir.write("in/a.jpg", b"photo");
+        let to = dir.join("out/2021/a.jpg");
+
+        move_by_copy(&from, &to, Some(&get_file_hash(&from).unwrap())).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"photo");
+        assert!(!get_partial_path(&to).exists());
+    }
+
+    #[test]
+    fn a_move_by_copy_keeps_the_original_when_the_copy_does_not_match() {
+        let dir = TestDir::new("transfer-move-by-copy-changed");
+        let from = dir.write("in/a.jpg", b"photo");
+        let to = dir.join("out/a.jpg");
+
+        assert!(move_by_copy(&from, &to, Some("0000")).is_err());
+        assert!(move_by_copy_no_clobber(&from, &to, Some("0000")).is_err());
+
+        assert_eq!(fs::read(&from).unwrap(), b"photo");
+        assert!(!to.exists());
+        assert!(!get_partial_path(&to).exists());
+    }
+
+    #[test]
+    fn a_move_by_copy_without_clobbering_keeps_both_files() {
+        let dir = TestDir::new("transfer-move-by-copy-no-clobber");
+        let from = dir.write("in/a.jpg", b"photo");
+        let to = dir.write("out/a.jpg", b"other photo");
+
+        let err = move_by_copy_no_clobber(&from, &to, None).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(|err| err.kind()),
+            Some(io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(fs::read(&from).unwrap(), b"photo");
+        assert_eq!(fs::read(&to).unwrap(), b"other photo");
+        assert!(!get_partial_path(&to).exists());
+
+        fs::remove_file(&to).unwrap();
+        move_by_copy_no_clobber(&from, &to, None).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"photo");
+    }
+
+    #[test]
+    fn a_copy_of_a_changed_file_is_not_kept() {
+        let dir = TestDir::new("transfer-copy-changed");
+        let from = dir.write("in/a.jpg", b"photo");
+        let to = dir.join("out/a.jpg");
+
+        // the hash of the original does not match what is read, as when the file changed since it was hashed
+        let err = copy_file_verified(&from, &to, Some("0000")).unwrap_err();
+
+        assert_eq!(err.to_string(), "The file changed since it was hashed.");
+        assert_eq!(fs::read(&from).unwrap(), b"photo");
+        assert!(!to.exists());
+        assert!(!get_partial_path(&to).exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn only_unsupported_reflinks_fall_back_to_copies() {
+        for errno in [libc::EOPNOTSUPP, libc::EXDEV, libc::EINVAL, libc::ENOTTY] {
+            let err = get_reflink_error(io::Error::from_raw_os_error(errno));
+            assert!(is_unsupported(&err), "{}", errno);
+        }
+
+        for errno in [
+            libc::EIO,
+            libc::ENOSPC,
+            libc::EBADF,
+            libc::EACCES,
+            libc::EPERM,
+        ] {
+            let err = get_reflink_error(io::Error::from_raw_os_error(errno));
+            assert!(!is_unsupported(&err), "{}", errno);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn only_unsupported_hard_links_fall_back_to_copies() {
+        let err = get_hard_link_error(io::Error::from_raw_os_error(libc::EPERM));
+        assert!(is_unsupported(&err));
+        assert!(is_unsupported(&get_hard_link_error(
+            io::Error::from_raw_os_error(libc::EXDEV)
+        )));
+
+        for errno in [libc::EACCES, libc::EIO, libc::ENOSPC, libc::EEXIST] {
+            let err = get_hard_link_error(io::Error::from_raw_os_error(errno));
+            assert!(!is_unsupported(&err), "{}", errno);
+        }
+
+        // a permission error from anything but the link itself is not worked around
+        assert!(!is_unsupported(&io::Error::from_raw_os_error(libc::EPERM)));
+        assert!(!is_unsupported(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+
+    #[test]
+    fn a_hard_link_shares_the_file() {
+        let dir = TestDir::new("transfer-hard-link");
+        let from = dir.write("in/a.jpg", b"photo");
+        let to = dir.join("out/2021/a.jpg");
+
+        let fallback = link_file(TransferMode::HardLink, &from, &to, None).unwrap();
+
+        assert_eq!(fallback, None);
+        assert_eq!(fs::read(&to).unwrap(), b"photo");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = fs::metadata(&from).unwrap();
+            assert_eq!(fs::metadata(&to).unwrap().ino(), metadata.ino());
+            assert_eq!(metadata.nlink(), 2);
+        }
+    }
+
+    #[test]
+    fn a_reflink_is_cloned_or_else_copied() {
+        let dir = TestDir::new("transfer-reflink");
+        let from = dir.write("in/a.jpg", b"photo");
+        let to = dir.join("out/2021/a.jpg");
+
+        // whether the file system of the test directory supports reflinks decides which happens
+        let fallback = link_file(TransferMode::Reflink, &from, &to, None).unwrap();
+
+        if let Some(reason) = fallback {
+            assert!(
+                reason.starts_with("The reflink could not be created"),
+                "{}",
+                reason
+            );
+        }
+        assert_eq!(fs::read(&from).unwrap(), b"photo");
+        assert_eq!(fs::read(&to).unwrap(), b"photo");
+        assert!(!get_partial_path(&to).exists());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{fs::File, io, io::Read, path::Path};
+
+/// The kinds of files that are organized. The order is the preference for the primary photo of a group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileKind {
+    Raw,
+    Photo,
+    Video,
+    Sidecar,
+}
+
+/// A photo or video format, as detected from the first bytes of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Bmp,
+    Gif,
+    Jpeg,
+    JpegXr,
+    Png,
+    /// TIFF, including the RAW formats that are plain TIFF files (ARW, DNG, NEF)
+    Tiff,
+    Avif,
+    Heif,
+    Jxl,
+    WebP,
+    Cr2,
+    Cr3,
+    Orf,
+    Raf,
+    ThreeGp,
+    Avi,
+    M4v,
+    Mov,
+    Mp4,
+}
+
+impl FileFormat {
+    /// The extension given to files of this format when their extension is fixed.
+    pub fn extension(&self) -> &'static str {
+        self.extensions()[0]
+    }
+
+    /// The extensions that files of this format may have (the first is preferred).
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            FileFormat::Bmp => &["bmp"],
+            FileFormat::Gif => &["gif"],
+            FileFormat::Jpeg => &["jpg", "jpeg"],
+            FileFormat::JpegXr => &["wmp"],
+            FileFormat::Png => &["png"],
+            FileFormat::Tiff => &["tif", "tiff", "arw", "dng", "nef"],
+            FileFormat::Avif => &["avif"],
+            FileFormat::Heif => &["heic", "heif"],
+            FileFormat::Jxl => &["jxl"],
+            FileFormat::WebP => &["webp"],
+            FileFormat::Cr2 => &["cr2"],
+            FileFormat::Cr3 => &["cr3"],
+            FileFormat::Orf => &["orf"],
+            FileFormat::Raf => &["raf"],
+            FileFormat::ThreeGp => &["3gp"],
+            FileFormat::Avi => &["avi"],
+            // QuickTime and MPEG-4 videos share a container, and cameras give them either extension
+            FileFormat::M4v => &["m4v", "mp4", "mov"],
+            FileFormat::Mov => &["mov", "mp4", "m4v"],
+            FileFormat::Mp4 => &["mp4", "m4v", "mov"],
+        }
+    }
+
+    /// The kind of file of this format.
+    pub fn kind(&self) -> FileKind {
+        match self {
+            FileFormat::Cr2 | FileFormat::Cr3 | FileFormat::Orf | FileFormat::Raf => FileKind::Raw,
+            FileFormat::ThreeGp
+            | FileFormat::Avi
+            | FileFormat::M4v
+            | FileFormat::Mov
+            | FileFormat::Mp4 => FileKind::Video,
+            _ => FileKind::Photo,
+        }
+    }
+
+    /// True if a file of this format may have the extension of `path` (ignoring case).
+    pub fn matches_extension(&self, path: &Path) -> bool {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => {
+                let ext = ext.to_lowercase();
+                self.extensions()
+                    .iter()
+                    .any(|format_ext| *format_ext == ext)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Detects the format of a file from its first bytes, whatever its extension.
+/// Returns None for files that are not in a known photo or video format.
+pub fn detect_file_format(path: &Path) -> io::Result<Option<FileFormat>> {
+    let mut head = Vec::with_capacity(32);
+    File::open(path)?.take(32).read_to_end(&mut head)?;
+
+    Ok(detect_format(&head))
+}
+
+#[doc(hidden)]
+fn detect_format(head: &[u8]) -> Option<FileFormat> {
+    let starts_with = |magic: &[u8]| head.starts_with(magic);
+    let at =
+        |position: usize, magic: &[u8]| head.get(position..position + magic.len()) == Some(magic);
+
+    if starts_with(b"\xff\xd8\xff") {
+        return Some(FileFormat::Jpeg);
+    }
+    if starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(FileFormat::Png);
+    }
+    if starts_with(b"GIF87a") || starts_with(b"GIF89a") {
+        return Some(FileFormat::Gif);
+    }
+    if starts_with(b"BM") && head.len() >= 14 {
+        return Some(FileFormat::Bmp);
+    }
+    if starts_with(b"II*\0") && at(8, b"CR") {
+        return Some(FileFormat::Cr2);
+    }
+    if starts_with(b"II*\0") || starts_with(b"MM\0*") {
+        return Some(FileFormat::Tiff);
+    }
+    if starts_with(b"IIRO") || starts_with(b"IIRS") || starts_with(b"MMOR") {
+        return Some(FileFormat::Orf);
+    }
+    if starts_with(b"II\xbc") {
+        return Some(FileFormat::JpegXr);
+    }
+    if starts_with(b"FUJIFILMCCD-RAW ") {
+        return Some(FileFormat::Raf);
+    }
+    if starts_with(b"\xff\x0a") || starts_with(b"\0\0\0\x0cJXL \r\n\x87\n") {
+        return Some(FileFormat::Jxl);
+    }
+    if starts_with(b"RIFF") && at(8, b"WEBP") {
+        return Some(FileFormat::WebP);
+    }
+    if starts_with(b"RIFF") && at(8, b"AVI ") {
+        return Some(FileFormat::Avi);
+    }
+
+    if at(4, b"ftyp") {
+        let brand = head.get(8..12)?;
+        return match brand {
+            b"crx " => Some(FileFormat::Cr3),
+            b"avif" | b"avis" => Some(FileFormat::Avif),
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1" => {
+                Some(FileFormat::Heif)
+            }
+            b"qt  " => Some(FileFormat::Mov),
+            b"M4V " | b"M4VH" | b"M4VP" => Some(FileFormat::M4v),
+            _ if brand.starts_with(b"3g") => Some(FileFormat::ThreeGp),
+            _ => Some(FileFormat::Mp4),
+        };
+    }
+
+    // QuickTime files may start with a box other than ftyp
+    if at(4, b"moov") || at(4, b"mdat") || at(4, b"wide") {
+        return Some(FileFormat::Mov);
+    }
+
+    None
+}
+
+/// Determines the kind of a file by inspecting the extension
+pub fn get_file_kind(path: &Path) -> Option<FileKind> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "bmp" => Some(FileKind::Photo),
+        "gif" => Some(FileKind::Photo),
+        "jpg" => Some(FileKind::Photo),
+        "jpeg" => Some(FileKind::Photo),
+        "png" => Some(FileKind::Photo),
+        "tif" => Some(FileKind::Photo),
+        "tiff" => Some(FileKind::Photo),
+        "wmp" => Some(FileKind::Photo),
+        "avif" => Some(FileKind::Photo),
+        "heic" => Some(FileKind::Photo),
+        "heif" => Some(FileKind::Photo),
+        "jxl" => Some(FileKind::Photo),
+        "webp" => Some(FileKind::Photo),
+        "arw" => Some(FileKind::Raw),
+        "cr2" => Some(FileKind::Raw),
+        "cr3" => Some(FileKind::Raw),
+        "dng" => Some(FileKind::Raw),
+        "nef" => Some(FileKind::Raw),
+        "orf" => Some(FileKind::Raw),
+        "raf" => Some(FileKind::Raw),
+        "3gp" => Some(FileKind::Video),
+        "avi" => Some(FileKind::Video),
+        "m4v" => Some(FileKind::Video),
+        "mov" => Some(FileKind::Video),
+        "mp4" => Some(FileKind::Video),
+        "aae" => Some(FileKind::Sidecar),
+        "xmp" => Some(FileKind::Sidecar),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_formats_from_their_first_bytes() {
+        let cases: &[(&[u8], FileFormat)] = &[
+            (b"\xff\xd8\xff\xe1\0\0Exif", FileFormat::Jpeg),
+            (b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR", FileFormat::Png),
+            (b"GIF89a\x01\0\x01\0", FileFormat::Gif),
+            (b"BM\x3a\0\0\0\0\0\0\0\x36\0\0\0", FileFormat::Bmp),
+            (b"II*\0\x10\0\0\0CR\x02\0", FileFormat::Cr2),
+            (b"II*\0\x08\0\0\0", FileFormat::Tiff),
+            (b"MM\0*\0\0\0\x08", FileFormat::Tiff),
+            (b"IIRO\x08\0\0\0", FileFormat::Orf),
+            (b"II\xbc\x01", FileFormat::JpegXr),
+            (b"FUJIFILMCCD-RAW 0201", FileFormat::Raf),
+            (b"\xff\x0a\xfa\x7f", FileFormat::Jxl),
+            (b"\0\0\0\x0cJXL \r\n\x87\n", FileFormat::Jxl),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", FileFormat::WebP),
+            (b"RIFF\x24\0\0\0AVI LIST", FileFormat::Avi),
+            (b"\0\0\0\x18ftypcrx \0\0\0\x01", FileFormat::Cr3),
+            (b"\0\0\0\x1cftypavif\0\0\0\0", FileFormat::Avif),
+            (b"\0\0\0\x18ftypheic\0\0\0\0", FileFormat::Heif),
+            (b"\0\0\0\x18ftypmif1\0\0\0\0", FileFormat::Heif),
+            (b"\0\0\0\x14ftypqt  \0\0\0\0", FileFormat::Mov),
+            (b"\0\0\0\x1cftypM4V \0\0\0\x01", FileFormat::M4v),
+            (b"\0\0\0\x14ftyp3gp4\0\0\0\0", FileFormat::ThreeGp),
+            (b"\0\0\0\x20ftypisom\0\0\x02\0", FileFormat::Mp4),
+            (b"\0\0\0\x08wide\0\0\0\0mdat", FileFormat::Mov),
+        ];
+
+        for (head, format) in cases {
+            assert_eq!(detect_format(head), Some(*format), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn does_not_detect_other_files() {
+        for head in [
+            &b""[..],
+            b"BM",
+            b"\xff\xd8",
+            b"RIFF\x24\0\0\0WAVEfmt ",
+            b"\0\0\0\x18ftyp",
+            b"plain text file",
+        ] {
+            assert_eq!(detect_format(head), None, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn matches_extensions_ignoring_case() {
+        assert!(FileFormat::Jpeg.matches_extension(Path::new("a.JPEG")));
+        assert!(FileFormat::Tiff.matches_extension(Path::new("a.nef")));
+        assert!(FileFormat::Mp4.matches_extension(Path::new("a.m4v")));
+        assert!(FileFormat::Mp4.matches_extension(Path::new("a.MOV")));
+        assert!(FileFormat::Mov.matches_extension(Path::new("a.mp4")));
+        assert!(!FileFormat::Avi.matches_extension(Path::new("a.mov")));
+        assert!(!FileFormat::Png.matches_extension(Path::new("a.jpg")));
+        assert!(!FileFormat::Png.matches_extension(Path::new("png")));
+        assert_eq!(FileFormat::Heif.extension(), "heic");
+    }
+
+    #[test]
+    fn gets_file_kinds_from_extensions() {
+        assert_eq!(get_file_kind(Path::new("a.CR3")), Some(FileKind::Raw));
+        assert_eq!(get_file_kind(Path::new("a.heic")), Some(FileKind::Photo));
+        assert_eq!(get_file_kind(Path::new("a.Mov")), Some(FileKind::Video));
+        assert_eq!(get_file_kind(Path::new("a.xmp")), Some(FileKind::Sidecar));
+        assert_eq!(get_file_kind(Path::new("a.txt")), None);
+        assert_eq!(get_file_kind(Path::new("jpg")), None);
+        assert_eq!(FileFormat::Raf.kind(), FileKind::Raw);
+        assert_eq!(FileFormat::Avi.kind(), FileKind::Video);
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, ensure};
+use chrono_tz::Tz;
+use std::{fmt, sync::OnceLock};
+
+/// The simplified timezone boundaries: one polygon per line, see the comments at the top of the file.
+const TIME_ZONE_BOUNDARIES: &str = include_str!("time_zone_boundaries.txt");
+
+/// Where a photo was taken, from the GPS tags of its EXIF information.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpsPosition {
+    /// Degrees north of the equator (negative is south)
+    pub latitude: f64,
+    /// Degrees east of the prime meridian (negative is west)
+    pub longitude: f64,
+}
+
+impl GpsPosition {
+    /// A position, if it is a valid one. Cameras without a GPS fix often record 0, 0, which is not used.
+    pub fn new(latitude: f64, longitude: f64) -> Option<GpsPosition> {
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return None;
+        }
+
+        if latitude == 0.0 && longitude == 0.0 {
+            return None;
+        }
+
+        Some(GpsPosition {
+            latitude,
+            longitude,
+        })
+    }
+
+    /// Finds the timezone of the position from the embedded timezone boundaries.
+    /// A position outside every boundary (e.g. at sea) gets the nautical timezone of its longitude.
+    pub fn time_zone(&self) -> anyhow::Result<Tz> {
+        let boundary = get_boundaries()?
+            .iter()
+            .filter(|boundary| boundary.contains(self.longitude, self.latitude))
+            .min_by(|a, b| a.area.total_cmp(&b.area));
+
+        Ok(match boundary {
+            Some(boundary) => boundary.time_zone,
+            None => get_nautical_time_zone(self.longitude),
+        })
+    }
+}
+
+impl fmt::Display for GpsPosition {
+    /// Formats as `34.05223, -118.24368`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.5}, {:.5}", self.latitude, self.longitude)
+    }
+}
+
+/// A polygon of the timezone boundaries.
+struct Boundary {
+    time_zone: Tz,
+    /// The corners of the polygon, as (longitude, latitude)
+    corners: Vec<(f64, f64)>,
+    /// The area of the polygon (in square degrees), so that the smallest polygon that contains a position wins
+    area: f64,
+}
+
+impl Boundary {
+    /// Tests if a point is inside the polygon (by counting the edges that a ray to the east crosses).
+    fn contains(&self, x: f64, y: f64) -> bool {
+        let mut inside = false;
+        let mut previous = self.corners[self.corners.len() - 1];
+        for &corner in &self.corners {
+            let ((x1, y1), (x2, y2)) = (corner, previous);
+            if (y1 > y) != (y2 > y) && x < (x2 - x1) * (y - y1) / (y2 - y1) + x1 {
+                inside = !inside;
+            }
+            previous = corner;
+        }
+
+        inside
+    }
+}
+
+/// Gets the timezone boundaries, parsing them the first time.
+#[doc(hidden)]
+fn get_boundaries() -> anyhow::Result<&'static [Boundary]> {
+    static BOUNDARIES: OnceLock<Result<Vec<Boundary>, String>> = OnceLock::new();
+    BOUNDARIES
+        .get_or_init(|| parse_boundaries(TIME_ZONE_BOUNDARIES).map_err(|err| err.to_string()))
+        .as_deref()
+        .map_err(|err| anyhow!("{}", err))
+}
+
+/// Parses the lines of the timezone boundaries, skipping comments and empty lines.
+#[doc(hidden)]
+fn parse_boundaries(text: &str) -> anyhow::Result<Vec<Boundary>> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_boundary)
+        .collect()
+}
+
+#[doc(hidden)]
+fn parse_boundary(line: &str) -> anyhow::Result<Boundary> {
+    let (name, corners) = match line.split_once(':') {
+        Some(parts) => parts,
+        None => bail!("The timezone boundary '{}' has no timezone.", line),
+    };
+
+    let time_zone = match name.trim().parse::<Tz>() {
+        Ok(time_zone) => time_zone,
+        Err(_) => bail!("The timezone boundary '{}' has an unknown timezone.", line),
+    };
+
+    let corners = corners
+        .split(',')
+        .map(|corner| {
+            let mut numbers = corner
+                .split_whitespace()
+                .map(|number| number.parse::<f64>());
+            match (numbers.next(), numbers.next(), numbers.next()) {
+                (Some(Ok(longitude)), Some(Ok(latitude)), None) => Ok((longitude, latitude)),
+                _ => Err(anyhow!(
+                    "The timezone boundary '{}' has a bad corner '{}'.",
+                    line,
+                    corner.trim()
+                )),
+            }
+        })
+        .collect::<anyhow::Result<Vec<(f64, f64)>>>()?;
+
+    ensure!(
+        corners.len() >= 3,
+        "The timezone boundary '{}' has fewer than 3 corners.",
+        line
+    );
+
+    // the shoelace formula
+    let mut area = 0.0;
+    let mut previous = corners[corners.len() - 1];
+    for &corner in &corners {
+        area += previous.0 * corner.1 - corner.0 * previous.1;
+        previous = corner;
+    }
+
+    Ok(Boundary {
+        time_zone,
+        corners,
+        area: area.abs() / 2.0,
+    })
+}
+
+/// Gets the nautical timezone of a longitude: one hour per 15 degrees (note that Etc/GMT+N is N hours west).
+#[doc(hidden)]
+fn get_nautical_time_zone(longitude: f64) -> Tz {
+    let hours = (longitude / 15.0).round() as i32;
+    let name = match hours {
+        0 => "Etc/GMT".to_string(),
+        _ => format!("Etc/GMT{:+}", -hours),
+    };
+
+    name.parse().unwrap_or(Tz::UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_zone_at(latitude: f64, longitude: f64) -> String {
+        GpsPosition::new(latitude, longitude)
+            .unwrap()
+            .time_zone()
+            .unwrap()
+            .name()
+            .to_string()
+    }
+
+    #[test]
+    fn every_boundary_parses() {
+        let boundaries = parse_boundaries(TIME_ZONE_BOUNDARIES).unwrap();
+
+        assert!(boundaries.len() > 100);
+        assert!(boundaries.iter().all(|boundary| boundary.area > 0.0));
+    }
+
+    #[test]
+    fn bad_boundaries_are_errors() {
+        for line in [
+            "America/Denver -109 37, -102 37, -102 41",
+            "America/Nowhere: -109 37, -102 37, -102 41",
+            "America/Denver: -109 37, -102, -102 41",
+            "America/Denver: -109 37, -102 north, -102 41",
+            "America/Denver: -109 37, -102 37 0, -102 41",
+            "America/Denver: -109 37, -102 37",
+        ] {
+            assert!(parse_boundary(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn cities_have_their_time_zone() {
+        for (latitude, longitude, time_zone) in [
+            (34.05, -118.24, "America/Los_Angeles"),
+            (39.74, -104.99, "America/Denver"),
+            (33.45, -112.07, "America/Phoenix"),
+            (41.88, -87.63, "America/Chicago"),
+            (40.71, -74.01, "America/New_York"),
+            (21.31, -157.86, "Pacific/Honolulu"),
+            (19.43, -99.13, "America/Mexico_City"),
+            (51.51, -0.13, "Europe/London"),
+            (48.86, 2.35, "Europe/Paris"),
+            (41.9, 12.5, "Europe/Rome"),
+            (52.52, 13.4, "Europe/Berlin"),
+            (35.68, 139.69, "Asia/Tokyo"),
+            (-33.87, 151.21, "Australia/Sydney"),
+        ] {
+            assert_eq!(
+                time_zone_at(latitude, longitude),
+                time_zone,
+                "{}, {}",
+                latitude,
+                longitude
+            );
+        }
+    }
+
+    #[test]
+    fn cities_near_a_border_have_their_time_zone() {
+        for (latitude, longitude, time_zone) in [
+            // El Paso is on Mountain time, unlike the rest of Texas
+            (31.76, -106.49, "America/Denver"),
+            (31.69, -106.42, "America/Ciudad_Juarez"),
+            (31.99, -102.08, "America/Chicago"),
+            // Trieste and Gorizia are in Italy, next to Slovenia
+            (45.65, 13.77, "Europe/Rome"),
+            (45.94, 13.62, "Europe/Rome"),
+            (45.55, 13.73, "Europe/Ljubljana"),
+            (46.06, 14.51, "Europe/Ljubljana"),
+        ] {
+            assert_eq!(
+                time_zone_at(latitude, longitude),
+                time_zone,
+                "{}, {}",
+                latitude,
+                longitude
+            );
+        }
+    }
+
+    #[test]
+    fn positions_at_sea_have_a_nautical_time_zone() {
+        for (latitude, longitude, time_zone) in [
+            (30.0, -40.0, "Etc/GMT+3"),
+            (-40.0, -130.0, "Etc/GMT+9"),
+            (0.5, 0.5, "Etc/GMT"),
+            (-10.0, 80.0, "Etc/GMT-5"),
+            (-50.0, 179.9, "Etc/GMT-12"),
+            (-50.0, -179.9, "Etc/GMT+12"),
+        ] {
+            assert_eq!(
+                time_zone_at(latitude, longitude),
+                time_zone,
+                "{}, {}",
+                latitude,
+                longitude
+            );
+        }
+    }
+
+    #[test]
+    fn positions_without_a_fix_are_ignored() {
+        assert_eq!(GpsPosition::new(0.0, 0.0), None);
+        assert_eq!(GpsPosition::new(91.0, 0.0), None);
+        assert_eq!(GpsPosition::new(0.0, -181.0), None);
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// A file in the hash index.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct HashIndexEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified_secs: u64,
+    pub modified_nanos: u32,
+    pub hash: String,
+}
+
+/// A persistent index of the content hashes of the files in the organized directory.
+///
+/// The index is saved as one JSON object per line. An entry is trusted on a later run as long as
+/// the size and modified time of its file have not changed, so only new or changed files are hashed again.
+pub struct HashIndex {
+    path: PathBuf,
+    entries: HashMap<PathBuf, HashIndexEntry>,
+    paths_by_hash: HashMap<String, BTreeSet<PathBuf>>,
+}
+
+impl HashIndex {
+    /// Loads the index from disk. A missing index file is an empty index.
+    pub fn load(path: &Path) -> anyhow::Result<HashIndex> {
+        let mut index = HashIndex {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+            paths_by_hash: HashMap::new(),
+        };
+
+        if !path.exists() {
+            return Ok(index);
+        }
+
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            index.insert(serde_json::from_str(&line)?);
+        }
+
+        Ok(index)
+    }
+
+    /// Saves the index to disk, writing to a temporary file first.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        // sorted so that the file is stable between runs
+        // paths that are not UTF-8 cannot be written as JSON, so those files are hashed again by the next run
+        let mut entries: Vec<&HashIndexEntry> = self
+            .entries
+            .values()
+            .filter(|entry| entry.path.to_str().is_some())
+            .collect();
+        entries.sort_by(|x, y| x.path.cmp(&y.path));
+
+        {
+            let mut file = File::create(&temp_path)?;
+            for entry in entries {
+                let mut line = serde_json::to_string(entry)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+            }
+            file.sync_all()?;
+        }
+
+        fs::rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Gets the entry for a path, if it is indexed and the file has not changed since.
+    pub fn get_current(&self, path: &Path, metadata: &fs::Metadata) -> Option<&HashIndexEntry> {
+        let entry = self.entries.get(path)?;
+        let (modified_secs, modified_nanos) = get_modified(metadata);
+
+        if entry.size == metadata.len()
+            && entry.modified_secs == modified_secs
+            && entry.modified_nanos == modified_nanos
+        {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    /// Adds (or replaces) the entry for a file.
+    pub fn insert_file(&mut self, path: &Path, metadata: &fs::Metadata, hash: &str) {
+        let (modified_secs, modified_nanos) = get_modified(metadata);
+        self.insert(HashIndexEntry {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified_secs,
+            modified_nanos,
+            hash: hash.to_string(),
+        });
+    }
+
+    /// Removes the entry for a file.
+    pub fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            if let Some(paths) = self.paths_by_hash.get_mut(&entry.hash) {
+                paths.remove(path);
+                if paths.is_empty() {
+                    self.paths_by_hash.remove(&entry.hash);
+                }
+            }
+        }
+    }
+
+    /// Removes every entry whose path does not satisfy `keep`.
+    pub fn retain<P>(&mut self, keep: P)
+    where
+        P: Fn(&Path) -> bool,
+    {
+        let removed: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .filter(|path| !keep(path))
+            .cloned()
+            .collect();
+
+        for path in removed {
+            self.remove(&path);
+        }
+    }
+
+    /// Finds the indexed files with a hash, in path order.
+    pub fn find_hash(&self, hash: &str) -> impl Iterator<Item = &PathBuf> {
+        self.paths_by_hash.get(hash).into_iter().flatten()
+    }
+
+    fn insert(&mut self, entry: HashIndexEntry) {
+        self.remove(&entry.path);
+        self.paths_by_hash
+            .entry(entry.hash.clone())
+            .or_default()
+            .insert(entry.path.clone());
+        self.entries.insert(entry.path.clone(), entry);
+    }
+}
+
+#[doc(hidden)]
+fn get_modified(metadata: &fs::Metadata) -> (u64, u32) {
+    match metadata
+        .modified()
+        .map(|time| time.duration_since(UNIX_EPOCH))
+    {
+        Ok(Ok(duration)) => (duration.as_secs(), duration.subsec_nanos()),
+        _ => (0, 0),
+    }
+}
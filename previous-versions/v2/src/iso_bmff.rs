@@ -0,0 +1,217 @@
+use std::io::{Read, Seek, SeekFrom};
+
+/// The signature box that starts a JPEG XL container.
+const JXL_SIGNATURE: &[u8; 12] = b"\0\0\0\x0cJXL \r\n\x87\n";
+
+/// The largest EXIF box that is read into memory.
+const MAX_EXIF_LEN: u64 = 16 * 1024 * 1024;
+
+/// An ISO-BMFF box header: the type, where the payload starts, and where the box ends.
+pub struct BoxHeader {
+    pub box_type: [u8; 4],
+    pub payload_start: u64,
+    pub end: u64,
+}
+
+/// Reads the header of the box at `start`. Returns None when there is no (valid) box before `parent_end`.
+pub fn read_box_header<R>(
+    reader: &mut R,
+    start: u64,
+    parent_end: u64,
+) -> anyhow::Result<Option<BoxHeader>>
+where
+    R: Read + Seek,
+{
+    if start + 8 > parent_end {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::Start(start))?;
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+
+    let mut box_type = [0u8; 4];
+    box_type.copy_from_slice(&header[4..8]);
+
+    let (payload_start, end) =
+        match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            // the size is in the following 64 bits
+            1 => {
+                let mut large_size = [0u8; 8];
+                reader.read_exact(&mut large_size)?;
+                match start.checked_add(u64::from_be_bytes(large_size)) {
+                    Some(end) => (start + 16, end),
+                    None => return Ok(None),
+                }
+            }
+            // the box extends to the end of its parent
+            0 => (start + 8, parent_end),
+            size => (start + 8, start + size as u64),
+        };
+
+    if end < payload_start || end > parent_end {
+        return Ok(None);
+    }
+
+    Ok(Some(BoxHeader {
+        box_type,
+        payload_start,
+        end,
+    }))
+}
+
+/// Reads the payload of a box. Returns None when the payload is larger than `max_len`.
+pub fn read_box_payload<R>(
+    reader: &mut R,
+    header: &BoxHeader,
+    max_len: u64,
+) -> anyhow::Result<Option<Vec<u8>>>
+where
+    R: Read + Seek,
+{
+    let len = header.end - header.payload_start;
+    if len > max_len {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::Start(header.payload_start))?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(payload))
+}
+
+/// Finds the raw EXIF (TIFF) data in the `Exif` box of a JPEG XL container.
+/// Returns None for bare JPEG XL codestreams and containers without (uncompressed) EXIF data.
+pub fn find_jxl_exif<R>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>>
+where
+    R: Read + Seek,
+{
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut signature = [0u8; 12];
+    if len < 12 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut signature)?;
+    if &signature != JXL_SIGNATURE {
+        return Ok(None);
+    }
+
+    let mut position = 0;
+    while let Some(header) = read_box_header(reader, position, len)? {
+        if &header.box_type == b"Exif" {
+            if let Some(payload) = read_box_payload(reader, &header, MAX_EXIF_LEN)? {
+                // the payload starts with the offset of the TIFF header
+                if payload.len() < 4 {
+                    return Ok(None);
+                }
+                let offset =
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+                return Ok(payload.get(4 + offset..).map(|tiff| tiff.to_vec()));
+            }
+        }
+
+        position = header.end;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_exif::*;
+    use std::io::Cursor;
+
+    fn jxl_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Builds a JPEG XL container with an `Exif` box.
+    fn jxl_file(tiff: &[u8]) -> Vec<u8> {
+        let mut exif = vec![0, 0, 0, 0];
+        exif.extend_from_slice(tiff);
+
+        let mut file = JXL_SIGNATURE.to_vec();
+        file.extend(jxl_box(b"ftyp", b"jxl \0\0\0\0jxl "));
+        file.extend(jxl_box(b"Exif", &exif));
+        file.extend(jxl_box(b"jxlc", b"\xff\x0a"));
+        file
+    }
+
+    fn read_header(bytes: &[u8]) -> Option<BoxHeader> {
+        read_box_header(&mut Cursor::new(bytes), 0, bytes.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn reads_box_sizes() {
+        let header = read_header(b"\0\0\0\x0cfree\0\0\0\0").unwrap();
+        assert_eq!(&header.box_type, b"free");
+        assert_eq!((header.payload_start, header.end), (8, 12));
+
+        // a size of 0 extends to the end of the parent
+        let header = read_header(b"\0\0\0\0mdat\0\0\0\0\0\0").unwrap();
+        assert_eq!((header.payload_start, header.end), (8, 14));
+
+        // a size of 1 is followed by a 64-bit size
+        let header = read_header(b"\0\0\0\x01mdat\0\0\0\0\0\0\0\x12\0\0").unwrap();
+        assert_eq!((header.payload_start, header.end), (16, 18));
+    }
+
+    #[test]
+    fn rejects_boxes_that_do_not_fit() {
+        // truncated header
+        assert!(read_header(b"\0\0\0\x0cfre").is_none());
+        // larger than its parent
+        assert!(read_header(b"\0\0\0\xfffree\0\0\0\0").is_none());
+        // smaller than its header
+        assert!(read_header(b"\0\0\0\x04free\0\0\0\0").is_none());
+        // a 64-bit size that overflows
+        assert!(read_header(b"\0\0\0\x01mdat\xff\xff\xff\xff\xff\xff\xff\xff").is_none());
+    }
+
+    #[test]
+    fn finds_the_exif_of_a_jxl_container() {
+        let tiff = tiff_with_date_time_original("2019:07:04 12:34:56");
+
+        let exif = find_jxl_exif(&mut Cursor::new(jxl_file(&tiff))).unwrap();
+
+        assert_eq!(exif, Some(tiff));
+    }
+
+    #[test]
+    fn skips_the_offset_before_the_tiff_header() {
+        let tiff = tiff_with_date_time_original("2019:07:04 12:34:56");
+        let mut exif = vec![0, 0, 0, 2, b'X', b'X'];
+        exif.extend_from_slice(&tiff);
+        let mut file = JXL_SIGNATURE.to_vec();
+        file.extend(jxl_box(b"Exif", &exif));
+
+        assert_eq!(find_jxl_exif(&mut Cursor::new(file)).unwrap(), Some(tiff));
+    }
+
+    #[test]
+    fn other_jxl_files_have_no_exif() {
+        // a bare codestream
+        assert!(
+            find_jxl_exif(&mut Cursor::new(b"\xff\x0a\0\0\0\0\0\0\0\0\0\0\0"))
+                .unwrap()
+                .is_none()
+        );
+
+        // a container without an Exif box
+        let mut file = JXL_SIGNATURE.to_vec();
+        file.extend(jxl_box(b"jxlc", b"\xff\x0a"));
+        assert!(find_jxl_exif(&mut Cursor::new(file)).unwrap().is_none());
+
+        // an Exif box whose offset is past its end
+        let mut file = JXL_SIGNATURE.to_vec();
+        file.extend(jxl_box(b"Exif", &[0, 0, 1, 0, b'M', b'M']));
+        assert!(find_jxl_exif(&mut Cursor::new(file)).unwrap().is_none());
+    }
+}
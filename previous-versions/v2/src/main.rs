@@ -1,50 +1,268 @@
+mod capture_date_time;
+mod cli;
+mod clock_correction;
 mod config;
 mod file_hash;
+mod file_transfer;
+mod file_type;
+mod gps_position;
+mod hash_index;
+mod iso_bmff;
+mod move_journal;
+mod name_pattern;
+mod path_template;
 mod photo_date_time;
 mod photo_organizer;
+mod protection;
+mod raw_exif;
+#[cfg(test)]
+mod test_dir;
+#[cfg(test)]
+mod test_exif;
+mod video_date_time;
 
+use crate::capture_date_time::*;
+use crate::cli::*;
+use crate::clock_correction::*;
 use crate::config::*;
+use crate::file_hash::*;
+use crate::file_type::*;
+use crate::move_journal::*;
+use crate::name_pattern::*;
+use crate::photo_date_time::*;
 use crate::photo_organizer::*;
+use crate::protection::*;
+use anyhow::Context;
+use clap::Parser;
+use std::{cell::RefCell, path::Path, path::PathBuf, process::ExitCode};
 
-fn main() -> anyhow::Result<()> {
-    let config: Config = load_config()?;
+fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    print_header(&config);
+    match run(&cli) {
+        Ok(exit_code) => ExitCode::from(exit_code),
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+            ExitCode::from(EXIT_FATAL)
+        }
+    }
+}
+
+/// Runs the command of the command line and returns its exit code. An error is fatal.
+fn run(cli: &Cli) -> anyhow::Result<u8> {
+    match cli.command.as_ref().unwrap_or(&Command::Organize) {
+        Command::Organize => run_organize(&load_cli_configs(cli)?),
+        Command::Plan => {
+            let mut configs = load_cli_configs(cli)?;
+            for config in configs.iter_mut() {
+                config.options.plan = true;
+            }
+            run_organize(&configs)
+        }
+        Command::Verify => run_profiles(&load_cli_configs(cli)?, run_verify),
+        Command::Undo => run_profiles(&load_cli_configs(cli)?, run_undo),
+        Command::Dedupe => run_dedupe(&load_cli_configs(cli)?),
+        Command::Inspect { files, exif } => run_inspect(files, *exif),
+        Command::ClockOffset { reference, photo } => run_clock_offset(reference, photo),
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => run_profiles(&load_cli_configs(cli)?, run_config_check),
+    }
+}
+
+/// Loads the config of each profile chosen on the command line (or of `[default]`),
+/// and applies the flags of the command line to them.
+fn load_cli_configs(cli: &Cli) -> anyhow::Result<Vec<Config>> {
+    let loaded_config = load_config(cli.config.as_deref())?;
+
+    let profiles: Vec<Option<String>> = if cli.all_profiles {
+        let profile_names = loaded_config.profile_names();
+        anyhow::ensure!(!profile_names.is_empty(), "No profiles are defined.");
+        profile_names.into_iter().map(Some).collect()
+    } else if cli.profile.is_empty() {
+        vec![None]
+    } else {
+        cli.profile.iter().cloned().map(Some).collect()
+    };
+
+    profiles
+        .iter()
+        .map(|profile| {
+            let mut config = loaded_config.get_config(profile.as_deref())?;
+            cli.overrides.apply(&mut config);
+            Ok(config)
+        })
+        .collect()
+}
+
+/// Runs a command for each profile in turn. An error in one profile does not stop the others,
+/// unless there is only one.
+fn run_profiles<F>(configs: &[Config], run_profile: F) -> anyhow::Result<u8>
+where
+    F: Fn(&Config) -> anyhow::Result<u8>,
+{
+    if let [config] = configs {
+        return run_profile(config);
+    }
 
-    let on_event = create_on_photiso_event(config.clone());
+    let mut exit_codes = Vec::new();
+    for config in configs {
+        match run_profile(config) {
+            Ok(exit_code) => exit_codes.push(exit_code),
+            Err(err) => {
+                eprintln!("Error in profile {}: {:#}", get_profile_name(config), err);
+                exit_codes.push(EXIT_FATAL);
+            }
+        }
+    }
 
-    let result = photo_organizer::organize(
-        &config.directories.unorganized,
-        &config.directories.organized,
-        &config.directories.duplicates,
-        on_event,
-    )?;
+    Ok(combine_exit_codes(&exit_codes))
+}
 
-    print_footer(&config, &result);
+/// The exit code of several profiles: fatal when every profile failed, partial when any did not succeed.
+fn combine_exit_codes(exit_codes: &[u8]) -> u8 {
+    if exit_codes.iter().all(|exit_code| *exit_code == EXIT_FATAL) {
+        EXIT_FATAL
+    } else if exit_codes
+        .iter()
+        .any(|exit_code| *exit_code != EXIT_SUCCESS)
+    {
+        EXIT_PARTIAL
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+fn get_profile_name(config: &Config) -> &str {
+    config.profile.as_deref().unwrap_or("default")
+}
+
+/// Validates the config before organizing, printing its warnings.
+fn check_config(config: &Config) -> anyhow::Result<()> {
+    for warning in validate_config(config)? {
+        if config.options.output != Output::None {
+            eprintln!("Warning: {}", warning);
+        }
+    }
 
     Ok(())
 }
 
-fn create_on_photiso_event(config: Config) -> Box<dyn Fn(OrganizeEvent) -> bool> {
-    Box::new(move |event| -> bool {
-        on_photiso_event(&config, &event);
-        true
+fn get_organize_options(config: &Config) -> OrganizeOptions {
+    OrganizeOptions {
+        plan: config.options.plan,
+        journal: config.options.journal.clone(),
+        organized_template: config.options.organized_template.clone(),
+        duplicates_template: config.options.duplicates_template.clone(),
+        threads: config.options.threads,
+        hash_index: config.options.hash_index.clone(),
+        fix_extensions: config.options.fix_extensions,
+        mode: config.options.mode,
+        delete_source: config.options.delete_source,
+        include: config.options.include.clone(),
+        exclude: config.options.exclude.clone(),
+        protection: config.options.protect.clone(),
+        time_zone: config.options.time_zone,
+        clock_corrections: config.options.clock_corrections.clone(),
+        name_patterns: config.options.name_patterns.clone(),
+        date_sources: config.options.date_sources.clone(),
+    }
+}
+
+fn get_organize_sources(config: &Config) -> Vec<OrganizeSource> {
+    config
+        .directories
+        .get_sources()
+        .into_iter()
+        .map(|source| OrganizeSource {
+            dir: source.path,
+            recursive: source.recursive,
+            include: source.include,
+            exclude: source.exclude,
+        })
+        .collect()
+}
+
+fn run_organize(configs: &[Config]) -> anyhow::Result<u8> {
+    run_organize_profiles(configs, |config| {
+        photo_organizer::organize_sources(
+            &get_organize_sources(config),
+            &config.directories.organized,
+            &config.directories.duplicates,
+            &get_organize_options(config),
+            create_on_photiso_event(config.clone()),
+        )
+    })
+}
+
+fn run_dedupe(configs: &[Config]) -> anyhow::Result<u8> {
+    run_organize_profiles(configs, |config| {
+        photo_organizer::dedupe(
+            &config.directories.organized,
+            &config.directories.duplicates,
+            &get_organize_options(config),
+            create_on_photiso_event(config.clone()),
+        )
     })
 }
 
+/// Organizes (or dedupes) each profile in turn. Several profiles are followed by their combined result.
+fn run_organize_profiles<F>(configs: &[Config], organize: F) -> anyhow::Result<u8>
+where
+    F: Fn(&Config) -> anyhow::Result<OrganizeResult>,
+{
+    let combined = RefCell::new(OrganizeResult::default());
+
+    let exit_code = run_profiles(configs, |config| {
+        check_config(config)?;
+        print_header(config);
+
+        let result = organize(config)?;
+
+        print_footer(config, &result);
+        combined.borrow_mut().add(&result);
+
+        Ok(get_organize_exit_code(&result))
+    })?;
+
+    if let [.., last_config] = configs {
+        if configs.len() > 1 && last_config.options.output != Output::None {
+            println!(
+                "All profiles: {}",
+                configs
+                    .iter()
+                    .map(get_profile_name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            print_footer(last_config, &combined.borrow());
+        }
+    }
+
+    Ok(exit_code)
+}
+
+fn get_organize_exit_code(result: &OrganizeResult) -> u8 {
+    if result.files_errored > 0 {
+        EXIT_PARTIAL
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+fn create_on_photiso_event(config: Config) -> Box<dyn Fn(OrganizeEvent) -> bool> {
+    Box::new(move |event| -> bool { on_photiso_event(&config, &event) })
+}
+
 fn print_header(config: &Config) {
-    if config.options.output != "none" {
+    if config.options.output != Output::None {
         println!("========================================");
         println!("Photiso");
-        println!("========================================");                
-        println!();
-        println!("unorganized: {:?}", config.directories.unorganized);
-        println!("organized: {:?}", config.directories.organized);
-        println!("duplicates: {:?}", config.directories.duplicates);
+        println!("========================================");
         println!();
-        println!("stop on error: {:?}", config.options.stop_on_error);
+        print_settings(config);
         println!();
-        if config.options.output == "compact" {
+        if config.options.output == Output::Compact {
             println!("Progress Legend");
             println!("======");
             println!(". => a photo was moved to the organized directory.");
@@ -55,15 +273,112 @@ fn print_header(config: &Config) {
             println!();
         }
         println!("========================================");
-        if config.options.output == "compact" {
+        if config.options.output == Output::Compact {
             eprintln!();
             eprint!("Progress: ")
         }
     }
 }
 
+fn print_settings(config: &Config) {
+    if let Some(profile) = &config.profile {
+        println!("profile: {}", profile);
+    }
+    for source in config.directories.get_sources() {
+        print!("unorganized: {:?}", source.path);
+        if !source.recursive {
+            print!(" (not recursive)");
+        }
+        if !source.include.is_empty() {
+            print!(" include: {}", source.include.join(", "));
+        }
+        if !source.exclude.is_empty() {
+            print!(" exclude: {}", source.exclude.join(", "));
+        }
+        println!();
+    }
+    println!("organized: {:?}", config.directories.organized);
+    println!("duplicates: {:?}", config.directories.duplicates);
+    println!();
+    println!("stop on error: {:?}", config.options.stop_on_error);
+    println!("mode: {:?}", config.options.mode);
+    if config.options.delete_source {
+        println!("delete source: true (originals are deleted once their copies are verified)");
+    }
+    if let Some(journal) = &config.options.journal {
+        println!("journal: {:?}", journal);
+    }
+    println!("threads: {:?}", config.options.threads);
+    if let Some(hash_index) = &config.options.hash_index {
+        println!("hash index: {:?}", hash_index);
+    }
+    println!(
+        "organized template: {}",
+        config.options.organized_template.as_str()
+    );
+    println!(
+        "duplicates template: {}",
+        config.options.duplicates_template.as_str()
+    );
+    if !config.options.include.is_empty() {
+        println!("include: {}", config.options.include.join(", "));
+    }
+    if !config.options.exclude.is_empty() {
+        println!("exclude: {}", config.options.exclude.join(", "));
+    }
+    let protect = &config.options.protect;
+    if *protect != ProtectionRules::default() {
+        println!(
+            "protect: stem markers [{}], marker files [{}], xattrs [{}], read-only {}",
+            protect.stem_markers.join(", "),
+            protect.marker_files.join(", "),
+            protect.xattrs.join(", "),
+            protect.read_only
+        );
+    }
+    if config.options.time_zone != PathTimeZone::default() {
+        println!("time zone: {}", config.options.time_zone);
+    }
+    if config.options.date_sources != DEFAULT_DATE_SOURCES {
+        println!(
+            "date sources: {}",
+            config
+                .options
+                .date_sources
+                .iter()
+                .map(DateSource::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if config.options.name_patterns != NamePattern::defaults() {
+        println!(
+            "name patterns: {}",
+            config
+                .options
+                .name_patterns
+                .iter()
+                .map(NamePattern::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if !config.options.clock_corrections.is_empty() {
+        println!("clock corrections:");
+        for clock_correction in &config.options.clock_corrections {
+            println!("  {}", clock_correction);
+        }
+    }
+    if config.options.fix_extensions {
+        println!("fix extensions: true");
+    }
+    if config.options.plan {
+        println!("plan: true (no files will be moved)");
+    }
+}
+
 fn print_footer(config: &Config, result: &OrganizeResult) {
-    if config.options.output != "none" {
+    if config.options.output != Output::None {
         println!();
         println!();
         println!("========================================");
@@ -73,10 +388,41 @@ fn print_footer(config: &Config, result: &OrganizeResult) {
         println!();
         println!("Files: {}", result.files);
         println!("Moved: {}", result.photos_moved);
+        if !result.dated_by.is_empty() {
+            println!(
+                "Dated by: {}",
+                result
+                    .dated_by
+                    .iter()
+                    .map(|(source, count)| format!("{} {}", source.label(), count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
         println!("Duplicates: {}", result.duplicate_photos_moved);
+        println!("Companions: {}", result.companions_moved);
         println!("Skipped: {}", result.files_skipped);
         println!("Already correct: {}", result.photos_noop);
+        println!("Extensions fixed: {}", result.extensions_fixed);
         println!("Errors: {}", result.files_errored);
+        if result.sources.len() > 1 {
+            println!();
+            println!("Sources:");
+            for source in &result.sources {
+                let counts = &source.result;
+                println!(
+                    "  {:?}: {} files, {} moved, {} duplicates, {} companions, {} skipped, {} already correct, {} errors",
+                    source.source,
+                    counts.files,
+                    counts.photos_moved,
+                    counts.duplicate_photos_moved,
+                    counts.companions_moved,
+                    counts.files_skipped,
+                    counts.photos_noop,
+                    counts.files_errored
+                );
+            }
+        }
         println!();
         println!("Duration: {:?}", result.duration);
 
@@ -93,11 +439,11 @@ fn print_footer(config: &Config, result: &OrganizeResult) {
 }
 
 fn on_photiso_event(config: &Config, event: &OrganizeEvent) -> bool {
-    match config.options.output.as_str() {
-        "none" => on_photiso_event_none(event),
-        "summary" => on_photiso_event_none(event),
-        "compact" => on_photiso_event_compact(event),
-        _ => on_photiso_event_default(event),
+    match config.options.output {
+        Output::None => on_photiso_event_none(event),
+        Output::Summary => on_photiso_event_none(event),
+        Output::Compact => on_photiso_event_compact(event),
+        Output::Default => on_photiso_event_default(config, event),
     }
 
     if let OrganizeEvent::FileError { file: _, error: _ } = event {
@@ -108,20 +454,19 @@ fn on_photiso_event(config: &Config, event: &OrganizeEvent) -> bool {
     true
 }
 
-fn on_photiso_event_none(event: &OrganizeEvent) {
-    match event {
-        _ => {}
-    }
-}
+fn on_photiso_event_none(_event: &OrganizeEvent) {}
 
 fn on_photiso_event_compact(event: &OrganizeEvent) {
     match event {
-        OrganizeEvent::PhotoMoved { from: _, to: _ } => {
+        OrganizeEvent::PhotoMoved { .. } => {
             eprint!(".");
         }
         OrganizeEvent::DuplicatePhotoMoved { from: _, to: _ } => {
             eprint!("*");
         }
+        OrganizeEvent::CompanionMoved { from: _, to: _ } => {
+            eprint!("+");
+        }
         OrganizeEvent::PhotoNoOp { file: _ } => {
             eprint!("_");
         }
@@ -136,19 +481,63 @@ fn on_photiso_event_compact(event: &OrganizeEvent) {
     }
 }
 
-fn on_photiso_event_default(event: &OrganizeEvent) {
+fn on_photiso_event_default(config: &Config, event: &OrganizeEvent) {
+    let transferred = config.options.mode.as_past_tense();
+
     match event {
+        OrganizeEvent::SourceStarted { source } if config.directories.get_sources().len() > 1 => {
+            println!("Source: {:?}", source);
+            println!();
+        }
+        OrganizeEvent::SourceFinished { source, result }
+            if config.directories.get_sources().len() > 1 =>
+        {
+            println!(
+                "Source finished: {:?} ({} files, {} {}, {} duplicates, {} errors)",
+                source,
+                result.files,
+                result.photos_moved,
+                transferred,
+                result.duplicate_photos_moved,
+                result.files_errored
+            );
+            println!();
+        }
         OrganizeEvent::DirStarted { dir } => {
             println!("{:?}", dir);
         }
-        OrganizeEvent::DirFinished { dir: _ } => {
+        OrganizeEvent::DirFinished => {
             println!();
         }
-        OrganizeEvent::PhotoMoved { from, to } => {
-            println!("  Photo moved: {:?} -> {:?}", from, to);
+        OrganizeEvent::DirSkipped { dir, reason } => {
+            println!("Directory skipped: {:?} -> {}", dir, reason);
+        }
+        OrganizeEvent::ExtensionFixed { file, extension } => {
+            println!("  Extension fixed: {:?} -> .{}", file, extension);
+        }
+        OrganizeEvent::LinkRemoved { link } => {
+            println!("  Link removed: {:?}", link);
+        }
+        OrganizeEvent::FileWarning { file, warning } => {
+            println!("  File warning: {:?} -> {}", file, warning);
+        }
+        OrganizeEvent::CopyFallback { file, reason } => {
+            println!("  Link fallback: {:?} -> {}", file, reason);
+        }
+        OrganizeEvent::PhotoMoved { from, to, dated_by } => {
+            println!(
+                "  Photo {}: {:?} -> {:?} ({})",
+                transferred,
+                from,
+                to,
+                dated_by.label()
+            );
         }
         OrganizeEvent::DuplicatePhotoMoved { from, to } => {
-            println!("  Duplicate photo moved: {:?} -> {:?}", from, to);
+            println!("  Duplicate photo {}: {:?} -> {:?}", transferred, from, to);
+        }
+        OrganizeEvent::CompanionMoved { from, to } => {
+            println!("  Companion {}: {:?} -> {:?}", transferred, from, to);
         }
         OrganizeEvent::PhotoNoOp { file } => {
             println!("  Already correct: {:?}", file);
@@ -163,3 +552,323 @@ fn on_photiso_event_default(event: &OrganizeEvent) {
         _ => {}
     }
 }
+
+/// Gets the journal that undo and verify read, which must be configured.
+fn get_journal(config: &Config) -> anyhow::Result<&Path> {
+    config.options.journal.as_deref().context(
+        "No journal is configured, so there are no moves to read (set 'journal' in [options], PHOTISO_JOURNAL, or --journal).",
+    )
+}
+
+fn run_undo(config: &Config) -> anyhow::Result<u8> {
+    let output = config.options.output;
+    let result = move_journal::undo(get_journal(config)?, move |event| -> bool {
+        on_undo_event(output, &event);
+        true
+    })?;
+
+    if config.options.output != Output::None {
+        println!();
+        println!("========================================");
+        println!();
+        println!("Restored: {}", result.files_restored);
+        println!("Skipped: {}", result.files_skipped);
+        println!("Errors: {}", result.files_errored);
+        println!();
+        println!("Duration: {:?}", result.duration);
+        println!();
+        println!("========================================");
+    }
+
+    if result.files_skipped > 0 || result.files_errored > 0 {
+        return Ok(EXIT_PARTIAL);
+    }
+
+    Ok(EXIT_SUCCESS)
+}
+
+fn run_verify(config: &Config) -> anyhow::Result<u8> {
+    let output = config.options.output;
+    let stop_on_error = config.options.stop_on_error;
+    let result = move_journal::verify(get_journal(config)?, move |event| -> bool {
+        on_verify_event(output, &event);
+        !(stop_on_error && matches!(event, VerifyEvent::FileError { .. }))
+    })?;
+
+    if config.options.output != Output::None {
+        println!();
+        println!("========================================");
+        println!();
+        println!("Verified: {}", result.files_verified);
+        println!("Missing: {}", result.files_missing);
+        println!("Changed: {}", result.files_changed);
+        println!("Unfinished: {}", result.files_unfinished);
+        println!("Errors: {}", result.files_errored);
+        println!();
+        println!("Duration: {:?}", result.duration);
+        println!();
+        println!("========================================");
+    }
+
+    if result.files_missing > 0
+        || result.files_changed > 0
+        || result.files_unfinished > 0
+        || result.files_errored > 0
+    {
+        return Ok(EXIT_PARTIAL);
+    }
+
+    Ok(EXIT_SUCCESS)
+}
+
+fn on_verify_event(output: Output, event: &VerifyEvent) {
+    match event {
+        VerifyEvent::PhotoVerified { file } => {
+            if output == Output::Default {
+                println!("  Verified: {:?}", file);
+            }
+        }
+        // problems are printed unless the output is none
+        VerifyEvent::PhotoMissing { file } => {
+            if output != Output::None {
+                println!("  Missing: {:?}", file);
+            }
+        }
+        VerifyEvent::PhotoChanged { file } => {
+            if output != Output::None {
+                println!("  Changed: {:?}", file);
+            }
+        }
+        VerifyEvent::MoveUnfinished { from, to } => {
+            if output != Output::None {
+                println!("  Unfinished: {:?} -> {:?}", from, to);
+            }
+        }
+        VerifyEvent::FileError { file, error } => {
+            if output != Output::None {
+                println!("  File error: {:?} -> {:?}", file, error);
+            }
+        }
+    }
+}
+
+/// Prints what is read from each file. Inspect does not use the config.
+fn run_inspect(files: &[PathBuf], exif: bool) -> anyhow::Result<u8> {
+    let mut exit_code = EXIT_SUCCESS;
+
+    for file in files {
+        println!("{:?}", file);
+        if let Err(err) = inspect_file(file, exif) {
+            println!("  Error: {:#}", err);
+            exit_code = EXIT_PARTIAL;
+        }
+        println!();
+    }
+
+    Ok(exit_code)
+}
+
+fn inspect_file(file: &Path, exif: bool) -> anyhow::Result<()> {
+    match detect_file_format(file)? {
+        Some(format) => {
+            println!("  format: {:?} ({:?})", format, format.kind());
+            if !format.matches_extension(file) {
+                println!("  extension: should be .{}", format.extension());
+            }
+        }
+        None => match get_file_kind(file) {
+            Some(FileKind::Sidecar) => println!("  format: sidecar (by extension)"),
+            Some(kind) => println!(
+                "  format: not recognized, so it is skipped ({:?} by extension)",
+                kind
+            ),
+            None => println!("  format: not a photo"),
+        },
+    }
+
+    println!("  hash: {}", get_file_hash(file)?);
+
+    // names are read with the default name patterns, as inspect does not use the config
+    let mut date_time_info = PhotoDateTimeInfo::load(file)?;
+    date_time_info.read_name(file, &NamePattern::defaults());
+    let (dated_by, best) = date_time_info.best(DEFAULT_DATE_SOURCES);
+    println!("  best date-time: {} ({})", best, dated_by.label());
+    for (source, date_time) in date_time_info.date_times() {
+        if let Some(date_time) = date_time {
+            println!("  {}: {}", source.label(), date_time);
+        }
+    }
+    if let Some(make) = date_time_info.camera_make() {
+        println!("  camera make: {}", make);
+    }
+    if let Some(model) = date_time_info.camera_model() {
+        println!("  camera model: {}", model);
+    }
+    if let Some(serial) = date_time_info.camera_serial() {
+        println!("  camera serial: {}", serial);
+    }
+    // a timezone that cannot be found is reported with the warnings
+    if let Some(position) = date_time_info.gps_position() {
+        match position.time_zone() {
+            Ok(time_zone) => println!("  gps position: {} ({})", position, time_zone.name()),
+            Err(_) => println!("  gps position: {}", position),
+        }
+    }
+    for warning in date_time_info.warnings() {
+        println!("  Warning: {}", warning);
+    }
+
+    if exif {
+        println!();
+        print_all_exif(file)?;
+    }
+
+    Ok(())
+}
+
+/// Prints the clock offset of the camera of `photo` from the camera of `reference`, for two photos taken
+/// at the same moment, with the clock correction that fixes it. Like inspect, this does not use the config.
+fn run_clock_offset(reference: &Path, photo: &Path) -> anyhow::Result<u8> {
+    let reference_info = PhotoDateTimeInfo::load(reference)?;
+    let photo_info = PhotoDateTimeInfo::load(photo)?;
+
+    let mut date_times = Vec::new();
+    for (file, date_time_info) in [(reference, &reference_info), (photo, &photo_info)] {
+        let date_time = date_time_info.camera_clock_date_time().with_context(|| {
+            format!(
+                "The photo {:?} has no date-time from its camera's clock (EXIF or video metadata).",
+                file
+            )
+        })?;
+        println!("{:?}: {}", file, date_time);
+        println!("  camera: {}", describe_camera(date_time_info));
+        date_times.push(date_time.local);
+    }
+
+    let offset = ClockOffset::between(&date_times[1], &date_times[0]);
+    println!();
+    if offset.0.is_zero() {
+        println!("The clocks of the two cameras agree.");
+        return Ok(EXIT_SUCCESS);
+    }
+
+    println!(
+        "The clock of the camera of {:?} needs {} to match the reference. To correct it, add this to the config:",
+        photo, offset
+    );
+    println!();
+    println!("[[options.clockCorrections]]");
+    for (key, value) in [
+        ("make", photo_info.camera_make()),
+        ("model", photo_info.camera_model()),
+        ("serial", photo_info.camera_serial()),
+    ] {
+        if let Some(value) = value {
+            println!("{} = {:?}", key, value);
+        }
+    }
+    println!("#from = \"{}\"", date_times[1].format("%Y-%m-%d"));
+    println!("#until = \"{}\"", date_times[1].format("%Y-%m-%d"));
+    println!("offset = \"{}\"", offset);
+
+    Ok(EXIT_SUCCESS)
+}
+
+/// Describes the camera of a photo, e.g. `Canon Canon EOS R5, serial 012345`.
+fn describe_camera(date_time_info: &PhotoDateTimeInfo) -> String {
+    let camera: Vec<&str> = [date_time_info.camera_make(), date_time_info.camera_model()]
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+    let mut description = if camera.is_empty() {
+        "unknown camera".to_string()
+    } else {
+        camera.join(" ")
+    };
+    if let Some(serial) = date_time_info.camera_serial() {
+        description.push_str(&format!(", serial {}", serial));
+    }
+
+    description
+}
+
+fn run_config_check(config: &Config) -> anyhow::Result<u8> {
+    let warnings = validate_config(config)?;
+
+    if config.sources.is_empty() {
+        println!("sources: (none, only flags and defaults)");
+    } else {
+        println!("sources (later ones override earlier ones):");
+        for source in &config.sources {
+            println!("  {}", source);
+        }
+    }
+    println!();
+    print_settings(config);
+    println!("output: {:?}", config.options.output);
+    println!();
+
+    for warning in &warnings {
+        println!("Warning: {}", warning);
+    }
+    println!("The config is valid.");
+
+    Ok(EXIT_SUCCESS)
+}
+
+fn on_undo_event(output: Output, event: &UndoEvent) {
+    if output != Output::Default {
+        return;
+    }
+
+    match event {
+        UndoEvent::PhotoRestored { from, to } => {
+            println!("  Photo restored: {:?} -> {:?}", from, to);
+        }
+        UndoEvent::FileSkipped { file, reason } => {
+            println!("  File skipped: {:?} -> {}", file, reason);
+        }
+        UndoEvent::FileError { file, error } => {
+            println!("  File error: {:?} -> {:?}", file, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn stop_on_error_stops_at_the_first_error() {
+        let dir = TestDir::new("main-stop-on-error");
+        dir.write("in/IMG-20210704-WA0001.jpg", b"\xff\xd8\xff\xe0photo 1");
+        dir.write("in/IMG-20210705-WA0001.jpg", b"\xff\xd8\xff\xe0photo 2");
+        // the organized directory is a file, so no photo can be moved into it
+        dir.write("out", b"");
+        let mut config = Config::default();
+        config.directories.unorganized = dir.join("in");
+        config.directories.organized = dir.join("out");
+        config.directories.duplicates = dir.join("dup");
+        config.options.output = Output::None;
+        config.options.threads = 1;
+
+        for (stop_on_error, errors) in [(true, 1), (false, 2)] {
+            config.options.stop_on_error = stop_on_error;
+
+            let result = photo_organizer::organize_sources(
+                &get_organize_sources(&config),
+                &config.directories.organized,
+                &config.directories.duplicates,
+                &get_organize_options(&config),
+                create_on_photiso_event(config.clone()),
+            )
+            .unwrap();
+
+            assert_eq!(result.files_errored, errors);
+            assert_eq!(get_organize_exit_code(&result), EXIT_PARTIAL);
+        }
+        assert_eq!(run_organize(&[config]).unwrap(), EXIT_PARTIAL);
+    }
+}
@@ -0,0 +1,623 @@
+use crate::file_hash::*;
+use crate::file_transfer::*;
+use anyhow::ensure;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    fs::{File, OpenOptions},
+    io,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// The kind of move recorded in the journal.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum MoveKind {
+    PhotoMoved,
+    DuplicatePhotoMoved,
+}
+
+/// Whether a recorded move happened.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveState {
+    /// Recorded before the file is moved. A pending entry without a later committed one is a move
+    /// that failed (or was interrupted), so the file may or may not have moved.
+    Pending,
+    /// Recorded once the file is moved. Journals written before pending entries existed only hold these.
+    #[default]
+    Committed,
+}
+
+/// A single move recorded in the journal.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveJournalEntry {
+    pub kind: MoveKind,
+    #[serde(default)]
+    pub state: MoveState,
+    /// How the file was transferred. Journals written before copies existed only hold moves.
+    #[serde(default)]
+    pub mode: TransferMode,
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub hash: String,
+    pub timestamp: String,
+}
+
+/// An append-only journal of every move, one JSON object per line.
+pub struct MoveJournal {
+    file: File,
+}
+
+impl MoveJournal {
+    /// Opens the journal for appending, creating it if it does not exist.
+    pub fn open(path: &Path) -> anyhow::Result<MoveJournal> {
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(MoveJournal { file })
+    }
+
+    /// Records a pending move. The entry is synced to disk before this returns, so it must be called
+    /// before the move, and the returned entry committed (see `commit`) once the move succeeds.
+    pub fn record(
+        &self,
+        kind: MoveKind,
+        mode: TransferMode,
+        from: &Path,
+        to: &Path,
+        hash: &str,
+    ) -> anyhow::Result<MoveJournalEntry> {
+        // JSON can only hold UTF-8 paths, and a move that cannot be recorded cannot be undone
+        ensure!(
+            from.to_str().is_some() && to.to_str().is_some(),
+            "The journal cannot record a path that is not valid UTF-8, so the file was not moved."
+        );
+
+        let entry = MoveJournalEntry {
+            kind,
+            state: MoveState::Pending,
+            mode,
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            hash: hash.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        self.write(&entry)?;
+
+        Ok(entry)
+    }
+
+    /// Records that a pending move succeeded. The entry is synced to disk before this returns.
+    pub fn commit(&self, pending: &MoveJournalEntry) -> anyhow::Result<()> {
+        let entry = MoveJournalEntry {
+            state: MoveState::Committed,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            ..pending.clone()
+        };
+
+        self.write(&entry)
+    }
+
+    #[doc(hidden)]
+    fn write(&self, entry: &MoveJournalEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        (&self.file).write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+/// Reads the moves in the journal, in the order they were recorded.
+///
+/// A pending entry that a later entry committed is left out. A pending entry that was never committed
+/// (e.g. the run crashed between the move and its commit) is checked against the disk: when the file is at
+/// its destination with the recorded hash (and, for a move, gone from its original location), the move
+/// happened and the entry is read as committed. Otherwise it is read as pending, since it is not known
+/// whether the file moved.
+pub fn read_move_journal(path: &Path) -> anyhow::Result<Vec<MoveJournalEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut entries: Vec<Option<MoveJournalEntry>> = Vec::new();
+    // the pending entries that are not committed yet, by their move
+    let mut pending: HashMap<(PathBuf, PathBuf, String), Vec<usize>> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: MoveJournalEntry = serde_json::from_str(&line)?;
+        let key = (entry.from.clone(), entry.to.clone(), entry.hash.clone());
+        match entry.state {
+            MoveState::Pending => pending.entry(key).or_default().push(entries.len()),
+            MoveState::Committed => {
+                if let Some(i) = pending.get_mut(&key).and_then(|indexes| indexes.pop()) {
+                    entries[i] = None;
+                }
+            }
+        }
+        entries.push(Some(entry));
+    }
+
+    let mut entries: Vec<MoveJournalEntry> = entries.into_iter().flatten().collect();
+    for entry in entries.iter_mut() {
+        if entry.state == MoveState::Pending && is_move_done(entry) {
+            entry.state = MoveState::Committed;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Tests if the move of a pending entry happened, from the files on disk.
+#[doc(hidden)]
+fn is_move_done(entry: &MoveJournalEntry) -> bool {
+    if entry.mode == TransferMode::Move && entry.from.exists() {
+        return false;
+    }
+
+    entry.to.is_file() && get_file_hash(&entry.to).is_ok_and(|hash| hash == entry.hash)
+}
+
+/// An event raised as moves are undone.
+pub enum UndoEvent<'a> {
+    /// Raised when a file is moved back to its original location.
+    PhotoRestored { from: &'a Path, to: &'a Path },
+    /// Raised when a move cannot be undone safely.
+    FileSkipped { file: &'a Path, reason: &'a str },
+    /// Raised when there is an error undoing a move.
+    FileError {
+        file: &'a Path,
+        error: anyhow::Error,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct UndoResult {
+    pub files_restored: u64,
+    pub files_skipped: u64,
+    pub files_errored: u64,
+    pub duration: Duration,
+}
+
+/// Undoes the moves recorded in a journal
+///
+/// # Arguments
+///
+/// * `journal_path` - The journal written while organizing.
+/// * `event_handler` - The handler for listening to events as undo progresses.
+///
+/// Moves are replayed from the last to the first. A move is only undone when the file at its
+/// destination still has the recorded hash and nothing has appeared at its original location since.
+/// A move that was never committed, and that cannot be told to have happened, is skipped.
+///
+/// Once finished, the journal is rewritten to hold only the moves that were not undone (including those
+/// that were never committed).
+///
+/// If the `event_handler` returns true, undo continues; otherwise undo will stop and return.
+///
+pub fn undo<F>(journal_path: &Path, event_handler: F) -> anyhow::Result<UndoResult>
+where
+    F: Fn(UndoEvent) -> bool,
+{
+    let timer = Instant::now();
+
+    let entries = read_move_journal(journal_path)?;
+
+    let mut result = UndoResult {
+        files_restored: 0,
+        files_skipped: 0,
+        files_errored: 0,
+        duration: Duration::default(),
+    };
+
+    // entries that were not undone, most recent first
+    let mut remaining = Vec::new();
+    let mut canceled = false;
+
+    for entry in entries.into_iter().rev() {
+        if canceled {
+            remaining.push(entry);
+            continue;
+        }
+
+        let undone = match entry.state {
+            MoveState::Pending => Ok(Some(
+                "The move was not finished, so the file may not have moved (check it by hand).",
+            )),
+            MoveState::Committed => undo_move(&entry),
+        };
+        let keep_going = match undone {
+            Ok(None) => {
+                result.files_restored += 1;
+                event_handler(UndoEvent::PhotoRestored {
+                    from: &entry.to,
+                    to: &entry.from,
+                })
+            }
+            Ok(Some(reason)) => {
+                result.files_skipped += 1;
+                let keep_going = event_handler(UndoEvent::FileSkipped {
+                    file: &entry.to,
+                    reason,
+                });
+                remaining.push(entry);
+                keep_going
+            }
+            Err(error) => {
+                result.files_errored += 1;
+                let keep_going = event_handler(UndoEvent::FileError {
+                    file: &entry.to,
+                    error,
+                });
+                remaining.push(entry);
+                keep_going
+            }
+        };
+
+        canceled = !keep_going;
+    }
+
+    remaining.reverse();
+    rewrite_move_journal(journal_path, &remaining)?;
+
+    result.duration = timer.elapsed();
+
+    Ok(result)
+}
+
+/// Moves a file back to where it came from, or removes the copy of a file that is still at its original location.
+/// Returns the reason when the move cannot be undone safely.
+#[doc(hidden)]
+fn undo_move(entry: &MoveJournalEntry) -> anyhow::Result<Option<&'static str>> {
+    if !entry.to.exists() {
+        return Ok(Some("File is no longer at its moved location."));
+    }
+
+    if entry.mode != TransferMode::Move && entry.from.exists() {
+        if get_file_hash(&entry.to)? != entry.hash {
+            return Ok(Some("File contents changed since it was copied."));
+        }
+
+        if get_file_hash(&entry.from)? != entry.hash {
+            return Ok(Some("The original file changed since it was copied."));
+        }
+
+        fs::remove_file(&entry.to)?;
+
+        return Ok(None);
+    }
+
+    const ORIGINAL_EXISTS: &str = "A file already exists at the original location.";
+
+    if entry.from.exists() {
+        return Ok(Some(ORIGINAL_EXISTS));
+    }
+
+    if get_file_hash(&entry.to)? != entry.hash {
+        return Ok(Some("File contents changed since it was moved."));
+    }
+
+    // a file may still appear at the original location after it was checked, which is never replaced
+    match move_file_no_clobber(&entry.to, &entry.from, Some(&entry.hash)) {
+        Ok(()) => Ok(None),
+        Err(err) => match err.downcast_ref::<io::Error>() {
+            Some(io_err) if io_err.kind() == io::ErrorKind::AlreadyExists => {
+                Ok(Some(ORIGINAL_EXISTS))
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+/// An event raised as moved photos are verified.
+pub enum VerifyEvent<'a> {
+    /// Raised when a photo is at its moved location with the recorded contents.
+    PhotoVerified { file: &'a Path },
+    /// Raised when a photo is no longer at its moved location.
+    PhotoMissing { file: &'a Path },
+    /// Raised when the contents of a photo changed since it was moved.
+    PhotoChanged { file: &'a Path },
+    /// Raised when a move was not finished, and the photo is not at its moved location.
+    MoveUnfinished { from: &'a Path, to: &'a Path },
+    /// Raised when there is an error verifying a photo.
+    FileError {
+        file: &'a Path,
+        error: anyhow::Error,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct VerifyResult {
+    pub files_verified: u64,
+    pub files_missing: u64,
+    pub files_changed: u64,
+    pub files_unfinished: u64,
+    pub files_errored: u64,
+    pub duration: Duration,
+}
+
+/// Verifies the moves recorded in a journal
+///
+/// # Arguments
+///
+/// * `journal_path` - The journal written while organizing.
+/// * `event_handler` - The handler for listening to events as verify progresses.
+///
+/// Each photo must still be at its moved location with the hash recorded when it was moved.
+/// A move whose destination was later moved again (e.g. by organizing in place) is verified at its
+/// final location only. A move that was never committed (and did not happen) is reported as unfinished.
+/// Nothing is changed.
+///
+/// If the `event_handler` returns true, verify continues; otherwise verify will stop and return.
+///
+pub fn verify<F>(journal_path: &Path, event_handler: F) -> anyhow::Result<VerifyResult>
+where
+    F: Fn(VerifyEvent) -> bool,
+{
+    let timer = Instant::now();
+
+    let entries = read_move_journal(journal_path)?;
+
+    let mut result = VerifyResult {
+        files_verified: 0,
+        files_missing: 0,
+        files_changed: 0,
+        files_unfinished: 0,
+        files_errored: 0,
+        duration: Duration::default(),
+    };
+
+    // the last move from each path
+    let last_moved_from: HashMap<&Path, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.from.as_path(), i))
+        .collect();
+
+    for (i, entry) in entries.iter().enumerate() {
+        // a destination that was moved again is verified by the later move
+        if matches!(last_moved_from.get(entry.to.as_path()), Some(&later) if later > i) {
+            continue;
+        }
+
+        let keep_going = if entry.state == MoveState::Pending {
+            result.files_unfinished += 1;
+            event_handler(VerifyEvent::MoveUnfinished {
+                from: &entry.from,
+                to: &entry.to,
+            })
+        } else if !entry.to.exists() {
+            result.files_missing += 1;
+            event_handler(VerifyEvent::PhotoMissing { file: &entry.to })
+        } else {
+            match get_file_hash(&entry.to) {
+                Ok(hash) if hash == entry.hash => {
+                    result.files_verified += 1;
+                    event_handler(VerifyEvent::PhotoVerified { file: &entry.to })
+                }
+                Ok(_) => {
+                    result.files_changed += 1;
+                    event_handler(VerifyEvent::PhotoChanged { file: &entry.to })
+                }
+                Err(error) => {
+                    result.files_errored += 1;
+                    event_handler(VerifyEvent::FileError {
+                        file: &entry.to,
+                        error,
+                    })
+                }
+            }
+        };
+
+        if !keep_going {
+            break;
+        }
+    }
+
+    result.duration = timer.elapsed();
+
+    Ok(result)
+}
+
+/// Replaces the journal with the given entries, writing to a temporary file first.
+#[doc(hidden)]
+fn rewrite_move_journal(path: &Path, entries: &[MoveJournalEntry]) -> anyhow::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    {
+        let mut file = File::create(&temp_path)?;
+        for entry in entries {
+            let mut line = serde_json::to_string(entry)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_all()?;
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    /// Moves a file the way organize does, recording it in the journal.
+    fn journaled_move(journal: &MoveJournal, from: &Path, to: &Path) {
+        let hash = get_file_hash(from).unwrap();
+        let pending = journal
+            .record(MoveKind::PhotoMoved, TransferMode::Move, from, to, &hash)
+            .unwrap();
+        move_file(from, to, Some(&hash)).unwrap();
+        journal.commit(&pending).unwrap();
+    }
+
+    #[test]
+    fn committed_pending_entries_are_read_once() {
+        let dir = TestDir::new("journal-pending");
+        let journal_path = dir.join("journal.jsonl");
+        let journal = MoveJournal::open(&journal_path).unwrap();
+
+        journaled_move(
+            &journal,
+            &dir.write("in/a.jpg", b"a"),
+            &dir.join("out/a.jpg"),
+        );
+
+        let entries = read_move_journal(&journal_path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, MoveState::Committed);
+    }
+
+    #[test]
+    fn a_move_that_crashed_before_its_commit_can_be_undone() {
+        let dir = TestDir::new("journal-crash");
+        let journal_path = dir.join("journal.jsonl");
+        let journal = MoveJournal::open(&journal_path).unwrap();
+
+        // the file moved, but the run stopped before the move was committed
+        let from = dir.write("in/a.jpg", b"a");
+        let to = dir.join("out/a.jpg");
+        let hash = get_file_hash(&from).unwrap();
+        journal
+            .record(MoveKind::PhotoMoved, TransferMode::Move, &from, &to, &hash)
+            .unwrap();
+        move_file(&from, &to, Some(&hash)).unwrap();
+
+        let entries = read_move_journal(&journal_path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, MoveState::Committed);
+        assert_eq!(verify(&journal_path, |_| true).unwrap().files_verified, 1);
+
+        let result = undo(&journal_path, |_| true).unwrap();
+
+        assert_eq!(result.files_restored, 1);
+        assert_eq!(fs::read(&from).unwrap(), b"a");
+        assert!(!to.exists());
+        assert!(read_move_journal(&journal_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn undo_keeps_and_reports_unfinished_moves() {
+        let dir = TestDir::new("journal-undo");
+        let journal_path = dir.join("journal.jsonl");
+        let journal = MoveJournal::open(&journal_path).unwrap();
+
+        let from = dir.write("in/a.jpg", b"a");
+        let to = dir.join("out/a.jpg");
+        journaled_move(&journal, &from, &to);
+
+        // a move that failed after it was recorded
+        let failed_from = dir.write("in/b.jpg", b"b");
+        journal
+            .record(
+                MoveKind::PhotoMoved,
+                TransferMode::Move,
+                &failed_from,
+                &dir.join("out/b.jpg"),
+                &get_file_hash(&failed_from).unwrap(),
+            )
+            .unwrap();
+
+        let skipped = std::sync::Mutex::new(Vec::new());
+        let result = undo(&journal_path, |event| {
+            if let UndoEvent::FileSkipped { file, .. } = event {
+                skipped.lock().unwrap().push(file.to_path_buf());
+            }
+            true
+        })
+        .unwrap();
+
+        assert_eq!(result.files_restored, 1);
+        assert_eq!(result.files_skipped, 1);
+        assert_eq!(result.files_errored, 0);
+        assert_eq!(skipped.into_inner().unwrap(), [dir.join("out/b.jpg")]);
+        assert_eq!(fs::read(&from).unwrap(), b"a");
+        assert!(!to.exists());
+        assert!(failed_from.exists());
+        let entries = read_move_journal(&journal_path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].from, failed_from);
+        assert_eq!(entries[0].state, MoveState::Pending);
+    }
+
+    #[test]
+    fn undo_never_replaces_a_file_at_the_original_location() {
+        let dir = TestDir::new("journal-no-clobber");
+        let journal_path = dir.join("journal.jsonl");
+        let journal = MoveJournal::open(&journal_path).unwrap();
+
+        let from = dir.write("in/a.jpg", b"a");
+        let to = dir.join("out/a.jpg");
+        journaled_move(&journal, &from, &to);
+        dir.write("in/a.jpg", b"new");
+
+        let result = undo(&journal_path, |_| true).unwrap();
+
+        assert_eq!(result.files_restored, 0);
+        assert_eq!(result.files_skipped, 1);
+        assert_eq!(fs::read(&from).unwrap(), b"new");
+        assert_eq!(fs::read(&to).unwrap(), b"a");
+        assert_eq!(read_move_journal(&journal_path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn move_file_no_clobber_fails_when_the_destination_exists() {
+        let dir = TestDir::new("journal-rename");
+        let from = dir.write("a.jpg", b"a");
+        let to = dir.write("b.jpg", b"b");
+
+        let err = move_file_no_clobber(&from, &to, None).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(|err| err.kind()),
+            Some(io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(fs::read(&from).unwrap(), b"a");
+        assert_eq!(fs::read(&to).unwrap(), b"b");
+    }
+
+    #[test]
+    fn verify_reports_unfinished_moves() {
+        let dir = TestDir::new("journal-verify");
+        let journal_path = dir.join("journal.jsonl");
+        let journal = MoveJournal::open(&journal_path).unwrap();
+
+        journaled_move(
+            &journal,
+            &dir.write("in/a.jpg", b"a"),
+            &dir.join("out/a.jpg"),
+        );
+        journal
+            .record(
+                MoveKind::PhotoMoved,
+                TransferMode::Move,
+                &dir.write("in/b.jpg", b"b"),
+                &dir.join("out/b.jpg"),
+                "HASH",
+            )
+            .unwrap();
+
+        let result = verify(&journal_path, |_| true).unwrap();
+
+        assert_eq!(result.files_verified, 1);
+        assert_eq!(result.files_missing, 0);
+        assert_eq!(result.files_unfinished, 1);
+    }
+}
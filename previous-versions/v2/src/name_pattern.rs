@@ -0,0 +1,434 @@
+use anyhow::{bail, ensure};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, path::Path};
+
+/// The default name patterns, in order: camera and phone file names, screenshots, messaging apps,
+/// dated file names, and dated folders.
+pub const DEFAULT_NAME_PATTERNS: &[&str] = &[
+    "PXL_{year}{month}{day}_{hour}{minute}{second}{fraction}",
+    "{year}{month}{day}_{hour}{minute}{second}",
+    "{year}-{month}-{day} at {hour}.{minute}.{second} {ampm}",
+    "{year}-{month}-{day} at {hour}.{minute}.{second}",
+    "Screenshot_{year}{month}{day}-{hour}{minute}{second}",
+    "IMG-{year}{month}{day}-WA",
+    "VID-{year}{month}{day}-WA",
+    "{year}-{month}-{day}",
+    "{year}/{month}/{day}",
+    "{year}/{month}",
+];
+
+/// A pattern of the date-time in the name of a photo (e.g. `IMG_{year}{month}{day}_{hour}{minute}{second}`),
+/// for photos without a date-time in their metadata (e.g. scans and screenshots).
+///
+/// A pattern is found anywhere in the file name. A pattern with a `/` is found in the path of the
+/// directory instead (e.g. `{year}/{month}` finds `2004/07 Vacation`), nearest the file first.
+///
+/// * `{year}` - 4 digits, the only token that is required.
+/// * `{month}`, `{day}`, `{hour}`, `{minute}`, `{second}` - 2 digits (or 1, e.g. `7.05.09 PM`). Those not
+///   in the pattern are the first month, day, or 0.
+/// * `{fraction}` - 1 to 9 digits of a second.
+/// * `{ampm}` - `AM` or `PM`, for a 12-hour `{hour}`.
+///
+/// Letters match ignoring case and a space matches any space (e.g. a narrow no-break space).
+/// A pattern that starts or ends with a number does not match in the middle of a longer number.
+/// Use `{{` and `}}` for literal braces.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct NamePattern {
+    text: String,
+    parts: Vec<NamePatternPart>,
+    /// True if the pattern is found in the path of the directory rather than the file name
+    in_dir: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NamePatternPart {
+    Literal(char),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Fraction,
+    AmPm,
+}
+
+/// The parts of a date-time found so far.
+#[derive(Default)]
+struct FoundDateTime {
+    year: Option<i32>,
+    month: Option<u32>,
+    day: Option<u32>,
+    hour: Option<u32>,
+    minute: Option<u32>,
+    second: Option<u32>,
+    nanosecond: Option<u32>,
+    pm: Option<bool>,
+}
+
+impl NamePattern {
+    /// Parses and validates a name pattern.
+    pub fn parse(text: &str) -> anyhow::Result<NamePattern> {
+        let mut parts = Vec::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    parts.push(NamePatternPart::Literal('{'));
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    parts.push(NamePatternPart::Literal('}'));
+                }
+                '{' => {
+                    let mut token = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => token.push(c),
+                            None => bail!(
+                                "The name pattern '{}' has a '{{' without a '}}' (use '{{{{' for a literal brace).",
+                                text
+                            ),
+                        }
+                    }
+                    parts.push(match token.as_str() {
+                        "year" => NamePatternPart::Year,
+                        "month" => NamePatternPart::Month,
+                        "day" => NamePatternPart::Day,
+                        "hour" => NamePatternPart::Hour,
+                        "minute" => NamePatternPart::Minute,
+                        "second" => NamePatternPart::Second,
+                        "fraction" => NamePatternPart::Fraction,
+                        "ampm" => NamePatternPart::AmPm,
+                        _ => bail!(
+                            "Unknown token '{{{}}}' in the name pattern '{}' (expected year, month, day, hour, minute, second, fraction, or ampm).",
+                            token,
+                            text
+                        ),
+                    });
+                }
+                '}' => bail!(
+                    "The name pattern '{}' has a '}}' without a '{{' (use '}}}}' for a literal brace).",
+                    text
+                ),
+                '\\' => parts.push(NamePatternPart::Literal('/')),
+                c => parts.push(NamePatternPart::Literal(c)),
+            }
+        }
+
+        ensure!(
+            parts.contains(&NamePatternPart::Year),
+            "The name pattern '{}' must contain {{year}}.",
+            text
+        );
+        for token in [
+            NamePatternPart::Year,
+            NamePatternPart::Month,
+            NamePatternPart::Day,
+            NamePatternPart::Hour,
+            NamePatternPart::Minute,
+            NamePatternPart::Second,
+            NamePatternPart::Fraction,
+            NamePatternPart::AmPm,
+        ] {
+            ensure!(
+                parts.iter().filter(|part| **part == token).count() <= 1,
+                "The name pattern '{}' contains {{{}}} more than once.",
+                text,
+                format!("{:?}", token).to_lowercase()
+            );
+        }
+
+        let in_dir = parts.contains(&NamePatternPart::Literal('/'));
+
+        Ok(NamePattern {
+            text: text.to_string(),
+            parts,
+            in_dir,
+        })
+    }
+
+    /// The default name patterns.
+    pub fn defaults() -> Vec<NamePattern> {
+        DEFAULT_NAME_PATTERNS
+            .iter()
+            .map(|text| NamePattern::parse(text).unwrap())
+            .collect()
+    }
+
+    /// The pattern as written.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Finds the date-time of the pattern in the name (or directory) of a file, nearest the end first.
+    /// Matches that are not a valid date-time are passed over.
+    pub fn find(&self, file_path: &Path) -> Option<NaiveDateTime> {
+        let text = if self.in_dir {
+            let dir = file_path.parent()?.to_string_lossy();
+            format!("{}/", dir.replace('\\', "/"))
+        } else {
+            file_path.file_name()?.to_string_lossy().into_owned()
+        };
+        let chars: Vec<char> = text.chars().collect();
+
+        (0..chars.len())
+            .rev()
+            .find_map(|start| self.find_at(&chars, start))
+    }
+
+    /// Matches the pattern at `start`, giving the date-time if it is a valid one.
+    #[doc(hidden)]
+    fn find_at(&self, chars: &[char], start: usize) -> Option<NaiveDateTime> {
+        let is_digit_at = |i: usize| chars.get(i).is_some_and(|c| c.is_ascii_digit());
+        if self.starts_with_number() && start > 0 && is_digit_at(start - 1) {
+            return None;
+        }
+
+        let mut found = FoundDateTime::default();
+        let mut i = start;
+        for part in &self.parts {
+            match part {
+                NamePatternPart::Literal(expected) => {
+                    let c = chars.get(i)?;
+                    let is_match = if expected.is_whitespace() {
+                        c.is_whitespace()
+                    } else {
+                        c.to_lowercase().eq(expected.to_lowercase())
+                    };
+                    if !is_match {
+                        return None;
+                    }
+                    i += 1;
+                }
+                NamePatternPart::Year => {
+                    found.year = Some(read_digits(chars, &mut i, 4, 4)?.0 as i32)
+                }
+                NamePatternPart::Month => found.month = Some(read_digits(chars, &mut i, 1, 2)?.0),
+                NamePatternPart::Day => found.day = Some(read_digits(chars, &mut i, 1, 2)?.0),
+                NamePatternPart::Hour => found.hour = Some(read_digits(chars, &mut i, 1, 2)?.0),
+                NamePatternPart::Minute => found.minute = Some(read_digits(chars, &mut i, 1, 2)?.0),
+                NamePatternPart::Second => found.second = Some(read_digits(chars, &mut i, 1, 2)?.0),
+                NamePatternPart::Fraction => {
+                    let (fraction, digits) = read_digits(chars, &mut i, 1, 9)?;
+                    found.nanosecond = Some(fraction * 10u32.pow(9 - digits as u32));
+                }
+                NamePatternPart::AmPm => {
+                    let text: String = chars.get(i..i + 2)?.iter().collect();
+                    found.pm = match text.to_ascii_uppercase().as_str() {
+                        "AM" => Some(false),
+                        "PM" => Some(true),
+                        _ => return None,
+                    };
+                    i += 2;
+                }
+            }
+        }
+
+        if self.ends_with_number() && is_digit_at(i) {
+            return None;
+        }
+
+        found.to_date_time()
+    }
+
+    #[doc(hidden)]
+    fn starts_with_number(&self) -> bool {
+        !matches!(
+            self.parts.first(),
+            Some(NamePatternPart::Literal(_)) | Some(NamePatternPart::AmPm)
+        )
+    }
+
+    #[doc(hidden)]
+    fn ends_with_number(&self) -> bool {
+        !matches!(
+            self.parts.last(),
+            Some(NamePatternPart::Literal(_)) | Some(NamePatternPart::AmPm)
+        )
+    }
+}
+
+impl TryFrom<String> for NamePattern {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<NamePattern> {
+        NamePattern::parse(&text)
+    }
+}
+
+impl fmt::Display for NamePattern {
+    /// Formats the pattern as it is configured.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl FoundDateTime {
+    /// The date-time, if its parts make a valid one. Years before 1800 or after 2199 are not dates.
+    fn to_date_time(&self) -> Option<NaiveDateTime> {
+        let year = self.year?;
+        if !(1800..2200).contains(&year) {
+            return None;
+        }
+
+        let mut hour = self.hour.unwrap_or(0);
+        if let Some(pm) = self.pm {
+            if !(1..=12).contains(&hour) {
+                return None;
+            }
+            hour = hour % 12 + if pm { 12 } else { 0 };
+        }
+
+        NaiveDate::from_ymd_opt(year, self.month.unwrap_or(1), self.day.unwrap_or(1))?
+            .and_hms_nano_opt(
+                hour,
+                self.minute.unwrap_or(0),
+                self.second.unwrap_or(0),
+                self.nanosecond.unwrap_or(0),
+            )
+    }
+}
+
+/// Reads a number of `min` to `max` digits (as many as there are) at `i`, moving `i` past them.
+/// Gives the number and its count of digits.
+#[doc(hidden)]
+fn read_digits(chars: &[char], i: &mut usize, min: usize, max: usize) -> Option<(u32, usize)> {
+    let count = chars
+        .get(*i..)?
+        .iter()
+        .take(max)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if count < min {
+        return None;
+    }
+
+    let number = chars[*i..*i + count]
+        .iter()
+        .fold(0, |number, c| number * 10 + c.to_digit(10).unwrap_or(0));
+    *i += count;
+
+    Some((number, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, path: &str) -> Option<String> {
+        NamePattern::parse(pattern)
+            .unwrap()
+            .find(Path::new(path))
+            .map(|date_time| date_time.to_string())
+    }
+
+    #[test]
+    fn finds_date_times_in_file_names() {
+        for (pattern, path, date_time) in [
+            (
+                "PXL_{year}{month}{day}_{hour}{minute}{second}{fraction}",
+                "PXL_20230704_123456789.jpg",
+                "2023-07-04 12:34:56.789",
+            ),
+            (
+                "{year}{month}{day}_{hour}{minute}{second}",
+                "photos/IMG_20230704_123456.jpg",
+                "2023-07-04 12:34:56",
+            ),
+            (
+                "{year}-{month}-{day} at {hour}.{minute}.{second} {ampm}",
+                "Screenshot 2023-07-04 at 7.05.09\u{202f}pm.png",
+                "2023-07-04 19:05:09",
+            ),
+            (
+                "{year}-{month}-{day} at {hour}.{minute}.{second} {ampm}",
+                "Screenshot 2023-07-04 at 12.05.09 AM.png",
+                "2023-07-04 00:05:09",
+            ),
+            (
+                "IMG-{year}{month}{day}-WA",
+                "img-20210704-wa0001.jpg",
+                "2021-07-04 00:00:00",
+            ),
+            ("{{{year}}}", "scan {1998}.tif", "1998-01-01 00:00:00"),
+        ] {
+            assert_eq!(find(pattern, path).as_deref(), Some(date_time), "{}", path);
+        }
+    }
+
+    #[test]
+    fn finds_date_times_in_directories() {
+        assert_eq!(
+            find("{year}/{month}", "photos/2004/07 Vacation/scan.jpg").as_deref(),
+            Some("2004-07-01 00:00:00")
+        );
+        // a pattern with a / is not found in the file name
+        assert_eq!(find("{year}/{month}", "photos/2004_07.jpg"), None);
+        // nor a pattern without one in the directory
+        assert_eq!(find("{year}-{month}-{day}", "2004-07-04/scan.jpg"), None);
+    }
+
+    #[test]
+    fn finds_the_date_time_nearest_the_end() {
+        assert_eq!(
+            find("{year}-{month}-{day}", "2019-01-01 copy of 2020-02-02.jpg").as_deref(),
+            Some("2020-02-02 00:00:00")
+        );
+        // an invalid date-time nearer the end is passed over
+        assert_eq!(
+            find("{year}-{month}-{day}", "2019-01-01 copy of 2020-02-31.jpg").as_deref(),
+            Some("2019-01-01 00:00:00")
+        );
+    }
+
+    #[test]
+    fn does_not_find_invalid_date_times() {
+        for (pattern, path) in [
+            ("IMG-{year}{month}{day}-WA", "IMG-20230231-WA0001.jpg"),
+            ("IMG-{year}{month}{day}-WA", "IMG-10000101-WA0001.jpg"),
+            (
+                "{year}{month}{day}_{hour}{minute}{second}",
+                "20230704_256000.jpg",
+            ),
+            (
+                "{year}-{month}-{day} {hour}.{minute} {ampm}",
+                "2023-07-04 13.05 PM.jpg",
+            ),
+            // a number does not match in the middle of a longer number
+            (
+                "{year}{month}{day}_{hour}{minute}{second}",
+                "120230704_123456.jpg",
+            ),
+            (
+                "{year}{month}{day}_{hour}{minute}{second}",
+                "20230704_1234567.jpg",
+            ),
+            ("{year}-{month}-{day}", "no date.jpg"),
+        ] {
+            assert_eq!(find(pattern, path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn bad_patterns_are_errors() {
+        for pattern in [
+            "{month}{day}",
+            "{year}{yr}",
+            "{year}{month}{month}",
+            "{year}}",
+            "{year",
+        ] {
+            assert!(NamePattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn the_default_patterns_parse() {
+        assert_eq!(NamePattern::defaults().len(), DEFAULT_NAME_PATTERNS.len());
+    }
+}
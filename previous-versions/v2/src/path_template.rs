@@ -0,0 +1,426 @@
+use anyhow::{bail, ensure};
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
+
+#[doc(hidden)]
+pub use chrono::NaiveDateTime;
+
+/// The default template for organized photos (i.e. YYYY/MM/YYYY-MM-DD HH-MM-SS-FFFFFFFFF CCC.ext)
+pub const DEFAULT_ORGANIZED_TEMPLATE: &str = "{year}/{month}/{date} {time}{conflict}{ext}";
+
+/// The default template for duplicate photos (i.e. YYYY/MM/hash.CCC.ext)
+pub const DEFAULT_DUPLICATES_TEMPLATE: &str = "{year}/{month}/{hash}{conflict:.}{ext}";
+
+/// A template for the path of a photo, relative to the organized or duplicates directory.
+///
+/// Each `/` starts a new directory. Tokens in braces are replaced with values of the photo:
+///
+/// * `{year}`, `{month}`, `{day}`, `{hour}`, `{minute}`, `{second}`, `{fraction}` - parts of the date-time taken.
+/// * `{date}` - the date taken as YYYY-MM-DD.
+/// * `{time}` - the time taken as HH-MM-SS-FFFFFFFFF.
+/// * `{make}`, `{model}` - the camera make and model, or `Unknown`.
+/// * `{name}` - the original file name without its extension.
+/// * `{hash}` - the SHA256 hash of the photo. `{hash:N}` uses the first N characters.
+/// * `{conflict}` - a 3-digit number when another photo already has the path, preceded by a space.
+///   `{conflict:S}` precedes the number with S instead.
+/// * `{ext}` - the original (or fixed) extension, made lowercase, with a leading dot.
+///
+/// Use `{{` and `}}` for literal braces.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct PathTemplate {
+    text: String,
+    components: Vec<Vec<PathTemplatePart>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PathTemplatePart {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Fraction,
+    Date,
+    Time,
+    Make,
+    Model,
+    Name,
+    Hash(Option<usize>),
+    Conflict(String),
+    Ext,
+}
+
+/// The values of a photo used to fill in a path template.
+pub struct PathTemplateValues<'a> {
+    pub file_path: &'a Path,
+    /// The date-time the photo was taken, in the timezone of paths
+    pub date_time: &'a NaiveDateTime,
+    pub make: Option<&'a str>,
+    pub model: Option<&'a str>,
+    /// The extension to use instead of the extension of `file_path` (without a leading dot)
+    pub extension: Option<&'a str>,
+    pub hash: Option<&'a str>,
+    pub conflict: u32,
+}
+
+impl PathTemplate {
+    /// Parses and validates a path template.
+    pub fn parse(text: &str) -> anyhow::Result<PathTemplate> {
+        ensure!(!text.trim().is_empty(), "The path template is empty.");
+
+        let mut components = Vec::new();
+        for component in text.split(['/', '\\']) {
+            ensure!(
+                !component.is_empty(),
+                "The path template '{}' has an empty directory name.",
+                text
+            );
+            ensure!(
+                component != "." && component != "..",
+                "The path template '{}' cannot contain '.' or '..' directories.",
+                text
+            );
+
+            components.push(parse_component(text, component)?);
+        }
+
+        let conflicts = components
+            .iter()
+            .flatten()
+            .filter(|part| matches!(part, PathTemplatePart::Conflict(_)))
+            .count();
+        ensure!(
+            conflicts == 1,
+            "The path template '{}' must contain {{conflict}} exactly once.",
+            text
+        );
+
+        let last = components.last().unwrap();
+        ensure!(
+            last.iter()
+                .any(|part| matches!(part, PathTemplatePart::Conflict(_))),
+            "The path template '{}' must contain {{conflict}} in the file name.",
+            text
+        );
+
+        Ok(PathTemplate {
+            text: text.to_string(),
+            components,
+        })
+    }
+
+    /// The default template for organized photos.
+    pub fn organized_default() -> PathTemplate {
+        PathTemplate::parse(DEFAULT_ORGANIZED_TEMPLATE).unwrap()
+    }
+
+    /// The default template for duplicate photos.
+    pub fn duplicates_default() -> PathTemplate {
+        PathTemplate::parse(DEFAULT_DUPLICATES_TEMPLATE).unwrap()
+    }
+
+    /// The template as written.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// True if the template needs the hash of the photo.
+    pub fn uses_hash(&self) -> bool {
+        self.components
+            .iter()
+            .flatten()
+            .any(|part| matches!(part, PathTemplatePart::Hash(_)))
+    }
+
+    /// Fills in the template and places the result under `base_dir`.
+    pub fn render(&self, values: &PathTemplateValues, base_dir: &Path) -> anyhow::Result<PathBuf> {
+        let mut dest_path = PathBuf::from(base_dir);
+
+        for component in self.components.iter() {
+            let mut name = String::new();
+            for part in component.iter() {
+                name.push_str(&render_part(part, values)?);
+            }
+
+            ensure!(
+                !name.trim().is_empty() && name != "." && name != "..",
+                "The path template '{}' produced an invalid name '{}'.",
+                self.text,
+                name
+            );
+
+            dest_path.push(name);
+        }
+
+        Ok(dest_path)
+    }
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<PathTemplate> {
+        PathTemplate::parse(&text)
+    }
+}
+
+#[doc(hidden)]
+fn parse_component(text: &str, component: &str) -> anyhow::Result<Vec<PathTemplatePart>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = component.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => token.push(c),
+                        None => bail!("The path template '{}' has an unclosed '{{'.", text),
+                    }
+                }
+
+                if !literal.is_empty() {
+                    parts.push(PathTemplatePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(parse_token(text, &token)?);
+            }
+            '}' => bail!("The path template '{}' has an unmatched '}}'.", text),
+            _ => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        parts.push(PathTemplatePart::Literal(literal));
+    }
+
+    Ok(parts)
+}
+
+#[doc(hidden)]
+fn parse_token(text: &str, token: &str) -> anyhow::Result<PathTemplatePart> {
+    let (name, argument) = match token.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (token, None),
+    };
+
+    let part = match (name, argument) {
+        ("year", None) => PathTemplatePart::Year,
+        ("month", None) => PathTemplatePart::Month,
+        ("day", None) => PathTemplatePart::Day,
+        ("hour", None) => PathTemplatePart::Hour,
+        ("minute", None) => PathTemplatePart::Minute,
+        ("second", None) => PathTemplatePart::Second,
+        ("fraction", None) => PathTemplatePart::Fraction,
+        ("date", None) => PathTemplatePart::Date,
+        ("time", None) => PathTemplatePart::Time,
+        ("make", None) => PathTemplatePart::Make,
+        ("model", None) => PathTemplatePart::Model,
+        ("name", None) => PathTemplatePart::Name,
+        ("hash", None) => PathTemplatePart::Hash(None),
+        ("hash", Some(length)) => match length.parse::<usize>() {
+            Ok(length) if length > 0 => PathTemplatePart::Hash(Some(length)),
+            _ => bail!(
+                "The path template '{}' has an invalid hash length '{}'.",
+                text,
+                length
+            ),
+        },
+        ("conflict", None) => PathTemplatePart::Conflict(String::from(" ")),
+        ("conflict", Some(separator)) => PathTemplatePart::Conflict(separator.to_string()),
+        ("ext", None) => PathTemplatePart::Ext,
+        _ => bail!(
+            "The path template '{}' has an unknown token '{{{}}}'.",
+            text,
+            token
+        ),
+    };
+
+    Ok(part)
+}
+
+#[doc(hidden)]
+fn render_part(part: &PathTemplatePart, values: &PathTemplateValues) -> anyhow::Result<String> {
+    let date_time = values.date_time;
+
+    let text = match part {
+        PathTemplatePart::Literal(text) => text.clone(),
+        PathTemplatePart::Year => date_time.format("%Y").to_string(),
+        PathTemplatePart::Month => date_time.format("%m").to_string(),
+        PathTemplatePart::Day => date_time.format("%d").to_string(),
+        PathTemplatePart::Hour => date_time.format("%H").to_string(),
+        PathTemplatePart::Minute => date_time.format("%M").to_string(),
+        PathTemplatePart::Second => date_time.format("%S").to_string(),
+        PathTemplatePart::Fraction => date_time.format("%f").to_string(),
+        PathTemplatePart::Date => date_time.format("%Y-%m-%d").to_string(),
+        PathTemplatePart::Time => date_time.format("%H-%M-%S-%f").to_string(),
+        PathTemplatePart::Make => sanitize(values.make.unwrap_or("Unknown")),
+        PathTemplatePart::Model => sanitize(values.model.unwrap_or("Unknown")),
+        PathTemplatePart::Name => match values.file_path.file_stem() {
+            Some(stem) => sanitize(&stem.to_string_lossy()),
+            None => String::new(),
+        },
+        PathTemplatePart::Hash(length) => match values.hash {
+            Some(hash) => match length {
+                Some(length) => hash.chars().take(*length).collect(),
+                None => hash.to_string(),
+            },
+            None => bail!("The photo hash is required by the path template."),
+        },
+        PathTemplatePart::Conflict(separator) => {
+            if values.conflict > 0 {
+                format!("{}{:03}", separator, values.conflict)
+            } else {
+                String::new()
+            }
+        }
+        // extension is maintained, but made lowercase for consistency
+        PathTemplatePart::Ext => match (values.extension, values.file_path.extension()) {
+            (Some(ext), _) => format!(".{}", ext.to_lowercase()),
+            (None, Some(ext)) => format!(".{}", ext.to_string_lossy().to_lowercase()),
+            (None, None) => String::new(),
+        },
+    };
+
+    Ok(text)
+}
+
+/// Replaces characters that cannot appear in a file name.
+#[doc(hidden)]
+fn sanitize(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn render(template: &str, file_path: &str, hash: Option<&str>, conflict: u32) -> PathBuf {
+        let date_time = NaiveDate::from_ymd_opt(2021, 7, 4)
+            .unwrap()
+            .and_hms_nano_opt(9, 5, 3, 120_000_000)
+            .unwrap();
+        let values = PathTemplateValues {
+            file_path: Path::new(file_path),
+            date_time: &date_time,
+            make: Some("Canon"),
+            model: Some("Canon EOS R5"),
+            extension: None,
+            hash,
+            conflict,
+        };
+
+        PathTemplate::parse(template)
+            .unwrap()
+            .render(&values, Path::new("organized"))
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_the_default_templates() {
+        assert_eq!(
+            render(DEFAULT_ORGANIZED_TEMPLATE, "in/IMG_0001.JPG", None, 0),
+            Path::new("organized/2021/07/2021-07-04 09-05-03-120000000.jpg")
+        );
+        assert_eq!(
+            render(DEFAULT_ORGANIZED_TEMPLATE, "in/IMG_0001.JPG", None, 2),
+            Path::new("organized/2021/07/2021-07-04 09-05-03-120000000 002.jpg")
+        );
+        assert_eq!(
+            render(DEFAULT_DUPLICATES_TEMPLATE, "in/a.png", Some("ABCDEF"), 1),
+            Path::new("organized/2021/07/ABCDEF.001.png")
+        );
+    }
+
+    #[test]
+    fn renders_tokens_and_escapes() {
+        assert_eq!(
+            render(
+                "{make}/{model}/{{{name}}} {hash:4}{conflict:_}{ext}",
+                "in/a:b.Jpeg",
+                Some("ABCDEF"),
+                0
+            ),
+            Path::new("organized/Canon/Canon EOS R5/{a_b} ABCD.jpeg")
+        );
+        assert_eq!(
+            render(
+                "{day}-{hour}{minute}{second}.{fraction}{conflict}",
+                "a",
+                None,
+                0
+            ),
+            Path::new("organized/04-090503.120000000")
+        );
+    }
+
+    #[test]
+    fn uses_hash_only_with_a_hash_token() {
+        assert!(PathTemplate::duplicates_default().uses_hash());
+        assert!(!PathTemplate::organized_default().uses_hash());
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in [
+            "",
+            "{year}//{conflict}",
+            "../{conflict}",
+            "{year}/{month}",
+            "{conflict}/{name}",
+            "{name}{conflict}{conflict}",
+            "{nope}{conflict}",
+            "{hash:0}{conflict}",
+            "{name{conflict}",
+            "{name}}{conflict}",
+        ] {
+            assert!(PathTemplate::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn a_missing_hash_is_an_error() {
+        let date_time = NaiveDate::from_ymd_opt(2021, 7, 4)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let values = PathTemplateValues {
+            file_path: Path::new("a.jpg"),
+            date_time: &date_time,
+            make: None,
+            model: None,
+            extension: None,
+            hash: None,
+            conflict: 0,
+        };
+
+        assert!(PathTemplate::duplicates_default()
+            .render(&values, Path::new("duplicates"))
+            .is_err());
+    }
+}
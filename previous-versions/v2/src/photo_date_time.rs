@@ -1,99 +1,471 @@
-use chrono::TimeZone;
+use crate::capture_date_time::*;
+use crate::clock_correction::*;
+use crate::gps_position::*;
+use crate::iso_bmff::*;
+use crate::name_pattern::*;
+use crate::raw_exif::*;
+use crate::video_date_time::*;
+use anyhow::bail;
+use chrono::{Offset, TimeZone};
+use chrono_tz::Tz;
 use exif::{In, Tag};
-use std::{fs, fs::File, path::Path};
+use serde::Deserialize;
+use std::{fmt, fs, fs::File, io::Seek, path::Path, str::FromStr};
 
 #[doc(hidden)]
-pub use anyhow::*;
+pub use chrono::Utc;
+
+/// Where the date-time of a photo comes from.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum DateSource {
+    /// The EXIF `DateTimeOriginal`: when the photo was taken
+    ExifOriginal,
+    /// The EXIF `DateTimeDigitized`: when the photo was digitized to camera memory
+    ExifDigitized,
+    /// The EXIF `DateTime`: when the photo was taken (least precise)
+    ExifBase,
+    /// The GPS date and time stamps, in the timezone of the GPS position
+    GpsTime,
+    /// The QuickTime creation date (or AVI IDIT) metadata of a video
+    VideoMetadata,
+    /// The movie header of a video
+    VideoMovieHeader,
+    /// The track header of a video
+    VideoTrackHeader,
+    /// A name pattern of the file name (or directory)
+    FileName,
+    /// When the file was created
+    FileCreated,
+    /// When the file was last modified
+    FileModified,
+}
 
-#[doc(hidden)]
-pub use chrono::{DateTime, Utc};
+/// The default order of the date sources: EXIF, then GPS, then video metadata, then the file name.
+/// File times are left out, as `best` falls back to the earliest of them.
+pub const DEFAULT_DATE_SOURCES: &[DateSource] = &[
+    DateSource::ExifOriginal,
+    DateSource::ExifDigitized,
+    DateSource::ExifBase,
+    DateSource::GpsTime,
+    DateSource::VideoMetadata,
+    DateSource::VideoMovieHeader,
+    DateSource::VideoTrackHeader,
+    DateSource::FileName,
+];
+
+impl DateSource {
+    /// Every date source.
+    pub const ALL: [DateSource; 10] = [
+        DateSource::ExifOriginal,
+        DateSource::ExifDigitized,
+        DateSource::ExifBase,
+        DateSource::GpsTime,
+        DateSource::VideoMetadata,
+        DateSource::VideoMovieHeader,
+        DateSource::VideoTrackHeader,
+        DateSource::FileName,
+        DateSource::FileCreated,
+        DateSource::FileModified,
+    ];
+
+    /// The name of the source in the config (e.g. `exifOriginal`).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DateSource::ExifOriginal => "exifOriginal",
+            DateSource::ExifDigitized => "exifDigitized",
+            DateSource::ExifBase => "exifBase",
+            DateSource::GpsTime => "gpsTime",
+            DateSource::VideoMetadata => "videoMetadata",
+            DateSource::VideoMovieHeader => "videoMovieHeader",
+            DateSource::VideoTrackHeader => "videoTrackHeader",
+            DateSource::FileName => "fileName",
+            DateSource::FileCreated => "fileCreated",
+            DateSource::FileModified => "fileModified",
+        }
+    }
+
+    /// The name of the source for people (e.g. `exif original`).
+    pub fn label(&self) -> &'static str {
+        match self {
+            DateSource::ExifOriginal => "exif original",
+            DateSource::ExifDigitized => "exif digitized",
+            DateSource::ExifBase => "exif base",
+            DateSource::GpsTime => "gps time",
+            DateSource::VideoMetadata => "video metadata",
+            DateSource::VideoMovieHeader => "video movie header",
+            DateSource::VideoTrackHeader => "video track header",
+            DateSource::FileName => "file name",
+            DateSource::FileCreated => "file created",
+            DateSource::FileModified => "file modified",
+        }
+    }
+}
+
+impl FromStr for DateSource {
+    type Err = anyhow::Error;
+
+    /// Parses the name of a source in the config (e.g. `exifOriginal`).
+    fn from_str(text: &str) -> anyhow::Result<DateSource> {
+        match DateSource::ALL
+            .iter()
+            .find(|source| source.as_str() == text)
+        {
+            Some(source) => Ok(*source),
+            None => bail!(
+                "Unknown date source '{}' (expected one of {}).",
+                text,
+                DateSource::ALL
+                    .iter()
+                    .map(DateSource::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DateSource {
+    /// Formats the source as it is configured.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
 /// Date-time information for a photo
+#[derive(Default)]
 pub struct PhotoDateTimeInfo {
     /// When the file was created
     created: chrono::DateTime<Utc>,
     /// When the file was last modified
     modified: chrono::DateTime<Utc>,
     /// When the photo was taken (least precise)
-    exif_base: Option<chrono::DateTime<Utc>>,
+    exif_base: Option<CaptureDateTime>,
     /// When the photo was originally taken (most precise)
-    exif_original: Option<chrono::DateTime<Utc>>,
+    exif_original: Option<CaptureDateTime>,
     /// When the photo was digitized to camera memory
-    exif_digitized: Option<chrono::DateTime<Utc>>,
+    exif_digitized: Option<CaptureDateTime>,
+    /// When the photo was taken, from the GPS date and time stamps (in the timezone of the GPS position, if known)
+    gps_time: Option<CaptureDateTime>,
+    /// Where the photo was taken, from the GPS tags
+    gps_position: Option<GpsPosition>,
+    /// The timezone at the GPS position, if known
+    gps_time_zone: Option<Tz>,
+    /// When the photo was taken, from the GPS date and time stamps
+    gps_utc: Option<chrono::DateTime<Utc>>,
+    /// The EXIF date-times whose offset was found from the GPS position or time, rather than recorded
+    gps_offset_sources: Vec<DateSource>,
+    /// When the video was taken, from the QuickTime creation date (or AVI IDIT) metadata
+    video_metadata_created: Option<CaptureDateTime>,
+    /// When the video was created, from the movie header
+    video_movie_created: Option<CaptureDateTime>,
+    /// When the video was created, from the track header
+    video_track_created: Option<CaptureDateTime>,
+    /// When the photo was taken, from a name pattern of its file name (or directory)
+    name: Option<CaptureDateTime>,
+    /// The make of the camera that took the photo
+    camera_make: Option<String>,
+    /// The model of the camera that took the photo
+    camera_model: Option<String>,
+    /// The serial number of the camera body that took the photo
+    camera_serial: Option<String>,
+    /// Problems that did not stop the date-times from being read (e.g. a GPS timezone that could not be found)
+    warnings: Vec<String>,
 }
 
 impl PhotoDateTimeInfo {
-    /// Finds the date-time that is best to use as the taken date-time.
-    /// This prefers exif original, digitized, and base (in order).
-    /// This falls back to the file's earliest created or modified date-time.
-    pub fn best(&self) -> chrono::DateTime<Utc> {
-        if let Some(exif_original) = self.exif_original {
-            return exif_original.clone();
+    /// Finds the date-time that is best to use as the taken date-time, and the source it came from.
+    /// This uses the first of `sources` that has a date-time (see `DEFAULT_DATE_SOURCES`), and falls back
+    /// to the file's earliest created or modified date-time.
+    pub fn best(&self, sources: &[DateSource]) -> (DateSource, CaptureDateTime) {
+        if let Some(best) = sources
+            .iter()
+            .find_map(|source| self.get(*source).map(|date_time| (*source, date_time)))
+        {
+            return best;
+        }
+
+        if self.created <= self.modified {
+            return (
+                DateSource::FileCreated,
+                CaptureDateTime::from_instant(self.created),
+            );
         }
 
-        if let Some(exif_digitized) = self.exif_digitized {
-            return exif_digitized.clone();
+        (
+            DateSource::FileModified,
+            CaptureDateTime::from_instant(self.modified),
+        )
+    }
+
+    /// Gets the date-time of a source, if it was read.
+    pub fn get(&self, source: DateSource) -> Option<CaptureDateTime> {
+        match source {
+            DateSource::ExifOriginal => self.exif_original,
+            DateSource::ExifDigitized => self.exif_digitized,
+            DateSource::ExifBase => self.exif_base,
+            DateSource::GpsTime => self.gps_time,
+            DateSource::VideoMetadata => self.video_metadata_created,
+            DateSource::VideoMovieHeader => self.video_movie_created,
+            DateSource::VideoTrackHeader => self.video_track_created,
+            DateSource::FileName => self.name,
+            DateSource::FileCreated => Some(CaptureDateTime::from_instant(self.created)),
+            DateSource::FileModified => Some(CaptureDateTime::from_instant(self.modified)),
         }
+    }
+
+    /// Every date-time that was read, with the source it came from, in the order of `DateSource::ALL`.
+    pub fn date_times(&self) -> Vec<(DateSource, Option<CaptureDateTime>)> {
+        DateSource::ALL
+            .iter()
+            .map(|source| (*source, self.get(*source)))
+            .collect()
+    }
+
+    /// The make of the camera that took the photo, if known.
+    pub fn camera_make(&self) -> Option<&str> {
+        self.camera_make.as_deref()
+    }
+
+    /// The model of the camera that took the photo, if known.
+    pub fn camera_model(&self) -> Option<&str> {
+        self.camera_model.as_deref()
+    }
+
+    /// The serial number of the camera body that took the photo, if known.
+    pub fn camera_serial(&self) -> Option<&str> {
+        self.camera_serial.as_deref()
+    }
+
+    /// The date-time from the camera's clock (EXIF or video metadata) that `best` prefers, if there is one.
+    /// GPS times and file times do not come from the camera's clock.
+    pub fn camera_clock_date_time(&self) -> Option<CaptureDateTime> {
+        self.exif_original
+            .or(self.exif_digitized)
+            .or(self.exif_base)
+            .or(self.video_metadata_created)
+            .or(self.video_movie_created)
+            .or(self.video_track_created)
+    }
 
-        if let Some(exif_base) = self.exif_base {
-            return exif_base.clone();
+    /// Applies the first clock correction that matches the camera and the date-time of its clock: every
+    /// date-time from the camera's clock is shifted by its offset, so `best` gives the corrected date-time.
+    pub fn correct_clock(&mut self, corrections: &[ClockCorrection]) {
+        let date_time = match self.camera_clock_date_time() {
+            Some(date_time) => date_time,
+            None => return,
+        };
+
+        let camera = ClockCamera {
+            make: self.camera_make.as_deref(),
+            model: self.camera_model.as_deref(),
+            serial: self.camera_serial.as_deref(),
+        };
+        let correction = match corrections
+            .iter()
+            .find(|correction| correction.matches(&camera, &date_time.local))
+        {
+            Some(correction) => correction,
+            None => return,
+        };
+
+        for date_time in [
+            &mut self.exif_base,
+            &mut self.exif_original,
+            &mut self.exif_digitized,
+            &mut self.video_metadata_created,
+            &mut self.video_movie_created,
+            &mut self.video_track_created,
+        ]
+        .iter_mut()
+        {
+            if let Some(date_time) = date_time.as_mut() {
+                date_time.local += correction.offset.0;
+            }
         }
 
-        if self.modified < self.created {
-            return self.created.clone();
+        // the offset from the GPS is found again from the corrected clock
+        self.find_gps_offsets();
+    }
+
+    /// Gives the EXIF date-times without a recorded offset the offset of the timezone at the GPS position,
+    /// or else the offset between them and the GPS time.
+    fn find_gps_offsets(&mut self) {
+        let previous_sources = std::mem::take(&mut self.gps_offset_sources);
+        for (source, date_time) in [
+            (DateSource::ExifBase, &mut self.exif_base),
+            (DateSource::ExifOriginal, &mut self.exif_original),
+            (DateSource::ExifDigitized, &mut self.exif_digitized),
+        ] {
+            if let Some(date_time) = date_time.as_mut() {
+                if date_time.offset.is_none() || previous_sources.contains(&source) {
+                    date_time.offset =
+                        find_gps_offset(date_time.local, self.gps_time_zone, self.gps_utc);
+                    if date_time.offset.is_some() {
+                        self.gps_offset_sources.push(source);
+                    }
+                }
+            }
         }
+    }
+
+    /// Sets the timezone of the GPS position, which gives the GPS time and the offsets of the EXIF date-times.
+    /// When the timezone cannot be found, the GPS time is in UTC and the offsets are found from it instead,
+    /// and the error is kept as a warning.
+    fn set_gps_time_zone(&mut self, time_zone: Option<anyhow::Result<Tz>>) {
+        self.gps_time_zone = match time_zone.transpose() {
+            Ok(time_zone) => time_zone,
+            Err(err) => {
+                self.warnings.push(format!(
+                    "The timezone of the GPS position could not be found, so the GPS time is used in UTC: {:#}",
+                    err
+                ));
+                None
+            }
+        };
+
+        self.gps_time = self.gps_utc.map(|gps_utc| match self.gps_time_zone {
+            Some(time_zone) => CaptureDateTime::from_instant_in(gps_utc, &time_zone),
+            None => CaptureDateTime::from_instant_in(gps_utc, &Utc),
+        });
+        self.find_gps_offsets();
+    }
+
+    /// The problems that did not stop the date-times from being read.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Reads the date-time in the name of a file (or its directory) with the first name pattern that
+    /// matches it. A date-time in a name has no offset.
+    pub fn read_name(&mut self, file_path: &Path, name_patterns: &[NamePattern]) {
+        self.name = name_patterns
+            .iter()
+            .find_map(|name_pattern| name_pattern.find(file_path))
+            .map(|local| CaptureDateTime::new(local, None));
+    }
 
-        return self.modified.clone();
+    /// Where the photo was taken, if its GPS position is known.
+    pub fn gps_position(&self) -> Option<GpsPosition> {
+        self.gps_position
     }
 
     /// Loads the photo date-times for a file based on metadata and EXIF information.
+    /// Videos without EXIF information use the date-times in their container instead.
+    /// EXIF date-times without an offset get the offset of the timezone at the GPS position, or else the
+    /// offset between them and the GPS time (which is in UTC).
     pub fn load(file_path: &Path) -> anyhow::Result<PhotoDateTimeInfo> {
-        let file = File::open(&file_path)?;
+        let file = File::open(file_path)?;
 
         let metadata = fs::metadata(file_path)?;
         let created = convert_system_time_to_chrono_date_time(&metadata.created()?)?;
         let modified = convert_system_time_to_chrono_date_time(&metadata.modified()?)?;
 
-        let mut exif_base: Option<DateTime<Utc>> = None;
-        let mut exif_original: Option<DateTime<Utc>> = None;
-        let mut exif_digitized: Option<DateTime<Utc>> = None;
+        let mut exif_base: Option<CaptureDateTime> = None;
+        let mut exif_original: Option<CaptureDateTime> = None;
+        let mut exif_digitized: Option<CaptureDateTime> = None;
+        let mut gps_utc: Option<chrono::DateTime<Utc>> = None;
+        let mut gps_position: Option<GpsPosition> = None;
+        let mut camera_make: Option<String> = None;
+        let mut camera_model: Option<String> = None;
+        let mut camera_serial: Option<String> = None;
 
+        // RAW formats can split the EXIF information, so each value comes from the first that has it
         let mut bufreader = std::io::BufReader::new(&file);
-        let exifreader = exif::Reader::new();
-        if let Ok(exif) = exifreader.read_from_container(&mut bufreader) {
-            if let Some(date_time) =
-                get_exif_chrono_date_time_pair(&exif, Tag::DateTime, Tag::SubSecTime)
-            {
-                exif_base = Some(date_time);
-            }
-
-            if let Some(date_time) = get_exif_chrono_date_time_pair(
-                &exif,
-                Tag::DateTimeOriginal,
-                Tag::SubSecTimeOriginal,
-            ) {
-                exif_original = Some(date_time);
-            }
+        for exif in read_exif(&mut bufreader) {
+            exif_base = exif_base.or_else(|| {
+                get_exif_capture_date_time(&exif, Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime)
+            });
+
+            exif_original = exif_original.or_else(|| {
+                get_exif_capture_date_time(
+                    &exif,
+                    Tag::DateTimeOriginal,
+                    Tag::SubSecTimeOriginal,
+                    Tag::OffsetTimeOriginal,
+                )
+            });
+
+            exif_digitized = exif_digitized.or_else(|| {
+                get_exif_capture_date_time(
+                    &exif,
+                    Tag::DateTimeDigitized,
+                    Tag::SubSecTimeDigitized,
+                    Tag::OffsetTimeDigitized,
+                )
+            });
+
+            gps_utc = gps_utc.or_else(|| get_exif_gps_time(&exif));
+            gps_position = gps_position.or_else(|| get_exif_gps_position(&exif));
+
+            camera_make = camera_make.or_else(|| get_exif_field_string(&exif, Tag::Make));
+            camera_model = camera_model.or_else(|| get_exif_field_string(&exif, Tag::Model));
+            camera_serial =
+                camera_serial.or_else(|| get_exif_field_string(&exif, Tag::BodySerialNumber));
+        }
 
-            if let Some(date_time) = get_exif_chrono_date_time_pair(
-                &exif,
-                Tag::DateTimeDigitized,
-                Tag::SubSecTimeDigitized,
-            ) {
-                exif_digitized = Some(date_time);
+        let mut video = VideoDateTimeInfo::default();
+        if exif_base.is_none()
+            && exif_original.is_none()
+            && exif_digitized.is_none()
+            && gps_utc.is_none()
+        {
+            bufreader.rewind()?;
+            // a container that cannot be read falls back to the file date-times
+            if let Ok(video_date_time_info) = VideoDateTimeInfo::load(&mut bufreader) {
+                video = video_date_time_info;
             }
         }
 
-        return Ok(PhotoDateTimeInfo {
+        let mut date_time_info = PhotoDateTimeInfo {
             created,
             modified,
             exif_base,
             exif_original,
             exif_digitized,
-        });
+            gps_time: None,
+            gps_position,
+            gps_time_zone: None,
+            gps_utc,
+            gps_offset_sources: Vec::new(),
+            video_metadata_created: video.metadata_created,
+            video_movie_created: video.movie_created,
+            video_track_created: video.track_created,
+            name: None,
+            camera_make,
+            camera_model,
+            camera_serial,
+            warnings: Vec::new(),
+        };
+        date_time_info.set_gps_time_zone(gps_position.map(|position| position.time_zone()));
+
+        Ok(date_time_info)
     }
 }
 
+/// Reads the EXIF information of a file.
+/// The exif crate reads JPEG, TIFF, PNG, HEIF (heic, avif), WebP, and TIFF-based RAW formats
+/// (cr2, nef, arw, dng). JPEG XL and the other RAW formats (cr3, orf, raf) are read here.
+/// Most formats have (at most) one EXIF block, but CR3 files split it in two.
+#[doc(hidden)]
+fn read_exif<R>(reader: &mut R) -> Vec<exif::Exif>
+where
+    R: std::io::BufRead + Seek,
+{
+    let exifreader = exif::Reader::new();
+    if let Ok(exif) = exifreader.read_from_container(reader) {
+        return vec![exif];
+    }
+
+    if let Ok(Some(raw_exif)) = find_jxl_exif(reader) {
+        if let Ok(exif) = exifreader.read_raw(raw_exif) {
+            return vec![exif];
+        }
+    }
+
+    read_raw_exif(reader).unwrap_or_default()
+}
+
 // -------------------- std::time::SystemTime -> chrono::DateTime conversion -------------------- //
 
 #[doc(hidden)]
@@ -101,23 +473,31 @@ fn convert_system_time_to_chrono_date_time(
     value: &std::time::SystemTime,
 ) -> anyhow::Result<chrono::DateTime<Utc>> {
     let created_duration = value.duration_since(std::time::SystemTime::UNIX_EPOCH)?;
-    Ok(Utc.timestamp(
+    Utc.timestamp_opt(
         created_duration.as_secs() as i64,
         created_duration.subsec_nanos(),
-    ))
+    )
+    .single()
+    .ok_or_else(|| anyhow::anyhow!("The system time is out of range."))
 }
 
-// -------------------- EXIF -> chrono::DateTime conversion -------------------- //
+// -------------------- EXIF -> CaptureDateTime conversion -------------------- //
 
 #[doc(hidden)]
 fn days_in_month(year: i32, month: u32) -> i64 {
-    if month == 12 {
-        chrono::NaiveDate::from_ymd(year + 1, 1, 1)
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
     } else {
-        chrono::NaiveDate::from_ymd(year, month + 1, 1)
+        (year, month + 1)
+    };
+
+    match (
+        chrono::NaiveDate::from_ymd_opt(next_year, next_month, 1),
+        chrono::NaiveDate::from_ymd_opt(year, month, 1),
+    ) {
+        (Some(next), Some(first)) => next.signed_duration_since(first).num_days(),
+        _ => 31,
     }
-    .signed_duration_since(chrono::NaiveDate::from_ymd(year, month, 1))
-    .num_days()
 }
 
 // Unfortunately, exif::DateTime can have components beyond the bounds of a valid date and time
@@ -138,13 +518,13 @@ fn ensure_valid_exif_date_time(exif_date_time: &exif::DateTime) -> exif::DateTim
     }
 
     minute += second / 60;
-    second = second % 60;
+    second %= 60;
 
     hour += minute / 60;
-    minute = minute % 60;
+    minute %= 60;
 
     day += hour / 24;
-    hour = hour % 24;
+    hour %= 24;
 
     let mut cur_days_in_month = days_in_month(year as i32, month as u32) as u8;
     while day > cur_days_in_month {
@@ -160,9 +540,9 @@ fn ensure_valid_exif_date_time(exif_date_time: &exif::DateTime) -> exif::DateTim
     }
 
     year += (month / 13) as u16;
-    month = month % 13;
+    month %= 13;
 
-    return exif::DateTime {
+    exif::DateTime {
         year,
         month,
         day,
@@ -171,44 +551,44 @@ fn ensure_valid_exif_date_time(exif_date_time: &exif::DateTime) -> exif::DateTim
         second,
         nanosecond,
         offset: exif_date_time.offset,
-    };
+    }
 }
 
 // Tests if the date/time are greater than 0
 #[doc(hidden)]
-fn is_positive_exif_date_time(exif_date_time: &exif::DateTime) -> bool {    
+fn is_positive_exif_date_time(exif_date_time: &exif::DateTime) -> bool {
     // year, month, and day are 1 based
     // hour, minute, second, and nanosecond cannot be negative
-    exif_date_time.year > 0
-        && exif_date_time.month > 0
-        && exif_date_time.day > 0
+    exif_date_time.year > 0 && exif_date_time.month > 0 && exif_date_time.day > 0
 }
 
+// The EXIF date-time is the local time where the photo was taken; its offset (if any) only says where that was
 #[doc(hidden)]
-fn convert_exif_to_chrono_date_time(exif_date_time: &exif::DateTime) -> chrono::DateTime<Utc> {
-    let date = Utc.ymd(
+fn convert_exif_to_capture_date_time(exif_date_time: &exif::DateTime) -> Option<CaptureDateTime> {
+    let date = chrono::NaiveDate::from_ymd_opt(
         exif_date_time.year as i32,
         exif_date_time.month as u32,
         exif_date_time.day as u32,
-    );
+    )?;
 
-    let date_time = date.and_hms_nano(
+    let local = date.and_hms_nano_opt(
         exif_date_time.hour as u32,
         exif_date_time.minute as u32,
         exif_date_time.second as u32,
         exif_date_time.nanosecond.unwrap_or(0),
-    );
+    )?;
 
-    match exif_date_time.offset {
-        Some(offset_minutes) => date_time + chrono::Duration::minutes(offset_minutes as i64),
-        None => date_time,
-    }
+    let offset = exif_date_time
+        .offset
+        .and_then(|offset_minutes| chrono::FixedOffset::east_opt(offset_minutes as i32 * 60));
+
+    Some(CaptureDateTime::new(local, offset))
 }
 
 #[doc(hidden)]
 fn convert_exif_value_to_date_time(value: &exif::Value) -> Option<exif::DateTime> {
     if let exif::Value::Ascii(lines) = value {
-        if lines.len() > 0 {
+        if !lines.is_empty() {
             if let Ok(date_time) = exif::DateTime::from_ascii(&lines[0]) {
                 if is_positive_exif_date_time(&date_time) {
                     let date_time = ensure_valid_exif_date_time(&date_time);
@@ -224,7 +604,7 @@ fn convert_exif_value_to_date_time(value: &exif::Value) -> Option<exif::DateTime
 #[doc(hidden)]
 fn convert_exif_value_to_u32(value: &exif::Value) -> Option<u32> {
     if let exif::Value::Ascii(lines) = value {
-        if lines.len() > 0 {
+        if !lines.is_empty() {
             if let Ok(line) = std::str::from_utf8(&lines[0]) {
                 if let Ok(number) = line.parse() {
                     return Some(number);
@@ -237,12 +617,14 @@ fn convert_exif_value_to_u32(value: &exif::Value) -> Option<u32> {
 }
 
 #[doc(hidden)]
-#[allow(dead_code)]
 fn convert_exif_value_to_string(value: &exif::Value) -> Option<String> {
     if let exif::Value::Ascii(lines) = value {
-        if lines.len() > 0 {
+        if !lines.is_empty() {
             if let Ok(text) = std::str::from_utf8(&lines[0]) {
-                return Some(text.to_string());
+                let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+                if !text.is_empty() {
+                    return Some(text.to_string());
+                }
             }
         }
     }
@@ -252,12 +634,25 @@ fn convert_exif_value_to_string(value: &exif::Value) -> Option<String> {
 
 // -------------------- EXIF helpers -------------------- //
 
+// The offset of a date-time comes from its own offset tag, or else from OffsetTime (which cameras often
+// write for every date-time)
 #[doc(hidden)]
-fn get_exif_chrono_date_time(exif: &exif::Exif, tag: Tag) -> Option<chrono::DateTime<Utc>> {
+fn get_exif_date_time(exif: &exif::Exif, tag: Tag, offset_tag: Tag) -> Option<CaptureDateTime> {
     if let Some(field) = exif.get_field(tag, In::PRIMARY) {
-        if let Some(exif_date_time) = convert_exif_value_to_date_time(&field.value) {
-            let chrono_date_time = convert_exif_to_chrono_date_time(&exif_date_time);
-            return Some(chrono_date_time);
+        if let Some(mut exif_date_time) = convert_exif_value_to_date_time(&field.value) {
+            for offset_tag in [offset_tag, Tag::OffsetTime] {
+                if let Some(exif::Value::Ascii(lines)) =
+                    exif.get_field(offset_tag, In::PRIMARY).map(|f| &f.value)
+                {
+                    if let Some(line) = lines.first() {
+                        if exif_date_time.parse_offset(line).is_ok() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            return convert_exif_to_capture_date_time(&exif_date_time);
         }
     }
 
@@ -274,7 +669,6 @@ fn get_exif_field_u32(exif: &exif::Exif, tag: Tag) -> Option<u32> {
 }
 
 #[doc(hidden)]
-#[allow(dead_code)]
 fn get_exif_field_string(exif: &exif::Exif, tag: Tag) -> Option<String> {
     if let Some(field) = exif.get_field(tag, In::PRIMARY) {
         return convert_exif_value_to_string(&field.value);
@@ -284,14 +678,15 @@ fn get_exif_field_string(exif: &exif::Exif, tag: Tag) -> Option<String> {
 }
 
 #[doc(hidden)]
-fn get_exif_chrono_date_time_pair(
+fn get_exif_capture_date_time(
     exif: &exif::Exif,
     date_tag: Tag,
     sub_sec_tag: Tag,
-) -> Option<chrono::DateTime<Utc>> {
-    if let Some(date_time) = get_exif_chrono_date_time(exif, date_tag) {
+    offset_tag: Tag,
+) -> Option<CaptureDateTime> {
+    if let Some(mut date_time) = get_exif_date_time(exif, date_tag, offset_tag) {
         if let Some(sub_sec) = get_exif_field_u32(exif, sub_sec_tag) {
-            return Some(date_time + chrono::Duration::milliseconds(sub_sec as i64));
+            date_time.local += chrono::Duration::milliseconds(sub_sec as i64);
         }
 
         return Some(date_time);
@@ -300,21 +695,239 @@ fn get_exif_chrono_date_time_pair(
     None
 }
 
+// -------------------- GPS helpers -------------------- //
+
 #[doc(hidden)]
-#[allow(dead_code)]
-fn print_all_exif(file_path: &Path) -> anyhow::Result<()> {
-    let file = File::open(&file_path)?;
+fn get_exif_field_rationals(exif: &exif::Exif, tag: Tag) -> Option<Vec<f64>> {
+    if let Some(field) = exif.get_field(tag, In::PRIMARY) {
+        if let exif::Value::Rational(values) = &field.value {
+            return Some(values.iter().map(|value| value.to_f64()).collect());
+        }
+    }
+
+    None
+}
+
+/// Gets the GPS date and time stamps, which are in UTC.
+#[doc(hidden)]
+fn get_exif_gps_time(exif: &exif::Exif) -> Option<chrono::DateTime<Utc>> {
+    let date = get_exif_field_string(exif, Tag::GPSDateStamp)?;
+    let date = chrono::NaiveDate::parse_from_str(&date, "%Y:%m:%d").ok()?;
+
+    let time = get_exif_field_rationals(exif, Tag::GPSTimeStamp)?;
+    if time.len() < 3 {
+        return None;
+    }
+
+    let seconds = time[0] * 3600.0 + time[1] * 60.0 + time[2];
+    if !(0.0..86400.0).contains(&seconds) {
+        return None;
+    }
+
+    let midnight = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?);
+    Some(midnight + chrono::Duration::nanoseconds((seconds * 1e9).round() as i64))
+}
+
+/// Gets a GPS latitude or longitude in degrees, which is negative when its reference is `negative_ref`
+/// (south or west).
+#[doc(hidden)]
+fn get_exif_gps_coordinate(
+    exif: &exif::Exif,
+    tag: Tag,
+    ref_tag: Tag,
+    negative_ref: &str,
+) -> Option<f64> {
+    let values = get_exif_field_rationals(exif, tag)?;
+    if values.len() < 3 {
+        return None;
+    }
+
+    let degrees = values[0] + values[1] / 60.0 + values[2] / 3600.0;
+    match get_exif_field_string(exif, ref_tag) {
+        Some(reference) if reference.eq_ignore_ascii_case(negative_ref) => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+#[doc(hidden)]
+fn get_exif_gps_position(exif: &exif::Exif) -> Option<GpsPosition> {
+    let latitude = get_exif_gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S")?;
+    let longitude = get_exif_gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W")?;
+    GpsPosition::new(latitude, longitude)
+}
+
+/// Finds the offset of a local date-time from the timezone where the photo was taken or, without a position,
+/// from the GPS time (rounded to 15 minutes, as the GPS time is often recorded a little before or after).
+#[doc(hidden)]
+fn find_gps_offset(
+    local: chrono::NaiveDateTime,
+    time_zone: Option<Tz>,
+    gps_utc: Option<chrono::DateTime<Utc>>,
+) -> Option<chrono::FixedOffset> {
+    if let Some(time_zone) = time_zone {
+        return time_zone
+            .offset_from_local_datetime(&local)
+            .earliest()
+            .map(|offset| offset.fix());
+    }
+
+    let gps_utc = gps_utc?;
+    let minutes = (local - gps_utc.naive_utc()).num_seconds() as f64 / 60.0;
+    let minutes = (minutes / 15.0).round() as i32 * 15;
+    if minutes.abs() > 14 * 60 {
+        return None;
+    }
+
+    chrono::FixedOffset::east_opt(minutes * 60)
+}
+
+/// Prints every EXIF field of a file (for `photiso inspect --exif`).
+pub fn print_all_exif(file_path: &Path) -> anyhow::Result<()> {
+    let file = File::open(file_path)?;
     let mut buf_reader = std::io::BufReader::new(&file);
-    let exif_reader = exif::Reader::new();
-    let exif = exif_reader.read_from_container(&mut buf_reader)?;
-    for f in exif.fields() {
-        println!(
-            "{} {} {}",
-            f.tag,
-            f.ifd_num,
-            f.display_value().with_unit(&exif)
-        );
+    for exif in read_exif(&mut buf_reader) {
+        for f in exif.fields() {
+            println!(
+                "{} {} {}",
+                f.tag,
+                f.ifd_num,
+                f.display_value().with_unit(&exif)
+            );
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_exif::*;
+    use std::io::Cursor;
+
+    /// The date-time the photo was taken, from the first EXIF information of a file.
+    fn read_date_time_original(file: &[u8]) -> Option<String> {
+        let exifs = read_exif(&mut Cursor::new(file));
+        let exif = exifs.first()?;
+        get_exif_capture_date_time(
+            exif,
+            Tag::DateTimeOriginal,
+            Tag::SubSecTimeOriginal,
+            Tag::OffsetTimeOriginal,
+        )
+        .map(|date_time| date_time.local.to_string())
+    }
+
+    #[test]
+    fn reads_the_exif_of_webp_photos() {
+        let tiff = tiff_with_date_time_original("2019:07:04 12:34:56");
+        let mut chunks = b"VP8X\x0a\0\0\0\x08\0\0\0\0\0\0\0\0\0".to_vec();
+        chunks.extend_from_slice(b"EXIF");
+        chunks.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+        chunks.extend_from_slice(&tiff);
+        if tiff.len() % 2 == 1 {
+            chunks.push(0);
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&((chunks.len() + 4) as u32).to_le_bytes());
+        file.extend_from_slice(b"WEBP");
+        file.extend(chunks);
+
+        assert_eq!(
+            read_date_time_original(&file),
+            Some("2019-07-04 12:34:56".to_string())
+        );
+    }
+
+    #[test]
+    fn reads_the_exif_of_jxl_photos() {
+        let tiff = tiff_with_date_time_original("2019:07:04 12:34:56");
+        let mut exif = (tiff.len() as u32 + 12).to_be_bytes().to_vec();
+        exif.extend_from_slice(b"Exif\0\0\0\0");
+        exif.extend_from_slice(&tiff);
+        let mut file = b"\0\0\0\x0cJXL \r\n\x87\n".to_vec();
+        file.extend(exif);
+
+        assert_eq!(
+            read_date_time_original(&file),
+            Some("2019-07-04 12:34:56".to_string())
+        );
+    }
+
+    #[test]
+    fn files_without_exif_have_none() {
+        assert!(read_exif(&mut Cursor::new(b"\x89PNG\r\n\x1a\n not really a png")).is_empty());
+        assert!(read_exif(&mut Cursor::new(b"")).is_empty());
+    }
+
+    #[test]
+    fn a_gps_timezone_that_cannot_be_found_falls_back_to_the_gps_time() {
+        let date_time = |text| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S");
+        let mut date_time_info = PhotoDateTimeInfo {
+            exif_original: Some(CaptureDateTime::new(
+                date_time("2023-05-01 10:00:00").unwrap(),
+                None,
+            )),
+            gps_utc: Some(date_time("2023-05-01 08:00:00").unwrap().and_utc()),
+            ..Default::default()
+        };
+
+        date_time_info.set_gps_time_zone(Some(Err(anyhow::anyhow!("No boundaries."))));
+
+        assert_eq!(
+            date_time_info.warnings(),
+            ["The timezone of the GPS position could not be found, so the GPS time is used in UTC: No boundaries."]
+        );
+        let (_, gps_time) = date_time_info.best(&[DateSource::GpsTime]);
+        assert_eq!(gps_time.local, date_time("2023-05-01 08:00:00").unwrap());
+        assert_eq!(gps_time.offset, chrono::FixedOffset::east_opt(0));
+        let (_, original) = date_time_info.best(&[DateSource::ExifOriginal]);
+        assert_eq!(original.offset, chrono::FixedOffset::east_opt(2 * 3600));
+
+        date_time_info.warnings.clear();
+        date_time_info.set_gps_time_zone(Some(Ok(chrono_tz::Europe::Berlin)));
+        assert!(date_time_info.warnings().is_empty());
+        let (_, gps_time) = date_time_info.best(&[DateSource::GpsTime]);
+        assert_eq!(gps_time.local, date_time("2023-05-01 10:00:00").unwrap());
+    }
+
+    #[test]
+    fn corrected_clocks_find_the_gps_offset_again() {
+        let date_time = |text| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S");
+        // the camera's clock was an hour behind, in a timezone 2 hours ahead of UTC
+        let gps_utc = date_time("2023-05-01 08:00:00").unwrap().and_utc();
+        let corrections: Vec<ClockCorrection> =
+            vec![toml::from_str(r#"offset = "+01:00""#).unwrap()];
+
+        for gps_time_zone in [None, Some(chrono_tz::Europe::Berlin)] {
+            let mut date_time_info = PhotoDateTimeInfo {
+                exif_original: Some(CaptureDateTime::new(
+                    date_time("2023-05-01 09:00:00").unwrap(),
+                    None,
+                )),
+                exif_digitized: Some(CaptureDateTime::new(
+                    date_time("2023-05-01 09:00:00").unwrap(),
+                    chrono::FixedOffset::east_opt(3600),
+                )),
+                gps_time_zone,
+                gps_utc: Some(gps_utc),
+                ..Default::default()
+            };
+            date_time_info.find_gps_offsets();
+
+            date_time_info.correct_clock(&corrections);
+
+            let (_, original) = date_time_info.best(&[DateSource::ExifOriginal]);
+            assert_eq!(original.local, date_time("2023-05-01 10:00:00").unwrap());
+            assert_eq!(original.offset, chrono::FixedOffset::east_opt(2 * 3600));
+            assert_eq!(
+                original.in_time_zone(&PathTimeZone::Utc),
+                date_time("2023-05-01 08:00:00").unwrap()
+            );
+
+            // a recorded offset is the camera's own, so it is left alone
+            let (_, digitized) = date_time_info.best(&[DateSource::ExifDigitized]);
+            assert_eq!(digitized.offset, chrono::FixedOffset::east_opt(3600));
+        }
+    }
+}
@@ -1,29 +1,53 @@
+use crate::capture_date_time::*;
+use crate::clock_correction::*;
 use crate::file_hash::*;
+use crate::file_transfer::*;
+use crate::file_type::*;
+use crate::hash_index::*;
+use crate::move_journal::*;
+use crate::name_pattern::*;
+use crate::path_template::*;
 use crate::photo_date_time::*;
+use crate::protection::*;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use rayon::prelude::*;
 use std::{
-    cell::Cell,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsString,
     fs, io,
     path::Path,
     path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     time::{Duration, Instant},
 };
 
-#[doc(hidden)]
-pub use anyhow::*;
+use anyhow::{ensure, Context};
+
+/// The name of the files that list (in gitignore syntax) the files and directories to skip in their directory.
+pub const IGNORE_FILE_NAME: &str = ".photisoignore";
 
 /// An event raised as photos are organized.
 pub enum OrganizeEvent<'a> {
+    /// Raised when organizing a source directory starts.
+    /// Every event until its `SourceFinished` event belongs to this source.
+    SourceStarted { source: &'a Path },
+    /// Raised when organizing a source directory finishes, with the counts of that source.
+    SourceFinished {
+        source: &'a Path,
+        result: &'a OrganizeResult,
+    },
     /// Raised when processing an unorganized directory starts.
     DirStarted { dir: &'a Path },
     /// Raised when processing an unorganized directory finishes.
-    DirFinished { dir: &'a Path },
+    DirFinished,
     /// Raised when a directory is skipped.
     DirSkipped { dir: &'a Path, reason: &'a str },
-    /// Raised when processing an unorganized file starts.
-    FileStarted { file: &'a Path },
-    /// Raised when processing an unorganized file finishes.
-    FileFinished { file: &'a Path },
     /// Raised when file is skipped.
     FileSkipped { file: &'a Path, reason: &'a str },
     /// Raised when there is an error processing a file.
@@ -31,15 +55,32 @@ pub enum OrganizeEvent<'a> {
         file: &'a Path,
         error: anyhow::Error,
     },
-    /// Raised when photo is moved to its organized location.
-    PhotoMoved { from: &'a Path, to: &'a Path },
+    /// Raised when a photo is organized despite a problem, such as a GPS timezone that could not be found.
+    /// This is raised just before the photo's moved event.
+    FileWarning { file: &'a Path, warning: &'a str },
+    /// Raised when the extension of a photo does not match its contents and is fixed.
+    /// This is raised just before the photo is moved with its new extension.
+    ExtensionFixed { file: &'a Path, extension: &'a str },
+    /// Raised when a photo cannot be hard linked or reflinked to its destination, so it is copied instead.
+    /// This is raised just before the photo's moved event.
+    CopyFallback { file: &'a Path, reason: &'a str },
+    /// Raised when a symbolic link whose photo no longer exists is removed (in symlink mode).
+    LinkRemoved { link: &'a Path },
+    /// Raised when photo is moved to its organized location, with the source of the date-time it was organized by.
+    PhotoMoved {
+        from: &'a Path,
+        to: &'a Path,
+        dated_by: DateSource,
+    },
     /// Raised when duplicate photo is moved to its duplicates location.
     DuplicatePhotoMoved { from: &'a Path, to: &'a Path },
+    /// Raised when a companion (e.g. a sidecar) is moved next to its photo, in the organized or duplicates directory.
+    CompanionMoved { from: &'a Path, to: &'a Path },
     /// Raised when photo is already at its organized location.
     PhotoNoOp { file: &'a Path },
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct OrganizeResult {
     pub dirs: u64,
     pub dirs_skipped: u64,
@@ -48,83 +89,443 @@ pub struct OrganizeResult {
     pub files_errored: u64,
     pub photos_moved: u64,
     pub duplicate_photos_moved: u64,
+    /// The companions moved with their photos, which are not counted as photos (or duplicates) moved.
+    pub companions_moved: u64,
     pub photos_noop: u64,
+    pub extensions_fixed: u64,
+    pub links_removed: u64,
+    /// The photos moved, by the source of the date-time they were organized by.
+    pub dated_by: BTreeMap<DateSource, u64>,
     pub duration: Duration,
+    /// The counts of each source directory, in the order they were organized.
+    pub sources: Vec<SourceResult>,
+}
+
+/// The counts of one source directory.
+#[derive(Clone, Debug)]
+pub struct SourceResult {
+    pub source: PathBuf,
+    pub result: OrganizeResult,
+}
+
+impl OrganizeResult {
+    /// Adds the counters and duration of another result (e.g. of another profile) to this one.
+    pub fn add(&mut self, other: &OrganizeResult) {
+        self.dirs += other.dirs;
+        self.dirs_skipped += other.dirs_skipped;
+        self.files += other.files;
+        self.files_skipped += other.files_skipped;
+        self.files_errored += other.files_errored;
+        self.photos_moved += other.photos_moved;
+        self.duplicate_photos_moved += other.duplicate_photos_moved;
+        self.companions_moved += other.companions_moved;
+        self.photos_noop += other.photos_noop;
+        self.extensions_fixed += other.extensions_fixed;
+        self.links_removed += other.links_removed;
+        for (source, count) in &other.dated_by {
+            *self.dated_by.entry(*source).or_insert(0) += count;
+        }
+        self.duration += other.duration;
+        self.sources.extend(other.sources.iter().cloned());
+    }
+
+    /// Gets the counters that were added since an earlier result of the same run.
+    #[doc(hidden)]
+    fn since(&self, earlier: &OrganizeResult) -> OrganizeResult {
+        OrganizeResult {
+            dirs: self.dirs - earlier.dirs,
+            dirs_skipped: self.dirs_skipped - earlier.dirs_skipped,
+            files: self.files - earlier.files,
+            files_skipped: self.files_skipped - earlier.files_skipped,
+            files_errored: self.files_errored - earlier.files_errored,
+            photos_moved: self.photos_moved - earlier.photos_moved,
+            duplicate_photos_moved: self.duplicate_photos_moved - earlier.duplicate_photos_moved,
+            companions_moved: self.companions_moved - earlier.companions_moved,
+            photos_noop: self.photos_noop - earlier.photos_noop,
+            extensions_fixed: self.extensions_fixed - earlier.extensions_fixed,
+            links_removed: self.links_removed - earlier.links_removed,
+            dated_by: self
+                .dated_by
+                .iter()
+                .map(|(source, count)| {
+                    (*source, count - earlier.dated_by.get(source).unwrap_or(&0))
+                })
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            duration: self.duration.saturating_sub(earlier.duration),
+            sources: Vec::new(),
+        }
+    }
 }
 
-/// Organizes photos
+/// A directory of photos to organize, and which of its photos are organized.
+#[derive(Clone, Debug)]
+pub struct OrganizeSource {
+    /// The directory containing the photos that need to be organized.
+    pub dir: PathBuf,
+    /// When false, only the files directly inside `dir` are organized, not those of its subdirectories.
+    pub recursive: bool,
+    /// When not empty, only files whose path (relative to `dir`) matches one of these globs are organized.
+    /// A glob ending in `/` matches directories instead, and only the files inside them are organized.
+    pub include: Vec<String>,
+    /// Files and directories whose path (relative to `dir`) matches one of these globs are skipped.
+    /// A glob ending in `/` only matches directories.
+    pub exclude: Vec<String>,
+}
+
+impl OrganizeSource {
+    /// A source that organizes every photo of a directory and its subdirectories.
+    pub fn new(dir: &Path) -> OrganizeSource {
+        OrganizeSource {
+            dir: dir.to_path_buf(),
+            recursive: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Options that change how photos are organized.
+#[derive(Clone, Debug)]
+pub struct OrganizeOptions {
+    /// When true, organize computes where every photo would go and raises the same events,
+    /// but never moves a file nor creates a directory.
+    pub plan: bool,
+    /// When set, every move is recorded in this journal before it happens so that it can be undone.
+    pub journal: Option<PathBuf>,
+    /// The path of organized photos, relative to the organized directory.
+    pub organized_template: PathTemplate,
+    /// The path of duplicate photos, relative to the duplicates directory.
+    pub duplicates_template: PathTemplate,
+    /// The number of threads used to read photo dates and hashes. 0 uses one thread per CPU.
+    pub threads: usize,
+    /// When set, the content hashes of the organized directory are kept in this index between runs.
+    /// Any photo whose hash is already in the organized directory is treated as a duplicate.
+    pub hash_index: Option<PathBuf>,
+    /// When true, a photo whose extension does not match its contents (e.g. a PNG named .jpg, or a file
+    /// without an extension) gets the extension of its contents at its destination.
+    pub fix_extensions: bool,
+    /// How photos get to their destination: moved (the default), copied and verified, hard linked, or reflinked.
+    pub mode: TransferMode,
+    /// When true (in copy mode), a copied photo is deleted from its original location once the copy is verified.
+    pub delete_source: bool,
+    /// Globs of the files to organize in every source, as for `OrganizeSource::include`.
+    pub include: Vec<String>,
+    /// Globs of the files and directories to skip in every source, as for `OrganizeSource::exclude`.
+    pub exclude: Vec<String>,
+    /// The rules of the files and directories that are left alone.
+    pub protection: ProtectionRules,
+    /// The timezone of the date-times in organized paths: where each photo was taken (the default), UTC,
+    /// or a home timezone.
+    pub time_zone: PathTimeZone,
+    /// The corrections of cameras whose clock was wrong. The first one that matches a photo applies to it.
+    pub clock_corrections: Vec<ClockCorrection>,
+    /// The patterns of date-times in file (and directory) names, for photos without one in their metadata.
+    /// The first one that matches a photo is used.
+    pub name_patterns: Vec<NamePattern>,
+    /// The sources of the date-times that organize photos, in order (see `PhotoDateTimeInfo::best`).
+    pub date_sources: Vec<DateSource>,
+}
+
+impl Default for OrganizeOptions {
+    fn default() -> Self {
+        OrganizeOptions {
+            plan: false,
+            journal: None,
+            organized_template: PathTemplate::organized_default(),
+            duplicates_template: PathTemplate::duplicates_default(),
+            threads: 0,
+            hash_index: None,
+            fix_extensions: false,
+            mode: TransferMode::Move,
+            delete_source: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            protection: ProtectionRules::default(),
+            time_zone: PathTimeZone::default(),
+            clock_corrections: Vec::new(),
+            name_patterns: NamePattern::defaults(),
+            date_sources: DEFAULT_DATE_SOURCES.to_vec(),
+        }
+    }
+}
+
+/// Organizes the photos of several source directories in one run
 ///
 /// # Arguments
 ///
-/// * `unorganized_dir` - The directory containing the photos that need to be organized.
+/// * `sources` - The directories containing the photos that need to be organized, with their options.
 /// * `organized_dir` - The directory where organized photos should be placed.
 /// * `duplicates_dir` - The directory where exact duplicate photos should be placed.
+/// * `options` - The options that change how photos are organized.
 /// * `event_handler` - The handler for listening to events as organize progreses.
 ///
-/// To organize photos _in place_, pass the same directory for `unorganized_dir` and `organized_dir`.
+/// To organize photos _in place_, pass the same directory as a source and as `organized_dir`.
 ///
-/// The `duplicates_dir` cannot be the same directory as `unorganized_dir` nor `organized_dir`.
+/// The `duplicates_dir` cannot be the same directory as a source nor `organized_dir`.
 ///
 /// If the `event_handler` returns true, organize continues; otherwise organize will stop processing files and return.
 ///
 /// # Organization Details
 ///
-/// Only files with an extension of bmp, gif, jpg, jpeg, png, tif, tiff, or wmp are processed. Others are skipped.
+/// Only photos (avif, bmp, gif, heic, heif, jpg, jpeg, jxl, png, tif, tiff, webp, or wmp),
+/// RAW photos (arw, cr2, cr3, dng, nef, orf, or raf), and videos (3gp, avi, m4v, mov, or mp4) are processed.
+/// Others are skipped. A file's format is detected from its contents, whatever its extension, and files whose
+/// contents are not recognized are skipped; only sidecars (aae or xmp), which have no signature, are known by
+/// their extension. When `options.fix_extensions` is true, a file whose extension does not match its contents
+/// is given the extension of its contents at its destination.
+///
+/// Files protected by `options.protection` are left alone: files whose name contains one of its stem markers
+/// (`!` by default), has one of its extended attributes, or is read-only (when `read_only` is set), and the
+/// directories that contain one of its marker files (`.photiso-keep` by default), with everything in them.
+/// Protected photos are never moved by `dedupe` either. Names that are not valid UTF-8 are organized like any other.
+///
+/// # Photo Groups
 ///
-/// If a filename contains an exclamation point `!`, it will be skipped.
+/// Files in the same directory that share a name (ignoring extensions) are organized as a group, such as
+/// `IMG_0001.CR2` + `IMG_0001.JPG` or a photo and its `.xmp` or `.aae` sidecar. A sidecar may also be named
+/// after the full name of its photo (e.g. `IMG_0001.CR2.xmp`). The primary photo (a RAW photo, else a photo,
+/// else a video) decides the date-time, camera, and hash, so every file of the group gets the same destination
+/// name and conflict number. When the primary photo is a duplicate, the whole group goes to the duplicates
+/// directory. Sidecars without a photo are skipped.
 ///
-pub fn organize<F>(
-    unorganized_dir: &Path,
+/// The tree of each source is walked first, then the photo dates and hashes of all its files are read in
+/// one batch on a pool of `options.threads` threads.
+/// Moves and events happen one at a time, in the same order as a single-threaded run.
+///
+/// # Hash Index
+///
+/// When `options.hash_index` is set, the index is refreshed from the organized directory before organizing
+/// (only new or changed files are hashed). A photo whose contents already exist anywhere in the organized
+/// directory is moved to the duplicates directory, regardless of its date. Files inside the unorganized
+/// directory are indexed as they are organized. When organizing in place, a photo is only a duplicate of
+/// photos that were organized before it, so a photo already at its organized location stays there.
+///
+/// # Plan Mode
+///
+/// When `options.plan` is true, no files are moved and no directories are created.
+/// Destinations are resolved against both the files on disk and the moves planned so far,
+/// so the events and result describe what a real run would do.
+///
+/// # Copy Mode
+///
+/// When `options.mode` is `TransferMode::Copy`, photos are copied instead of moved, keeping their permissions
+/// and accessed and modified times. A copy is removed (and reported as an error) unless its hash matches the
+/// original. When `options.delete_source` is also true, the original is deleted once its copy is verified.
+/// `PhotoMoved`, `DuplicatePhotoMoved`, and `CompanionMoved` events are raised for copies too.
+/// Unless the original is deleted, a photo whose contents are already at its destination (or, with a hash index,
+/// anywhere in the organized directory) was copied by an earlier run, so it raises `PhotoNoOp` and is not copied
+/// again. The same goes for the link modes.
+///
+/// # Link Modes
+///
+/// When `options.mode` is `TransferMode::HardLink` or `TransferMode::Reflink`, a hard link or reflink
+/// (copy-on-write clone) is created at the destination and the original is left intact. When the file system
+/// cannot link a photo, it is copied instead (as in copy mode) after a `CopyFallback` event.
+///
+/// # Symlink Mode
+///
+/// When `options.mode` is `TransferMode::Symlink`, the organized directory is a view of relative symbolic links
+/// to the untouched photos in the unorganized directory (which cannot be the organized directory). Organizing
+/// again refreshes the view: links to photos that no longer exist are removed (raising `LinkRemoved`),
+/// links that already point to their photo are left as they are, and new photos are linked.
+///
+/// A move to another file system is a copy (verified the same way) followed by deleting the original.
+/// Copies are written to a hidden partial file that is renamed once it is verified, and partial files left
+/// by an interrupted run are skipped.
+///
+/// # Sources
+///
+/// The sources are organized one after the other. The events of each source are between its `SourceStarted`
+/// and `SourceFinished` events, and the result has the counts of each source in `sources`. A source nested inside another source is only organized as itself.
+///
+/// A source that is not `recursive` only organizes the files directly inside its directory. Its `exclude`
+/// globs skip the matching files and directories, and its `include` globs (when there are any) skip every
+/// file that does not match. Globs match the path relative to the source directory, using `/` (e.g.
+/// `*.jpg`, `**/thumbnails`, or `DCIM/**`). The globs of `options.include` and `options.exclude` apply to
+/// every source.
+///
+/// A `.photisoignore` file in any directory of a source skips the files and directories it lists, in
+/// gitignore syntax, relative to its directory. Ignore files of subdirectories take precedence, and `!`
+/// patterns include again what an ignore file of a parent directory excluded.
+///
+/// The reason of each `FileSkipped` and `DirSkipped` event names the glob (and ignore file) that skipped it.
+///
+pub fn organize_sources<F>(
+    sources: &[OrganizeSource],
     organized_dir: &Path,
     duplicates_dir: &Path,
+    options: &OrganizeOptions,
     event_handler: F,
 ) -> anyhow::Result<OrganizeResult>
 where
     F: Fn(OrganizeEvent) -> bool,
 {
-    // each of the directories must exist, otherwise canonicalize will fail.
-    if !unorganized_dir.exists() {
-        fs::create_dir_all(&unorganized_dir)?;
-    }
+    if !options.plan {
+        // each of the directories must exist, otherwise canonicalize will fail.
+        for source in sources {
+            if !source.dir.exists() {
+                fs::create_dir_all(&source.dir)?;
+            }
+        }
 
-    if !organized_dir.exists() {
-        fs::create_dir_all(&organized_dir)?;
+        if !organized_dir.exists() {
+            fs::create_dir_all(organized_dir)?;
+        }
+
+        if !&duplicates_dir.exists() {
+            fs::create_dir_all(duplicates_dir)?;
+        }
     }
 
-    if !&duplicates_dir.exists() {
-        fs::create_dir_all(&duplicates_dir)?;
+    let organizer = Organizer::new(
+        sources,
+        organized_dir,
+        duplicates_dir,
+        options,
+        event_handler,
+    )?;
+
+    organizer.organize()
+}
+
+/// Moves the duplicate photos of an organized directory to the duplicates directory
+///
+/// # Arguments
+///
+/// * `organized_dir` - The directory of organized photos.
+/// * `duplicates_dir` - The directory where duplicate photos are moved.
+/// * `options` - The options (as for organize).
+/// * `event_handler` - The handler for listening to events as dedupe progresses.
+///
+/// Every photo in the organized directory is hashed. The first photo (by path) with given contents stays,
+/// and the others are moved to the duplicates directory with their sidecars, raising `DuplicatePhotoMoved`
+/// (and `CompanionMoved`) events. Duplicates are always moved, whatever `options.mode` is, and are journaled as organize moves are.
+/// `options.plan` is honored.
+///
+/// If the `event_handler` returns true, dedupe continues; otherwise dedupe will stop and return.
+///
+pub fn dedupe<F>(
+    organized_dir: &Path,
+    duplicates_dir: &Path,
+    options: &OrganizeOptions,
+    event_handler: F,
+) -> anyhow::Result<OrganizeResult>
+where
+    F: Fn(OrganizeEvent) -> bool,
+{
+    if !options.plan && !duplicates_dir.exists() {
+        fs::create_dir_all(duplicates_dir)?;
     }
 
+    let options = OrganizeOptions {
+        mode: TransferMode::Move,
+        ..options.clone()
+    };
+
     let organizer = Organizer::new(
-        unorganized_dir,
+        &[OrganizeSource::new(organized_dir)],
         organized_dir,
         duplicates_dir,
+        &options,
         event_handler,
     )?;
 
-    Ok(organizer.organize()?)
+    organizer.dedupe()
 }
 
 struct OrganizerParams<F>
 where
     F: Fn(OrganizeEvent) -> bool,
 {
-    unorganized_dir: PathBuf,
     organized_dir: PathBuf,
     duplicates_dir: PathBuf,
 
+    options: OrganizeOptions,
     event_handler: F,
 }
 
 struct OrganizeCounters {
-    dirs: Cell<u64>,
-    dirs_skipped: Cell<u64>,
-    files: Cell<u64>,
-    files_skipped: Cell<u64>,
-    files_errored: Cell<u64>,
-    photos_moved: Cell<u64>,
-    duplicate_photos_moved: Cell<u64>,
-    photos_noop: Cell<u64>,
+    dirs: AtomicU64,
+    dirs_skipped: AtomicU64,
+    files: AtomicU64,
+    files_skipped: AtomicU64,
+    files_errored: AtomicU64,
+    photos_moved: AtomicU64,
+    duplicate_photos_moved: AtomicU64,
+    companions_moved: AtomicU64,
+    photos_noop: AtomicU64,
+    extensions_fixed: AtomicU64,
+    links_removed: AtomicU64,
+    /// The photos moved, indexed by `DateSource`
+    dated_by: [AtomicU64; DateSource::ALL.len()],
+}
+
+/// A source directory, with its globs compiled.
+struct SourceDir {
+    /// The canonical directory
+    dir: PathBuf,
+    /// The directory as it was given, which is what events report
+    lay_dir: PathBuf,
+    recursive: bool,
+    include_files: GlobList,
+    include_dirs: GlobList,
+    exclude_files: GlobList,
+    exclude_dirs: GlobList,
+}
+
+/// Globs that match paths relative to a source directory, with the patterns they were built from.
+struct GlobList {
+    set: GlobSet,
+    patterns: Vec<String>,
+}
+
+/// What is known about a file before deciding where it goes.
+/// This is gathered on the worker pool since reading EXIF data and hashing are the slow parts.
+enum PreparedFile {
+    Skipped(Cow<'static, str>),
+    Photo {
+        kind: FileKind,
+        date_time_info: Box<PhotoDateTimeInfo>,
+        hash: Option<String>,
+        fixed_extension: Option<&'static str>,
+    },
+    Sidecar {
+        hash: Option<String>,
+    },
+}
+
+/// A photo and the files that move with it, such as the JPEG of a RAW+JPEG pair,
+/// the video of a live photo, or an `.xmp` or `.aae` sidecar.
+struct PhotoGroup<'a> {
+    /// The photo whose date-time and camera decide where the group goes
+    primary: &'a Path,
+    date_time_info: Box<PhotoDateTimeInfo>,
+    hash: Option<String>,
+    fixed_extension: Option<&'static str>,
+    companions: Vec<Companion<'a>>,
+}
+
+struct Companion<'a> {
+    file_path: &'a Path,
+    hash: Option<String>,
+    fixed_extension: Option<&'static str>,
+}
+
+/// A step of organizing a source, in the order of its events.
+enum WalkStep {
+    DirSkipped(PathBuf, String),
+    /// A directory skipped because its ignore file (the second path) could not be loaded
+    InvalidIgnoreFile(PathBuf, PathBuf, anyhow::Error),
+    /// A directory and the number of its files, which follow the files of the directories before it
+    DirStarted(PathBuf, usize),
+    DirFinished,
+}
+
+/// What is organized next in a directory: a file on its own, or a photo group.
+enum PreparedEntry<'a> {
+    File(&'a Path, anyhow::Result<PreparedFile>),
+    Group(PhotoGroup<'a>),
 }
 
 #[doc(hidden)]
@@ -134,12 +535,24 @@ where
 {
     params: OrganizerParams<F>,
 
-    unorganized_dir: PathBuf,
+    sources: Vec<SourceDir>,
+    /// The index of the source being organized
+    current_source: Cell<usize>,
     organized_dir: PathBuf,
     duplicates_dir: PathBuf,
 
     counters: OrganizeCounters,
-    canceled: Cell<bool>,
+    canceled: AtomicBool,
+    journal: Option<MoveJournal>,
+    hash_index: Option<RefCell<HashIndex>>,
+    pool: rayon::ThreadPool,
+
+    // plan mode bookkeeping: destination -> source of each planned move, and the sources they vacate
+    planned_moves: RefCell<HashMap<PathBuf, PathBuf>>,
+    planned_vacated: RefCell<HashSet<PathBuf>>,
+
+    /// The files of the current source that are not organized yet
+    pending_files: RefCell<HashSet<PathBuf>>,
 }
 
 #[doc(hidden)]
@@ -149,149 +562,647 @@ where
 {
     /// Create a new instance of the organizer
     pub fn new(
-        unorganized_dir: &Path,
+        sources: &[OrganizeSource],
         organized_dir: &Path,
         duplicates_dir: &Path,
+        options: &OrganizeOptions,
         event_handler: F,
     ) -> anyhow::Result<Organizer<F>> {
-        let canonical_unorganized_dir = fs::canonicalize(unorganized_dir)?;
-        let canonical_organized_dir = fs::canonicalize(organized_dir)?;
-        let canonical_duplicates_dir = fs::canonicalize(duplicates_dir)?;
+        let canonical_organized_dir = canonicalize_dir(organized_dir, options.plan)?;
+        let canonical_duplicates_dir = canonicalize_dir(duplicates_dir, options.plan)?;
 
         ensure!(
-            canonical_unorganized_dir != canonical_duplicates_dir,
-            "The unorganized directory and duplicates directory cannot be the the same directory."
+            !sources.is_empty(),
+            "There must be at least one unorganized directory."
         );
         ensure!(
             canonical_organized_dir != canonical_duplicates_dir,
             "The organized directory and duplicates directory cannot be the the same directory."
         );
 
+        let mut source_dirs: Vec<SourceDir> = Vec::new();
+        for source in sources {
+            let canonical_source_dir = fs::canonicalize(&source.dir).with_context(|| {
+                format!("Could not open the unorganized directory {:?}", source.dir)
+            })?;
+
+            ensure!(
+                canonical_source_dir != canonical_duplicates_dir,
+                "The unorganized directory {:?} and duplicates directory cannot be the the same directory.",
+                source.dir
+            );
+            ensure!(
+                options.mode != TransferMode::Symlink
+                    || canonical_source_dir != canonical_organized_dir,
+                "The unorganized directory {:?} and organized directory cannot be the same directory in symlink mode.",
+                source.dir
+            );
+            ensure!(
+                source_dirs.iter().all(|s| s.dir != canonical_source_dir),
+                "The unorganized directory {:?} is given more than once.",
+                source.dir
+            );
+
+            let include: Vec<&String> = options.include.iter().chain(&source.include).collect();
+            let exclude: Vec<&String> = options.exclude.iter().chain(&source.exclude).collect();
+            source_dirs.push(SourceDir {
+                dir: canonical_source_dir,
+                lay_dir: source.dir.clone(),
+                recursive: source.recursive,
+                include_files: GlobList::new(get_file_patterns(&include))?,
+                include_dirs: GlobList::new(get_dir_patterns(&include, false))?,
+                exclude_files: GlobList::new(get_file_patterns(&exclude))?,
+                exclude_dirs: GlobList::new(get_dir_patterns(&exclude, true))?,
+            });
+        }
+
+        // plans are never journaled since nothing moves
+        let journal = match &options.journal {
+            Some(journal_path) if !options.plan => Some(MoveJournal::open(journal_path)?),
+            _ => None,
+        };
+
+        let hash_index = match &options.hash_index {
+            Some(hash_index_path) => Some(RefCell::new(HashIndex::load(hash_index_path)?)),
+            None => None,
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()?;
+
         Ok(Organizer {
             params: OrganizerParams {
-                unorganized_dir: unorganized_dir.to_path_buf(),
                 organized_dir: organized_dir.to_path_buf(),
                 duplicates_dir: duplicates_dir.to_path_buf(),
+                options: options.clone(),
                 event_handler,
             },
-            unorganized_dir: canonical_unorganized_dir,
+            sources: source_dirs,
+            current_source: Cell::new(0),
             organized_dir: canonical_organized_dir,
             duplicates_dir: canonical_duplicates_dir,
             counters: OrganizeCounters {
-                dirs: Cell::new(0),
-                dirs_skipped: Cell::new(0),
-                files: Cell::new(0),
-                photos_moved: Cell::new(0),
-                duplicate_photos_moved: Cell::new(0),
-                photos_noop: Cell::new(0),
-                files_skipped: Cell::new(0),
-                files_errored: Cell::new(0),
+                dirs: AtomicU64::new(0),
+                dirs_skipped: AtomicU64::new(0),
+                files: AtomicU64::new(0),
+                photos_moved: AtomicU64::new(0),
+                duplicate_photos_moved: AtomicU64::new(0),
+                companions_moved: AtomicU64::new(0),
+                photos_noop: AtomicU64::new(0),
+                extensions_fixed: AtomicU64::new(0),
+                links_removed: AtomicU64::new(0),
+                files_skipped: AtomicU64::new(0),
+                files_errored: AtomicU64::new(0),
+                dated_by: Default::default(),
             },
-            canceled: Cell::new(false),
+            canceled: AtomicBool::new(false),
+            journal,
+            hash_index,
+            pool,
+            planned_moves: RefCell::new(HashMap::new()),
+            planned_vacated: RefCell::new(HashSet::new()),
+            pending_files: RefCell::new(HashSet::new()),
         })
     }
 
-    /// Organize the unorganized directory of photos, placing photos to their organized location.
+    /// Organize the source directories of photos, placing photos to their organized location.
     /// Any duplicate photos are moved to the duplicates directory.
     pub fn organize(&self) -> anyhow::Result<OrganizeResult> {
-        self.canceled.set(false);
+        self.canceled.store(false, Ordering::SeqCst);
+        self.planned_moves.borrow_mut().clear();
+        self.planned_vacated.borrow_mut().clear();
+
+        let timer = Instant::now();
+        self.remove_dangling_links()?;
+        self.refresh_hash_index()?;
+
+        let mut source_results = Vec::new();
+        let organized = (|| -> anyhow::Result<()> {
+            for (index, source) in self.sources.iter().enumerate() {
+                if self.canceled.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                self.current_source.set(index);
+                let start = self.get_result(timer.elapsed());
+                self.on_event(OrganizeEvent::SourceStarted {
+                    source: &source.lay_dir,
+                });
+
+                let organized = self.organize_source(&source.dir);
+
+                let result = self.get_result(timer.elapsed()).since(&start);
+                self.on_event(OrganizeEvent::SourceFinished {
+                    source: &source.lay_dir,
+                    result: &result,
+                });
+                source_results.push(SourceResult {
+                    source: source.lay_dir.clone(),
+                    result,
+                });
+                organized?;
+            }
+            Ok(())
+        })();
+
+        // save what was indexed even when organize stops early
+        if let Some(hash_index) = &self.hash_index {
+            if !self.params.options.plan {
+                hash_index.borrow().save()?;
+            }
+        }
+        organized?;
+
+        let mut result = self.get_result(timer.elapsed());
+        result.sources = source_results;
+        Ok(result)
+    }
+
+    /// Moves the photos of the organized directory with the same contents as an earlier photo to the duplicates directory.
+    pub fn dedupe(&self) -> anyhow::Result<OrganizeResult> {
+        self.canceled.store(false, Ordering::SeqCst);
+        self.planned_moves.borrow_mut().clear();
+        self.planned_vacated.borrow_mut().clear();
 
         let timer = Instant::now();
-        self.organize_directory((&self).unorganized_dir.as_ref())?;
-        let duration = timer.elapsed();
-
-        Ok(OrganizeResult {
-            dirs: self.counters.dirs.get(),
-            dirs_skipped: self.counters.dirs_skipped.get(),
-            files: self.counters.files.get(),
-            photos_moved: self.counters.photos_moved.get(),
-            duplicate_photos_moved: self.counters.duplicate_photos_moved.get(),
-            photos_noop: self.counters.photos_noop.get(),
-            files_skipped: self.counters.files_skipped.get(),
-            files_errored: self.counters.files_errored.get(),
+        self.refresh_hash_index()?;
+
+        let mut files = Vec::new();
+        self.find_indexable_files(&self.organized_dir, &mut files)?;
+
+        // hashes that are already indexed are not computed again
+        let indexed_hashes: Vec<Option<String>> = match &self.hash_index {
+            Some(hash_index) => {
+                let hash_index = hash_index.borrow();
+                files
+                    .iter()
+                    .map(|(path, metadata)| {
+                        hash_index
+                            .get_current(path, metadata)
+                            .map(|entry| entry.hash.clone())
+                    })
+                    .collect()
+            }
+            None => vec![None; files.len()],
+        };
+
+        let hashes: Vec<anyhow::Result<String>> = self.pool.install(|| {
+            files
+                .par_iter()
+                .zip(indexed_hashes)
+                .map(|((path, _), hash)| match hash {
+                    Some(hash) => Ok(hash),
+                    None => get_file_hash(path),
+                })
+                .collect()
+        });
+
+        let mut kept: HashSet<String> = HashSet::new();
+        for ((file_path, _), hash) in files.iter().zip(hashes) {
+            if self.canceled.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let hash = match hash {
+                Ok(hash) => hash,
+                Err(err) => {
+                    self.raise_file_error(file_path, err);
+                    continue;
+                }
+            };
+
+            // the first photo with these contents stays
+            if kept.insert(hash.clone()) {
+                continue;
+            }
+
+            let deduped = (|| -> anyhow::Result<()> {
+                if let Some(reason) = self.get_protection_reason(file_path)? {
+                    self.raise_file_skipped(file_path, &reason);
+                    return Ok(());
+                }
+
+                let sidecars = find_sidecars(file_path)?;
+                let mut date_time_info = PhotoDateTimeInfo::load(file_path)?;
+                date_time_info.read_name(file_path, &self.params.options.name_patterns);
+                date_time_info.correct_clock(&self.params.options.clock_corrections);
+                let group = PhotoGroup {
+                    primary: file_path,
+                    date_time_info: Box::new(date_time_info),
+                    hash: Some(hash.clone()),
+                    fixed_extension: None,
+                    companions: sidecars
+                        .iter()
+                        .map(|sidecar_path| Companion {
+                            file_path: sidecar_path,
+                            hash: None,
+                            fixed_extension: None,
+                        })
+                        .collect(),
+                };
+                self.organize_duplicate(&group, &hash)
+            })();
+
+            if let Err(err) = deduped {
+                self.raise_file_error(file_path, err);
+            }
+        }
+
+        if let Some(hash_index) = &self.hash_index {
+            if !self.params.options.plan {
+                hash_index.borrow().save()?;
+            }
+        }
+
+        Ok(self.get_result(timer.elapsed()))
+    }
+
+    fn get_result(&self, duration: Duration) -> OrganizeResult {
+        OrganizeResult {
+            dirs: self.counters.dirs.load(Ordering::SeqCst),
+            dirs_skipped: self.counters.dirs_skipped.load(Ordering::SeqCst),
+            files: self.counters.files.load(Ordering::SeqCst),
+            photos_moved: self.counters.photos_moved.load(Ordering::SeqCst),
+            duplicate_photos_moved: self.counters.duplicate_photos_moved.load(Ordering::SeqCst),
+            companions_moved: self.counters.companions_moved.load(Ordering::SeqCst),
+            photos_noop: self.counters.photos_noop.load(Ordering::SeqCst),
+            extensions_fixed: self.counters.extensions_fixed.load(Ordering::SeqCst),
+            links_removed: self.counters.links_removed.load(Ordering::SeqCst),
+            files_skipped: self.counters.files_skipped.load(Ordering::SeqCst),
+            files_errored: self.counters.files_errored.load(Ordering::SeqCst),
+            dated_by: DateSource::ALL
+                .iter()
+                .map(|source| {
+                    let count = self.counters.dated_by[*source as usize].load(Ordering::SeqCst);
+                    (*source, count)
+                })
+                .filter(|(_, count)| *count > 0)
+                .collect(),
             duration,
-        })
+            sources: Vec::new(),
+        }
+    }
+
+    /// Organizes the current source: its whole tree is walked first, then every file is prepared on the
+    /// worker pool in one batch, then the directories are organized in the order of the walk.
+    fn organize_source(&self, dir: &Path) -> anyhow::Result<()> {
+        let mut steps = Vec::new();
+        let mut files = Vec::new();
+        let mut skip_reasons = Vec::new();
+        // a directory that cannot be read stops the walk, but what was walked before it is still organized
+        let walked = self.walk_directory(dir, &[], &mut steps, &mut files, &mut skip_reasons);
+        *self.pending_files.borrow_mut() = files.iter().cloned().collect();
+
+        let files: Vec<&PathBuf> = files.iter().collect();
+        let mut prepared_files = self.prepare_files(&files, skip_reasons).into_iter();
+        let mut remaining_files = files.as_slice();
+
+        for step in steps {
+            match step {
+                WalkStep::DirSkipped(dir, reason) => self.raise_dir_skipped(&dir, &reason),
+                WalkStep::InvalidIgnoreFile(dir, ignore_file, err) => {
+                    self.raise_file_error(&ignore_file, err);
+                    self.raise_dir_skipped(&dir, "Directory has an invalid ignore file.");
+                }
+                WalkStep::DirStarted(dir, file_count) => {
+                    self.raise_dir_started(&dir);
+
+                    let (dir_files, rest) = remaining_files.split_at(file_count);
+                    remaining_files = rest;
+                    let dir_prepared_files = prepared_files.by_ref().take(file_count).collect();
+                    self.organize_files(dir_files, dir_prepared_files);
+                }
+                WalkStep::DirFinished => self.raise_dir_finished(),
+            }
+        }
+        self.pending_files.borrow_mut().clear();
+
+        walked
     }
 
-    fn organize_directory(&self, dir: &Path) -> anyhow::Result<()> {
+    /// Walks a directory of the current source, adding the steps to organize it and its files (with why each
+    /// file is skipped, if it is). `ignores` are the ignore files of its parent directories.
+    fn walk_directory(
+        &self,
+        dir: &Path,
+        ignores: &[&Gitignore],
+        steps: &mut Vec<WalkStep>,
+        files: &mut Vec<PathBuf>,
+        skip_reasons: &mut Vec<Option<String>>,
+    ) -> anyhow::Result<()> {
+        let source = &self.sources[self.current_source.get()];
+        let mut skip_dir =
+            |reason: &str| steps.push(WalkStep::DirSkipped(dir.into(), reason.into()));
+
         // do not process the duplicates directory
         if dir == self.duplicates_dir {
-            self.raise_dir_skipped(dir, "Directory is the duplicates directory.");
+            skip_dir("Directory is the duplicates directory.");
+            return Ok(());
+        }
+
+        // do not process the symbolic links of the organized view
+        if self.params.options.mode == TransferMode::Symlink && dir == self.organized_dir {
+            skip_dir("Directory is the organized view.");
+            return Ok(());
+        }
+
+        // a source inside this source is organized on its own
+        if dir != source.dir && self.sources.iter().any(|s| s.dir == dir) {
+            skip_dir("Directory is another unorganized directory.");
+            return Ok(());
+        }
+
+        if let Some(reason) = self.get_dir_skip_reason(dir, ignores) {
+            skip_dir(&reason);
             return Ok(());
         }
 
-        self.raise_dir_started(dir);
+        if let Some(marker_file) = self.params.options.protection.find_marker_file(dir) {
+            skip_dir(&format!(
+                "Directory is protected: it contains '{}'.",
+                marker_file
+            ));
+            return Ok(());
+        }
+
+        // the ignore file of this directory applies to it and its subdirectories
+        let ignore_file = dir.join(IGNORE_FILE_NAME);
+        let dir_ignore = match ignore_file.is_file() {
+            true => match load_ignore_file(dir, &ignore_file) {
+                Ok(dir_ignore) => Some(dir_ignore),
+                Err(err) => {
+                    steps.push(WalkStep::InvalidIgnoreFile(dir.into(), ignore_file, err));
+                    return Ok(());
+                }
+            },
+            false => None,
+        };
+        let mut ignores = ignores.to_vec();
+        ignores.extend(dir_ignore.as_ref());
 
         let mut entries = fs::read_dir(dir)?
             .map(|res| res.map(|e| e.path()))
             .collect::<Result<Vec<_>, io::Error>>()?;
         entries.sort();
 
-        // organize files in this directory
-        for e in entries.iter().filter(|e| e.is_file()) {
-            match self.organize_file(&e) {
-                Ok(_) => {}
-                Err(err) => self.raise_file_error(&e, err),
-            }
+        let (dir_files, child_dirs): (Vec<PathBuf>, Vec<PathBuf>) = entries
+            .into_iter()
+            .filter(|e| e.is_file() || e.is_dir())
+            .partition(|e| e.is_file());
+        steps.push(WalkStep::DirStarted(dir.into(), dir_files.len()));
+        for file_path in dir_files {
+            skip_reasons.push(self.get_file_skip_reason(&file_path, &ignores));
+            files.push(file_path);
         }
 
-        // organize child directories
-        for e in entries.iter().filter(|e| e.is_dir()) {
-            self.organize_directory(&e)?;
+        // walk child directories
+        if source.recursive {
+            for child_dir in &child_dirs {
+                self.walk_directory(child_dir, &ignores, steps, files, skip_reasons)?;
+            }
         }
 
-        self.raise_dir_finished(dir);
+        steps.push(WalkStep::DirFinished);
 
         Ok(())
     }
 
-    fn organize_file(&self, file_path: &Path) -> anyhow::Result<()> {
-        self.raise_file_started(file_path);
+    /// Organizes the prepared files of a directory in order, by photo group.
+    fn organize_files(
+        &self,
+        files: &[&PathBuf],
+        prepared_files: Vec<anyhow::Result<PreparedFile>>,
+    ) {
+        for prepared_entry in group_files(files, prepared_files) {
+            match prepared_entry {
+                PreparedEntry::File(file_path, prepared_file) => {
+                    match self.organize_file(file_path, prepared_file) {
+                        Ok(_) => {}
+                        Err(err) => self.raise_file_error(file_path, err),
+                    }
+                }
+                PreparedEntry::Group(group) => {
+                    match self.organize_group(&group) {
+                        Ok(_) => {}
+                        Err(err) => self.raise_file_error(group.primary, err),
+                    }
 
-        if self.canceled.get() {
-            return Ok(());
+                    let mut pending_files = self.pending_files.borrow_mut();
+                    for companion in &group.companions {
+                        pending_files.remove(companion.file_path);
+                    }
+                    pending_files.remove(group.primary);
+                }
+            }
         }
+    }
 
-        // only handle files with photo extensions
-        if !is_photo_file(file_path) {
-            self.raise_file_skipped(file_path, "File does not have a photo extension.");
-            return Ok(());
+    /// Gets why a photo of the organized directory is protected, by its own rules or by a marker file
+    /// in its directory or any parent directory inside the organized directory, if it is.
+    fn get_protection_reason(&self, file_path: &Path) -> anyhow::Result<Option<String>> {
+        let protection = &self.params.options.protection;
+        if let Some(reason) = protection.get_file_reason(file_path)? {
+            return Ok(Some(reason));
         }
 
-        // never move a file with ! in the name
-        if file_path
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .contains("!")
+        for dir in file_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.organized_dir))
+        {
+            if let Some(marker_file) = protection.find_marker_file(dir) {
+                return Ok(Some(format!(
+                    "File is protected: its directory {:?} contains '{}'.",
+                    decry_path(dir, &self.organized_dir, &self.params.organized_dir),
+                    marker_file
+                )));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Gets why a directory of the current source is skipped by its globs or ignore files, if it is.
+    fn get_dir_skip_reason(&self, dir: &Path, ignores: &[&Gitignore]) -> Option<String> {
+        let source = &self.sources[self.current_source.get()];
+        let relative_dir = dir.strip_prefix(&source.dir).ok()?;
+        if relative_dir.as_os_str().is_empty() {
+            return None;
+        }
+
+        if let Some(pattern) = source.exclude_dirs.find_match(relative_dir) {
+            return Some(format!(
+                "Directory matches the exclude pattern '{}'.",
+                pattern
+            ));
+        }
+
+        find_ignore_match(ignores, dir, true).map(|(pattern, ignore_file)| {
+            format!(
+                "Directory is ignored by '{}' in {:?}.",
+                pattern,
+                self.decry_source_path(ignore_file)
+            )
+        })
+    }
+
+    /// Gets why a file of the current source is skipped by its globs or ignore files, if it is.
+    fn get_file_skip_reason(&self, file_path: &Path, ignores: &[&Gitignore]) -> Option<String> {
+        let source = &self.sources[self.current_source.get()];
+        let relative_path = file_path.strip_prefix(&source.dir).ok()?;
+
+        if file_path.file_name() == Some(IGNORE_FILE_NAME.as_ref()) {
+            return Some("File is an ignore file.".to_string());
+        }
+
+        if let Some(pattern) = source.exclude_files.find_match(relative_path) {
+            return Some(format!("File matches the exclude pattern '{}'.", pattern));
+        }
+
+        if !source.include_files.is_empty()
+            && source.include_files.find_match(relative_path).is_none()
+        {
+            return Some("File does not match an include pattern.".to_string());
+        }
+
+        // the files of any directory that matches are included
+        if !source.include_dirs.is_empty()
+            && !relative_path
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .any(|dir| source.include_dirs.find_match(dir).is_some())
         {
-            self.raise_file_skipped(file_path, "File name contains '!'.");
+            return Some("File is not in a directory that matches an include pattern.".to_string());
+        }
+
+        find_ignore_match(ignores, file_path, false).map(|(pattern, ignore_file)| {
+            format!(
+                "File is ignored by '{}' in {:?}.",
+                pattern,
+                self.decry_source_path(ignore_file)
+            )
+        })
+    }
+
+    /// Reads the date-times (and hashes, when they will be needed) of files on the worker pool.
+    /// Files with a skip reason are skipped without being read. The results are in the same order as the files.
+    fn prepare_files(
+        &self,
+        files: &[&PathBuf],
+        skip_reasons: Vec<Option<String>>,
+    ) -> Vec<anyhow::Result<PreparedFile>> {
+        // the journal and the hash index need the hash of every photo, so hash up front for them too
+        // copies are verified against the hash of the original
+        let needs_hash = self.params.options.organized_template.uses_hash()
+            || self.hash_index.is_some()
+            || (self.journal.is_some() && !self.params.options.plan)
+            || (self.params.options.mode == TransferMode::Copy && !self.params.options.plan);
+        let fix_extensions = self.params.options.fix_extensions;
+        let protection = &self.params.options.protection;
+        let clock_corrections = &self.params.options.clock_corrections;
+        let name_patterns = &self.params.options.name_patterns;
+        let canceled = &self.canceled;
+
+        self.pool.install(|| {
+            files
+                .par_iter()
+                .zip(skip_reasons)
+                .map(|(file_path, skip_reason)| {
+                    if canceled.load(Ordering::SeqCst) {
+                        return Ok(PreparedFile::Skipped("Organize was canceled.".into()));
+                    }
+                    if let Some(reason) = skip_reason {
+                        return Ok(PreparedFile::Skipped(reason.into()));
+                    }
+                    prepare_file(
+                        file_path,
+                        needs_hash,
+                        fix_extensions,
+                        protection,
+                        clock_corrections,
+                        name_patterns,
+                    )
+                })
+                .collect()
+        })
+    }
+
+    /// Organizes a file that is not part of a photo group: a skipped file, a file that could not
+    /// be prepared, or a sidecar without a photo.
+    fn organize_file(
+        &self,
+        file_path: &Path,
+        prepared_file: anyhow::Result<PreparedFile>,
+    ) -> anyhow::Result<()> {
+        if self.canceled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        match prepared_file? {
+            PreparedFile::Skipped(reason) => self.raise_file_skipped(file_path, &reason),
+            PreparedFile::Sidecar { .. } => {
+                self.raise_file_skipped(file_path, "Sidecar file does not have a photo.")
+            }
+            PreparedFile::Photo { .. } => {
+                unreachable!("Photos are always organized as part of a photo group.")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Organizes a photo and its companions. The primary photo decides where the group goes,
+    /// and every file of the group gets the same destination name (and conflict number).
+    fn organize_group(&self, group: &PhotoGroup) -> anyhow::Result<()> {
+        let file_path = group.primary;
+
+        if self.canceled.load(Ordering::SeqCst) {
             return Ok(());
         }
 
-        let photo_date_time_info = PhotoDateTimeInfo::load(file_path)?;
-        let photo_date_time = photo_date_time_info.best();
+        for warning in group.date_time_info.warnings() {
+            self.raise_file_warning(file_path, warning);
+        }
+
+        // a photo already in the organized directory (by contents) is a duplicate, whatever its date
+        if let Some(hash) = &group.hash {
+            if self.is_indexed_duplicate(file_path, hash) {
+                if !self.vacates_source() {
+                    return self.organize_already_organized(group);
+                }
+                return self.organize_duplicate(group, hash);
+            }
+        }
+
+        let template = &self.params.options.organized_template;
 
         let mut conflict = 0;
         loop {
             // check for cancellation at the start of each iteration
-            if self.canceled.get() {
+            if self.canceled.load(Ordering::SeqCst) {
                 return Ok(());
             }
 
-            let dest_path = get_organized_photo_path(
+            let dest_path = self.get_group_path(
+                template,
+                &self.organized_dir,
+                group,
                 file_path,
-                &photo_date_time,
+                group.fixed_extension,
+                group.hash.as_deref(),
                 conflict,
-                &self.organized_dir,
             )?;
 
             // if the file is already in the right place, do nothing
-            if file_path.to_str() == dest_path.to_str() {
+            if self.is_at(file_path, &dest_path) {
+                if let Some(hash) = &group.hash {
+                    self.index_file(&dest_path, &fs::metadata(file_path)?, hash);
+                }
                 self.raise_file_noop(file_path);
 
-                if self.canceled.get() {
+                if self.canceled.load(Ordering::SeqCst) {
                     return Ok(());
                 }
 
@@ -299,128 +1210,630 @@ where
             }
 
             // if there is already a file in this location,
-            if dest_path.exists() {
-                match are_same_file_contents(file_path, &dest_path)? {
-                    Some(hash) => {
-                        self.organize_duplicate(file_path, &photo_date_time, &hash)?;
+            if let Some(occupant_path) = self.get_occupant(&dest_path) {
+                match are_same_file_contents(file_path, group.hash.as_deref(), &occupant_path)? {
+                    // a source that stays in place was organized by an earlier run (e.g. copying a card again)
+                    Some(_) if !self.vacates_source() => {
+                        self.raise_file_noop(file_path);
                         break;
                     }
+                    Some(hash) => return self.organize_duplicate(group, &hash),
                     None => {
                         // if there is a different file in this location, try again with a higher conflict number
                         conflict += 1;
                         continue;
                     }
                 }
-            } else {
-                // move the file to the destination
-                move_file(file_path, dest_path.as_ref())?;
-                self.raise_file_moved(file_path, &dest_path);
-                break;
             }
+
+            // the companions must fit at the same conflict number
+            if !self.are_companion_paths_free(
+                template,
+                &self.organized_dir,
+                group,
+                group.hash.as_deref(),
+                conflict,
+            )? {
+                conflict += 1;
+                continue;
+            }
+
+            // move the file to the destination
+            self.raise_extension_fixed(file_path, group.fixed_extension);
+            self.move_photo(
+                MoveKind::PhotoMoved,
+                file_path,
+                dest_path.as_ref(),
+                group.hash.as_deref(),
+            )?;
+            self.raise_file_moved(file_path, &dest_path, group);
+            break;
         }
 
-        self.raise_file_finished(file_path);
+        self.finish_file();
+        self.organize_companions(
+            MoveKind::PhotoMoved,
+            template,
+            &self.organized_dir,
+            group,
+            group.hash.as_deref(),
+            conflict,
+        );
 
         Ok(())
     }
 
-    fn organize_duplicate(
-        &self,
-        file_path: &Path,
-        date_time: &chrono::DateTime<Utc>,
-        hash: &str,
-    ) -> anyhow::Result<()> {
+    /// Leaves a group whose primary photo is already in the organized directory (by contents) when the mode
+    /// leaves sources in place, since an earlier run organized it (e.g. copying the same card twice).
+    fn organize_already_organized(&self, group: &PhotoGroup) -> anyhow::Result<()> {
+        self.raise_file_noop(group.primary);
+        self.finish_file();
+
+        for companion in group.companions.iter() {
+            self.raise_file_noop(companion.file_path);
+            self.finish_file();
+        }
+
+        Ok(())
+    }
+
+    fn organize_duplicate(&self, group: &PhotoGroup, hash: &str) -> anyhow::Result<()> {
+        let file_path = group.primary;
+        let template = &self.params.options.duplicates_template;
+
         let mut conflict = 0;
         loop {
             // check for cancellation at the start of each iteration
-            if self.canceled.get() {
+            if self.canceled.load(Ordering::SeqCst) {
                 return Ok(());
             }
 
-            let dest_path = get_duplicate_photo_path(
+            let dest_path = self.get_group_path(
+                template,
+                &self.duplicates_dir,
+                group,
                 file_path,
-                date_time,
-                hash,
+                group.fixed_extension,
+                Some(hash),
                 conflict,
-                &self.duplicates_dir,
             )?;
 
             // if the duplicate is already in the right place, do nothing
-            if file_path.to_str() == dest_path.to_str() {
+            if self.is_at(file_path, &dest_path) {
                 self.raise_file_noop(file_path);
                 break;
             }
 
-            // if there is an existing duplicate, try again with a higher conflict number
-            if dest_path.exists() {
+            // if there is an existing duplicate (or companion), try again with a higher conflict number
+            if self.get_occupant(&dest_path).is_some()
+                || !self.are_companion_paths_free(
+                    template,
+                    &self.duplicates_dir,
+                    group,
+                    Some(hash),
+                    conflict,
+                )?
+            {
                 conflict += 1;
                 continue;
             }
 
             // move the duplicate to the destination
-            move_file(file_path, &dest_path)?;
+            self.raise_extension_fixed(file_path, group.fixed_extension);
+            self.move_photo(
+                MoveKind::DuplicatePhotoMoved,
+                file_path,
+                &dest_path,
+                Some(hash),
+            )?;
             self.raise_duplicate_moved(file_path, &dest_path);
             break;
         }
 
+        self.finish_file();
+        self.organize_companions(
+            MoveKind::DuplicatePhotoMoved,
+            template,
+            &self.duplicates_dir,
+            group,
+            Some(hash),
+            conflict,
+        );
+
+        Ok(())
+    }
+
+    /// Moves the companions of a group next to its primary photo.
+    /// Each companion raises its own events, and an error with one companion does not stop the others.
+    fn organize_companions(
+        &self,
+        kind: MoveKind,
+        template: &PathTemplate,
+        base_dir: &Path,
+        group: &PhotoGroup,
+        hash: Option<&str>,
+        conflict: u32,
+    ) {
+        for companion in group.companions.iter() {
+            let file_path = companion.file_path;
+
+            if self.canceled.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let organized = (|| -> anyhow::Result<()> {
+                let dest_path = self.get_group_path(
+                    template,
+                    base_dir,
+                    group,
+                    file_path,
+                    companion.fixed_extension,
+                    hash,
+                    conflict,
+                )?;
+
+                if self.is_at(file_path, &dest_path) {
+                    self.raise_file_noop(file_path);
+                } else if let Some(occupant_path) = self.get_occupant(&dest_path) {
+                    // only possible when the primary photo was already in place (or already copied there)
+                    if !self.vacates_source()
+                        && are_same_file_contents(
+                            file_path,
+                            companion.hash.as_deref(),
+                            &occupant_path,
+                        )?
+                        .is_some()
+                    {
+                        self.raise_file_noop(file_path);
+                    } else {
+                        self.raise_file_skipped(
+                            file_path,
+                            "Another file is at the destination of this companion.",
+                        );
+                    }
+                } else {
+                    self.raise_extension_fixed(file_path, companion.fixed_extension);
+                    self.move_photo(kind, file_path, &dest_path, companion.hash.as_deref())?;
+                    self.raise_companion_moved(file_path, &dest_path, kind);
+                }
+
+                Ok(())
+            })();
+
+            match organized {
+                Ok(_) => self.finish_file(),
+                Err(err) => self.raise_file_error(file_path, err),
+            }
+        }
+    }
+
+    /// True if no other file is at the destination of any companion of the group.
+    fn are_companion_paths_free(
+        &self,
+        template: &PathTemplate,
+        base_dir: &Path,
+        group: &PhotoGroup,
+        hash: Option<&str>,
+        conflict: u32,
+    ) -> anyhow::Result<bool> {
+        for companion in group.companions.iter() {
+            let file_path = companion.file_path;
+            let dest_path = self.get_group_path(
+                template,
+                base_dir,
+                group,
+                file_path,
+                companion.fixed_extension,
+                hash,
+                conflict,
+            )?;
+
+            if !self.is_at(file_path, &dest_path) && self.get_occupant(&dest_path).is_some() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Gets the destination of a file of a group. Every file uses the date-time, camera, and hash
+    /// of the primary photo, so only the extension differs.
+    /// A sidecar named after the full name of its photo (e.g. `IMG_0001.CR2.xmp`) keeps that form.
+    /// A `fixed_extension` replaces the extension of the file.
+    #[allow(clippy::too_many_arguments)]
+    fn get_group_path(
+        &self,
+        template: &PathTemplate,
+        base_dir: &Path,
+        group: &PhotoGroup,
+        file_path: &Path,
+        fixed_extension: Option<&str>,
+        hash: Option<&str>,
+        conflict: u32,
+    ) -> anyhow::Result<PathBuf> {
+        let (named_path, sidecar_ext) = split_sidecar_name(file_path);
+
+        let mut dest_path = template.render(
+            &PathTemplateValues {
+                file_path: &named_path,
+                date_time: &group
+                    .date_time_info
+                    .best(&self.params.options.date_sources)
+                    .1
+                    .in_time_zone(&self.params.options.time_zone),
+                make: group.date_time_info.camera_make(),
+                model: group.date_time_info.camera_model(),
+                extension: fixed_extension,
+                hash,
+                conflict,
+            },
+            base_dir,
+        )?;
+
+        if let Some(sidecar_ext) = sidecar_ext {
+            let mut file_name = dest_path.file_name().unwrap_or_default().to_os_string();
+            file_name.push(".");
+            file_name.push(sidecar_ext);
+            dest_path.set_file_name(file_name);
+        }
+
+        Ok(dest_path)
+    }
+
+    /// True if the file is already at the destination: it is the destination,
+    /// or (in symlink mode) the destination is a symbolic link to it.
+    fn is_at(&self, file_path: &Path, dest_path: &Path) -> bool {
+        if file_path.as_os_str() == dest_path.as_os_str() {
+            return true;
+        }
+
+        self.params.options.mode == TransferMode::Symlink
+            && is_symlink(dest_path)
+            && fs::canonicalize(dest_path).ok().as_deref() == Some(file_path)
+    }
+
+    /// Removes the symbolic links in the organized and duplicates directories whose photos no longer exist,
+    /// so that a symlink view can be refreshed by organizing again. This only happens in symlink mode.
+    fn remove_dangling_links(&self) -> anyhow::Result<()> {
+        if self.params.options.mode != TransferMode::Symlink {
+            return Ok(());
+        }
+
+        let mut links = Vec::new();
+        for dir in [&self.organized_dir, &self.duplicates_dir] {
+            if dir.exists() {
+                self.find_dangling_links(dir, &mut links)?;
+            }
+        }
+
+        for link in links {
+            if !self.params.options.plan {
+                fs::remove_file(&link)?;
+            }
+
+            if let Some(hash_index) = &self.hash_index {
+                hash_index.borrow_mut().remove(&link);
+            }
+            self.raise_link_removed(&link);
+        }
+
         Ok(())
     }
 
+    /// Finds the symbolic links whose targets no longer exist, skipping the unorganized directories.
+    fn find_dangling_links(&self, dir: &Path, links: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        if self.sources.iter().any(|s| s.dir == dir) {
+            return Ok(());
+        }
+
+        let mut entries = fs::read_dir(dir)?
+            .map(|res| res.map(|e| e.path()))
+            .collect::<Result<Vec<_>, io::Error>>()?;
+        entries.sort();
+
+        for e in entries {
+            if is_symlink(&e) {
+                if !e.exists() {
+                    links.push(e);
+                }
+            } else if e.is_dir() {
+                self.find_dangling_links(&e, links)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the path of the file that occupies a destination, taking planned moves into account.
+    /// While planning, a planned destination is occupied by the (not yet moved) source file.
+    fn get_occupant(&self, dest_path: &Path) -> Option<PathBuf> {
+        if self.params.options.plan {
+            if let Some(source_path) = self.planned_moves.borrow().get(dest_path) {
+                return Some(source_path.clone());
+            }
+
+            if self.planned_vacated.borrow().contains(dest_path) {
+                return None;
+            }
+        }
+
+        if dest_path.exists() {
+            Some(dest_path.to_path_buf())
+        } else {
+            None
+        }
+    }
+
+    /// True if transferring a photo leaves nothing at its original location (moves, and copies that delete the source).
+    fn vacates_source(&self) -> bool {
+        let options = &self.params.options;
+        options.mode == TransferMode::Move
+            || (options.mode == TransferMode::Copy && options.delete_source)
+    }
+
+    /// Moves (or copies) the photo, or records the transfer when planning.
+    /// When journaling, the transfer is recorded as pending (with the photo's hash) before the photo is transferred,
+    /// and committed once it succeeds.
+    fn move_photo(
+        &self,
+        kind: MoveKind,
+        from: &Path,
+        to: &Path,
+        hash: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let options = &self.params.options;
+
+        // a transfer keeps the size and modified time, so the source metadata describes the destination too
+        let metadata = fs::metadata(from)?;
+        let vacates_source = self.vacates_source();
+
+        if options.plan {
+            self.planned_moves
+                .borrow_mut()
+                .insert(to.to_path_buf(), from.to_path_buf());
+            if vacates_source {
+                self.planned_vacated.borrow_mut().insert(from.to_path_buf());
+            }
+        } else {
+            let pending = match &self.journal {
+                Some(journal) => {
+                    let hash = match hash {
+                        Some(hash) => hash.to_string(),
+                        None => get_file_hash(from)?,
+                    };
+                    Some(journal.record(kind, options.mode, from, to, &hash)?)
+                }
+                None => None,
+            };
+
+            match options.mode {
+                TransferMode::Move => move_file(from, to, hash)?,
+                // the original is only deleted once its copy is verified
+                TransferMode::Copy if options.delete_source => move_by_copy(from, to, hash)?,
+                TransferMode::Copy => copy_file_verified(from, to, hash)?,
+                TransferMode::Symlink => symlink_file(from, to)?,
+                TransferMode::HardLink | TransferMode::Reflink => {
+                    if let Some(reason) = link_file(options.mode, from, to, hash)? {
+                        self.raise_copy_fallback(from, &reason);
+                    }
+                }
+            }
+
+            // a transfer that failed stays pending, so undo and verify pass over it
+            if let (Some(journal), Some(pending)) = (&self.journal, &pending) {
+                journal.commit(pending)?;
+            }
+        }
+
+        if let Some(hash_index) = &self.hash_index {
+            if vacates_source {
+                hash_index.borrow_mut().remove(from);
+            }
+        }
+        if let Some(hash) = hash {
+            self.index_file(to, &metadata, hash);
+        }
+
+        Ok(())
+    }
+
+    /// Brings the hash index up to date with the organized directory.
+    /// Unchanged files keep their hash, new and changed files are hashed on the worker pool,
+    /// and files that are gone (or are inside the unorganized or duplicates directories) are removed.
+    fn refresh_hash_index(&self) -> anyhow::Result<()> {
+        let hash_index = match &self.hash_index {
+            Some(hash_index) => hash_index,
+            None => return Ok(()),
+        };
+
+        let mut files = Vec::new();
+        if self.organized_dir.exists() {
+            self.find_indexable_files(&self.organized_dir, &mut files)?;
+        }
+
+        let to_hash: Vec<&(PathBuf, fs::Metadata)> = {
+            let hash_index = hash_index.borrow();
+            files
+                .iter()
+                .filter(|(path, metadata)| hash_index.get_current(path, metadata).is_none())
+                .collect()
+        };
+
+        let hashes = self.pool.install(|| {
+            to_hash
+                .par_iter()
+                .map(|(path, _)| get_file_hash(path))
+                .collect::<Vec<_>>()
+        });
+
+        let mut hash_index = hash_index.borrow_mut();
+        for ((path, metadata), hash) in to_hash.into_iter().zip(hashes) {
+            hash_index.insert_file(path, metadata, &hash?);
+        }
+
+        let indexed: HashSet<&Path> = files.iter().map(|(path, _)| path.as_path()).collect();
+        hash_index.retain(|path| indexed.contains(path));
+
+        Ok(())
+    }
+
+    /// Finds the photos of the organized directory, skipping the unorganized and duplicates directories.
+    fn find_indexable_files(
+        &self,
+        dir: &Path,
+        files: &mut Vec<(PathBuf, fs::Metadata)>,
+    ) -> anyhow::Result<()> {
+        // an unorganized directory is only skipped when it is not the organized directory itself
+        if (dir != self.organized_dir && self.sources.iter().any(|s| s.dir == dir))
+            || dir == self.duplicates_dir
+        {
+            return Ok(());
+        }
+
+        let mut entries = fs::read_dir(dir)?
+            .map(|res| res.map(|e| e.path()))
+            .collect::<Result<Vec<_>, io::Error>>()?;
+        entries.sort();
+
+        for e in entries {
+            if e.is_dir() {
+                self.find_indexable_files(&e, files)?;
+            } else if is_photo_file(&e)? {
+                let metadata = fs::metadata(&e)?;
+                files.push((e, metadata));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// True if another photo in the organized directory has the same contents.
+    /// When organizing in place, photos of the source that are not organized yet do not count: the first of
+    /// the copies to be organized is kept, and it may be one that is already at its organized location.
+    fn is_indexed_duplicate(&self, file_path: &Path, hash: &str) -> bool {
+        let hash_index = match &self.hash_index {
+            Some(hash_index) => hash_index.borrow(),
+            None => return false,
+        };
+        let pending_files = self.pending_files.borrow();
+
+        let is_duplicate = hash_index.find_hash(hash).any(|path| {
+            !self.is_at(file_path, path)
+                && !pending_files.contains(path)
+                && self.get_occupant(path).is_some()
+        });
+        is_duplicate
+    }
+
+    /// Adds a photo to the hash index when it is in the organized directory.
+    fn index_file(&self, path: &Path, metadata: &fs::Metadata, hash: &str) {
+        if let Some(hash_index) = &self.hash_index {
+            // sidecars are not indexed, only photos can be duplicates
+            if path.starts_with(&self.organized_dir)
+                && !path.starts_with(&self.duplicates_dir)
+                && get_file_kind(path) != Some(FileKind::Sidecar)
+            {
+                hash_index.borrow_mut().insert_file(path, metadata, hash);
+            }
+        }
+    }
+
     // -------------------- Events --------------------//
 
     fn raise_dir_started(&self, dir: &Path) {
         self.on_event(OrganizeEvent::DirStarted {
-            dir: &decry_path(dir, &self.unorganized_dir, &self.params.unorganized_dir),
+            dir: &self.decry_source_path(dir),
         });
     }
 
-    fn raise_dir_finished(&self, dir: &Path) {
+    fn raise_dir_finished(&self) {
         increment(&self.counters.dirs);
-        self.on_event(OrganizeEvent::DirFinished {
-            dir: &decry_path(dir, &self.unorganized_dir, &self.params.unorganized_dir),
-        });
+        self.on_event(OrganizeEvent::DirFinished);
     }
 
     fn raise_dir_skipped(&self, dir: &Path, reason: &str) {
         increment(&self.counters.dirs_skipped);
         self.on_event(OrganizeEvent::DirSkipped {
-            dir: &decry_path(dir, &self.unorganized_dir, &self.params.unorganized_dir),
+            dir: &self.decry_source_path(dir),
             reason,
         });
     }
 
-    fn raise_file_started(&self, file: &Path) {
-        self.on_event(OrganizeEvent::FileStarted {
-            file: &decry_path(file, &self.unorganized_dir, &self.params.unorganized_dir),
+    /// Counts a file whose processing finished (every file raises another event about what happened to it).
+    fn finish_file(&self) {
+        increment(&self.counters.files);
+    }
+
+    fn raise_extension_fixed(&self, file: &Path, extension: Option<&str>) {
+        if let Some(extension) = extension {
+            increment(&self.counters.extensions_fixed);
+            self.on_event(OrganizeEvent::ExtensionFixed {
+                file: &self.decry_source_path(file),
+                extension,
+            });
+        }
+    }
+
+    fn raise_link_removed(&self, link: &Path) {
+        increment(&self.counters.links_removed);
+        let link = if link.starts_with(&self.duplicates_dir) {
+            decry_path(link, &self.duplicates_dir, &self.params.duplicates_dir)
+        } else {
+            decry_path(link, &self.organized_dir, &self.params.organized_dir)
+        };
+        self.on_event(OrganizeEvent::LinkRemoved { link: &link });
+    }
+
+    fn raise_file_warning(&self, file: &Path, warning: &str) {
+        self.on_event(OrganizeEvent::FileWarning {
+            file: &self.decry_source_path(file),
+            warning,
         });
     }
 
-    fn raise_file_finished(&self, file: &Path) {
-        increment(&self.counters.files);
-        self.on_event(OrganizeEvent::FileFinished {
-            file: &decry_path(file, &self.unorganized_dir, &self.params.unorganized_dir),
+    fn raise_copy_fallback(&self, file: &Path, reason: &str) {
+        self.on_event(OrganizeEvent::CopyFallback {
+            file: &self.decry_source_path(file),
+            reason,
         });
     }
 
-    fn raise_file_moved(&self, from: &Path, to: &Path) {
+    fn raise_file_moved(&self, from: &Path, to: &Path, group: &PhotoGroup) {
+        let (dated_by, _) = group.date_time_info.best(&self.params.options.date_sources);
         increment(&self.counters.photos_moved);
+        increment(&self.counters.dated_by[dated_by as usize]);
         self.on_event(OrganizeEvent::PhotoMoved {
-            from: &decry_path(from, &self.unorganized_dir, &self.params.unorganized_dir),
+            from: &self.decry_source_path(from),
             to: &decry_path(to, &self.organized_dir, &self.params.organized_dir),
+            dated_by,
+        });
+    }
+
+    fn raise_companion_moved(&self, from: &Path, to: &Path, kind: MoveKind) {
+        let to = match kind {
+            MoveKind::PhotoMoved => decry_path(to, &self.organized_dir, &self.params.organized_dir),
+            MoveKind::DuplicatePhotoMoved => {
+                decry_path(to, &self.duplicates_dir, &self.params.duplicates_dir)
+            }
+        };
+        increment(&self.counters.companions_moved);
+        self.on_event(OrganizeEvent::CompanionMoved {
+            from: &self.decry_source_path(from),
+            to: &to,
         });
     }
 
     fn raise_file_noop(&self, file: &Path) {
         increment(&self.counters.photos_noop);
         self.on_event(OrganizeEvent::PhotoNoOp {
-            file: &decry_path(file, &self.unorganized_dir, &self.params.unorganized_dir),
+            file: &self.decry_source_path(file),
         });
     }
 
     fn raise_duplicate_moved(&self, from: &Path, to: &Path) {
         increment(&self.counters.duplicate_photos_moved);
         self.on_event(OrganizeEvent::DuplicatePhotoMoved {
-            from: &decry_path(from, &self.unorganized_dir, &self.params.unorganized_dir),
+            from: &self.decry_source_path(from),
             to: &decry_path(to, &self.duplicates_dir, &self.params.duplicates_dir),
         });
     }
@@ -428,7 +1841,7 @@ where
     fn raise_file_skipped(&self, file: &Path, reason: &str) {
         increment(&self.counters.files_skipped);
         self.on_event(OrganizeEvent::FileSkipped {
-            file: &decry_path(file, &self.unorganized_dir, &self.params.unorganized_dir),
+            file: &self.decry_source_path(file),
             reason,
         });
     }
@@ -436,123 +1849,277 @@ where
     fn raise_file_error(&self, file: &Path, error: anyhow::Error) {
         increment(&self.counters.files_errored);
         self.on_event(OrganizeEvent::FileError {
-            file: &decry_path(file, &self.unorganized_dir, &self.params.unorganized_dir),
+            file: &self.decry_source_path(file),
             error,
         });
     }
 
+    /// Gets the path of a file of the current source as it would be under the source directory that was given.
+    fn decry_source_path(&self, path: &Path) -> PathBuf {
+        let source = &self.sources[self.current_source.get()];
+        decry_path(path, &source.dir, &source.lay_dir)
+    }
+
     fn on_event(&self, event: OrganizeEvent) {
         if !(self.params.event_handler)(event) {
-            self.canceled.set(true);
+            self.canceled.store(true, Ordering::SeqCst);
         }
     }
 }
 
-/// Determines if a file is a photo by inspecting the extension
+/// Decides if a file should be organized and reads its date-times (and hash when `needs_hash`).
+/// This runs on the worker pool, so it must not raise events nor touch the organizer.
 #[doc(hidden)]
-fn is_photo_file(path: &Path) -> bool {
-    if path.exists() && path.is_file() {
-        if let Some(ext) = path.extension() {
-            if let Some(str_ext) = ext.to_str() {
-                match str_ext.to_lowercase().as_str() {
-                    "bmp" => return true,
-                    "gif" => return true,
-                    "jpg" => return true,
-                    "jpeg" => return true,
-                    "png" => return true,
-                    "tif" => return true,
-                    "tiff" => return true,
-                    "wmp" => return true,
-                    _ => return false,
-                }
-            }
+fn prepare_file(
+    file_path: &Path,
+    needs_hash: bool,
+    fix_extensions: bool,
+    protection: &ProtectionRules,
+    clock_corrections: &[ClockCorrection],
+    name_patterns: &[NamePattern],
+) -> anyhow::Result<PreparedFile> {
+    if !file_path.is_file() {
+        return Ok(PreparedFile::Skipped("File is not a photo.".into()));
+    }
+
+    // never touch a protected file, not even to read it
+    if let Some(reason) = protection.get_file_reason(file_path)? {
+        return Ok(PreparedFile::Skipped(reason.into()));
+    }
+
+    if is_partial_file(file_path) {
+        return Ok(PreparedFile::Skipped(
+            "File is a partial copy left by an interrupted run.".into(),
+        ));
+    }
+
+    // the contents decide what a file is; the extension is only trusted for sidecars, which have no signature
+    let extension_kind = get_file_kind(file_path);
+    let format = if extension_kind == Some(FileKind::Sidecar) {
+        None
+    } else {
+        detect_file_format(file_path)?
+    };
+
+    let (kind, fixed_extension) = match (format, extension_kind) {
+        (Some(format), extension_kind) if format.matches_extension(file_path) => {
+            (extension_kind.unwrap_or_else(|| format.kind()), None)
         }
+        (Some(format), _) => (format.kind(), fix_extensions.then(|| format.extension())),
+        (None, Some(FileKind::Sidecar)) => (FileKind::Sidecar, None),
+        (None, _) => return Ok(PreparedFile::Skipped("File is not a photo.".into())),
+    };
+
+    let hash = if needs_hash {
+        Some(get_file_hash(file_path)?)
+    } else {
+        None
+    };
+
+    if kind == FileKind::Sidecar {
+        return Ok(PreparedFile::Sidecar { hash });
     }
 
-    false
+    let mut date_time_info = PhotoDateTimeInfo::load(file_path)?;
+    date_time_info.read_name(file_path, name_patterns);
+    date_time_info.correct_clock(clock_corrections);
+
+    Ok(PreparedFile::Photo {
+        kind,
+        date_time_info: Box::new(date_time_info),
+        hash,
+        fixed_extension,
+    })
 }
 
+/// Groups the prepared files of a directory that share a name (ignoring extensions).
+/// The primary photo of a group is its first RAW photo, else its first photo, else its first video.
+/// Entries are in the order of their first file, so events stay in file order as much as possible.
 #[doc(hidden)]
-fn get_organized_photo_path(
-    file_path: &Path,
-    date_time: &chrono::DateTime<Utc>,
-    conflict: u32,
-    organized_dir: &Path,
-) -> anyhow::Result<PathBuf> {
-    // folder path is '(organized)year/month' (i.e. YYYY/MM)
-    let mut dest_path = PathBuf::from(organized_dir);
-    dest_path.push(PathBuf::from(date_time.format("%Y").to_string()).as_path());
-    dest_path.push(PathBuf::from(date_time.format("%m").to_string()).as_path());
-
-    // file name is 'year-month-day hour-minute-second-fractionOfSeconds conflict' (i.e. YYYY-MM-DD HH-MM-SS-FFFFFFFFF CCC)
-    let mut file_name = date_time.format("%Y-%m-%d %H-%M-%S-%f").to_string();
-
-    // add .jpg to the end so that set_extension doesn't overwrite the last part
-    if conflict > 0 {
-        file_name = format!("{} {:03}.jpg", file_name, conflict);
-    } else {
-        file_name = format!("{}.jpg", file_name);
+fn group_files<'a>(
+    files: &[&'a PathBuf],
+    prepared_files: Vec<anyhow::Result<PreparedFile>>,
+) -> Vec<PreparedEntry<'a>> {
+    enum PendingEntry<'a> {
+        File(&'a Path, anyhow::Result<PreparedFile>),
+        Members(Vec<(&'a Path, PreparedFile)>),
     }
 
-    dest_path.push(PathBuf::from(file_name).as_path());
+    let mut pending_entries: Vec<PendingEntry> = Vec::new();
+    let mut group_indexes: HashMap<OsString, usize> = HashMap::new();
+
+    for (file_path, prepared_file) in files.iter().zip(prepared_files) {
+        let file_path = file_path.as_path();
+        match (prepared_file, get_group_name(file_path)) {
+            (Ok(prepared_file @ PreparedFile::Photo { .. }), Some(name))
+            | (Ok(prepared_file @ PreparedFile::Sidecar { .. }), Some(name)) => match group_indexes
+                .get(&name)
+            {
+                Some(index) => {
+                    if let PendingEntry::Members(members) = &mut pending_entries[*index] {
+                        members.push((file_path, prepared_file));
+                    }
+                }
+                None => {
+                    group_indexes.insert(name, pending_entries.len());
+                    pending_entries.push(PendingEntry::Members(vec![(file_path, prepared_file)]));
+                }
+            },
+            (prepared_file, _) => {
+                pending_entries.push(PendingEntry::File(file_path, prepared_file))
+            }
+        }
+    }
 
-    // extension is maintained, but made lowercase for consistency
-    let extension = OsString::from(
-        file_path
-            .extension()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_lowercase(),
-    );
+    let mut entries = Vec::new();
+    for pending_entry in pending_entries {
+        let mut members = match pending_entry {
+            PendingEntry::File(file_path, prepared_file) => {
+                entries.push(PreparedEntry::File(file_path, prepared_file));
+                continue;
+            }
+            PendingEntry::Members(members) => members,
+        };
+
+        let primary_index = members
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (_, prepared_file))| match prepared_file {
+                PreparedFile::Photo { kind, .. } => Some((*kind, index)),
+                _ => None,
+            })
+            .min()
+            .map(|(_, index)| index);
+
+        // sidecars without a photo are organized (skipped) on their own
+        let primary_index = match primary_index {
+            Some(primary_index) => primary_index,
+            None => {
+                for (file_path, prepared_file) in members {
+                    entries.push(PreparedEntry::File(file_path, Ok(prepared_file)));
+                }
+                continue;
+            }
+        };
+
+        let (primary, date_time_info, hash, fixed_extension) = match members.remove(primary_index) {
+            (
+                primary,
+                PreparedFile::Photo {
+                    date_time_info,
+                    hash,
+                    fixed_extension,
+                    ..
+                },
+            ) => (primary, date_time_info, hash, fixed_extension),
+            _ => unreachable!("The primary file of a photo group is a photo."),
+        };
+
+        let companions = members
+            .into_iter()
+            .map(|(file_path, prepared_file)| match prepared_file {
+                PreparedFile::Photo {
+                    hash,
+                    fixed_extension,
+                    ..
+                } => Companion {
+                    file_path,
+                    hash,
+                    fixed_extension,
+                },
+                PreparedFile::Sidecar { hash } => Companion {
+                    file_path,
+                    hash,
+                    fixed_extension: None,
+                },
+                PreparedFile::Skipped(_) => unreachable!("Skipped files are not grouped."),
+            })
+            .collect();
+
+        entries.push(PreparedEntry::Group(PhotoGroup {
+            primary,
+            date_time_info,
+            hash,
+            fixed_extension,
+            companions,
+        }));
+    }
 
-    dest_path.set_extension(extension);
+    entries
+}
 
-    return Ok(dest_path);
+/// The name that the files of a group share: the file name without its extension.
+/// A sidecar may also be named after the full name of its photo (e.g. `IMG_0001.CR2.xmp`).
+#[doc(hidden)]
+fn get_group_name(file_path: &Path) -> Option<OsString> {
+    let (named_path, _) = split_sidecar_name(file_path);
+    Some(named_path.file_stem()?.to_os_string())
 }
 
+/// Splits a sidecar named after the full name of its photo (e.g. `IMG_0001.CR2.xmp`)
+/// into the photo's path and the sidecar's extension (made lowercase). Other files are not split.
 #[doc(hidden)]
-fn get_duplicate_photo_path(
-    file_path: &Path,
-    date_time: &chrono::DateTime<Utc>,
-    hash: &str,
-    conflict: u32,
-    duplicates_dir: &Path,
-) -> anyhow::Result<PathBuf> {
-    // folder path is (duplicates)year/month (i.e. YYYY/MM)
-    let mut dest_path = PathBuf::from(duplicates_dir);
-    dest_path.push(PathBuf::from(date_time.format("%Y").to_string()).as_path());
-    dest_path.push(PathBuf::from(date_time.format("%m").to_string()).as_path());
-
-    // file name is 'hash conflict'
-    let mut file_name = format!("{}", hash);
-
-    // add .jpg to the end so that set_extension doesn't overwrite the last part
-    if conflict > 0 {
-        file_name = format!("{}.{:03}.jpg", file_name, conflict);
-    } else {
-        file_name = format!("{}.jpg", file_name);
+fn split_sidecar_name(file_path: &Path) -> (PathBuf, Option<String>) {
+    if get_file_kind(file_path) == Some(FileKind::Sidecar) {
+        if let (Some(stem), Some(ext)) = (file_path.file_stem(), file_path.extension()) {
+            let named_path = file_path.with_file_name(stem);
+            if matches!(
+                get_file_kind(&named_path),
+                Some(FileKind::Raw | FileKind::Photo | FileKind::Video)
+            ) {
+                return (named_path, Some(ext.to_string_lossy().to_lowercase()));
+            }
+        }
     }
 
-    dest_path.push(PathBuf::from(file_name).as_path());
+    (file_path.to_path_buf(), None)
+}
 
-    // extension is maintained, but made lowercase for consistency
-    let extension = OsString::from(
-        file_path
-            .extension()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_lowercase(),
-    );
-    dest_path.set_extension(extension);
+/// Finds the sidecars of a photo: the sidecar files in its directory that share its name.
+#[doc(hidden)]
+fn find_sidecars(file_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let (dir, name) = match (file_path.parent(), get_group_name(file_path)) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut sidecars: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?
+        .into_iter()
+        .filter(|path| {
+            path.is_file()
+                && get_file_kind(path) == Some(FileKind::Sidecar)
+                && get_group_name(path).as_ref() == Some(&name)
+        })
+        .collect();
+    sidecars.sort();
+
+    Ok(sidecars)
+}
 
-    return Ok(dest_path);
+/// True if the path is a symbolic link (whether or not its target exists).
+#[doc(hidden)]
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Determines if a file is a photo (or video) by inspecting its contents
+#[doc(hidden)]
+fn is_photo_file(path: &Path) -> io::Result<bool> {
+    Ok(path.is_file() && detect_file_format(path)?.is_some())
 }
 
 /// Returns true if the files are the same length and the file hases are equal
+/// The hash of `x` is only computed when `x_hash` is not already known.
 #[doc(hidden)]
-fn are_same_file_contents(x: &Path, y: &Path) -> anyhow::Result<Option<String>> {
+fn are_same_file_contents(
+    x: &Path,
+    x_hash: Option<&str>,
+    y: &Path,
+) -> anyhow::Result<Option<String>> {
     let x_len = fs::metadata(x)?.len();
     let y_len = fs::metadata(y)?.len();
 
@@ -560,25 +2127,107 @@ fn are_same_file_contents(x: &Path, y: &Path) -> anyhow::Result<Option<String>>
         return Ok(None);
     }
 
-    let x_hash = get_file_hash(x)?;
+    let x_hash = match x_hash {
+        Some(x_hash) => x_hash.to_string(),
+        None => get_file_hash(x)?,
+    };
     let y_hash = get_file_hash(y)?;
 
     if x_hash != y_hash {
         return Ok(None);
     }
 
-    return Ok(Some(x_hash));
+    Ok(Some(x_hash))
+}
+
+#[doc(hidden)]
+impl GlobList {
+    /// Builds globs where `*` does not match `/`. A trailing `/` of a pattern is not part of its glob.
+    fn new(patterns: Vec<String>) -> anyhow::Result<GlobList> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            let glob = GlobBuilder::new(pattern.trim_end_matches('/'))
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid pattern '{}'", pattern))?;
+            builder.add(glob);
+        }
+
+        Ok(GlobList {
+            set: builder.build()?,
+            patterns,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Gets the first pattern that matches a relative path.
+    fn find_match(&self, relative_path: &Path) -> Option<&str> {
+        self.set
+            .matches(relative_path)
+            .first()
+            .map(|&i| self.patterns[i].as_str())
+    }
+}
+
+/// Gets the patterns that match files: those that do not end in `/`.
+#[doc(hidden)]
+fn get_file_patterns(patterns: &[&String]) -> Vec<String> {
+    patterns
+        .iter()
+        .filter(|pattern| !pattern.ends_with('/'))
+        .map(|pattern| pattern.to_string())
+        .collect()
+}
+
+/// Gets the patterns that match directories: those that end in `/`, and the others too when `all` is true.
+#[doc(hidden)]
+fn get_dir_patterns(patterns: &[&String], all: bool) -> Vec<String> {
+    patterns
+        .iter()
+        .filter(|pattern| all || pattern.ends_with('/'))
+        .map(|pattern| pattern.to_string())
+        .collect()
 }
 
+/// Reads the ignore file of a directory.
 #[doc(hidden)]
-fn move_file(from: &Path, to: &Path) -> io::Result<()> {
-    if let Some(to_dir) = to.parent() {
-        fs::create_dir_all(to_dir)?;
+fn load_ignore_file(dir: &Path, ignore_file: &Path) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(err) = builder.add(ignore_file) {
+        return Err(err.into());
     }
+    Ok(builder.build()?)
+}
+
+/// Finds the pattern (and its ignore file) that ignores a path. The ignore files are in order from
+/// the top directory down, and the deepest one that matches the path decides.
+#[doc(hidden)]
+fn find_ignore_match<'a>(
+    ignores: &[&'a Gitignore],
+    path: &Path,
+    is_dir: bool,
+) -> Option<(&'a str, &'a Path)> {
+    for ignore in ignores.iter().rev() {
+        match ignore.matched(path, is_dir) {
+            Match::Ignore(glob) => return Some((glob.original(), glob.from()?)),
+            Match::Whitelist(_) => return None,
+            Match::None => {}
+        }
+    }
+    None
+}
 
-    fs::rename(from, to)?;
+/// Canonicalizes a directory. While planning, a directory that does not exist yet is made absolute instead.
+#[doc(hidden)]
+fn canonicalize_dir(dir: &Path, plan: bool) -> io::Result<PathBuf> {
+    if plan && !dir.exists() {
+        return std::path::absolute(dir);
+    }
 
-    Ok(())
+    fs::canonicalize(dir)
 }
 
 /// The reverse of fs::canonicalize.  Returns the path with the lay base instead of the the cannonical base.
@@ -591,6 +2240,797 @@ fn decry_path(canonical_path: &Path, canonical_base: &Path, lay_base: &Path) ->
 }
 
 #[doc(hidden)]
-fn increment(cell: &Cell<u64>) {
-    cell.set(cell.get() + 1)
+fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn organize<F>(
+        unorganized_dir: &Path,
+        organized_dir: &Path,
+        duplicates_dir: &Path,
+        options: &OrganizeOptions,
+        event_handler: F,
+    ) -> anyhow::Result<OrganizeResult>
+    where
+        F: Fn(OrganizeEvent) -> bool,
+    {
+        organize_sources(
+            &[OrganizeSource::new(unorganized_dir)],
+            organized_dir,
+            duplicates_dir,
+            options,
+            event_handler,
+        )
+    }
+
+    fn organize_dir(dir: &TestDir, options: &OrganizeOptions) -> OrganizeResult {
+        organize(
+            &dir.join("in"),
+            &dir.join("out"),
+            &dir.join("dup"),
+            options,
+            |_| true,
+        )
+        .unwrap()
+    }
+
+    /// The contents of a JPEG photo: its magic bytes followed by `text`, so that photos can differ.
+    fn jpeg(text: &str) -> Vec<u8> {
+        let mut contents = b"\xff\xd8\xff\xe0".to_vec();
+        contents.extend_from_slice(text.as_bytes());
+        contents
+    }
+
+    /// Describes an event with the paths relative to `dir`, or None for events about progress.
+    fn describe_event(dir: &TestDir, event: &OrganizeEvent) -> Option<String> {
+        Some(match event {
+            OrganizeEvent::PhotoMoved { from, to, dated_by } => format!(
+                "moved {} -> {} ({})",
+                dir.relative(from),
+                dir.relative(to),
+                dated_by.label()
+            ),
+            OrganizeEvent::DuplicatePhotoMoved { from, to } => {
+                format!("duplicate {} -> {}", dir.relative(from), dir.relative(to))
+            }
+            OrganizeEvent::CompanionMoved { from, to } => {
+                format!("companion {} -> {}", dir.relative(from), dir.relative(to))
+            }
+            OrganizeEvent::PhotoNoOp { file } => format!("noop {}", dir.relative(file)),
+            OrganizeEvent::FileSkipped { file, reason } => {
+                format!("skipped {} ({})", dir.relative(file), reason)
+            }
+            OrganizeEvent::DirSkipped {
+                dir: skipped,
+                reason,
+            } => {
+                format!("skipped dir {} ({})", dir.relative(skipped), reason)
+            }
+            OrganizeEvent::FileError { file, error } => {
+                format!("error {} ({})", dir.relative(file), error)
+            }
+            OrganizeEvent::ExtensionFixed { file, extension } => {
+                format!("extension {} -> .{}", dir.relative(file), extension)
+            }
+            OrganizeEvent::FileWarning { file, warning } => {
+                format!("warning {} ({})", dir.relative(file), warning)
+            }
+            OrganizeEvent::CopyFallback { file, .. } => format!("fallback {}", dir.relative(file)),
+            OrganizeEvent::LinkRemoved { link } => format!("unlinked {}", dir.relative(link)),
+            _ => return None,
+        })
+    }
+
+    /// Organizes `in` into `out` (and `dup`), giving the result and the described events.
+    fn organize_events(dir: &TestDir, options: &OrganizeOptions) -> (OrganizeResult, Vec<String>) {
+        let events = std::sync::Mutex::new(Vec::new());
+        let result = organize(
+            &dir.join("in"),
+            &dir.join("out"),
+            &dir.join("dup"),
+            options,
+            |event| {
+                if let Some(description) = describe_event(dir, &event) {
+                    events.lock().unwrap().push(description);
+                }
+                true
+            },
+        )
+        .unwrap();
+
+        (result, events.into_inner().unwrap())
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    if path.is_dir() {
+                        count_files(&path)
+                    } else {
+                        1
+                    }
+                })
+                .sum(),
+            Err(_) => 0,
+        }
+    }
+
+    #[test]
+    fn copying_the_same_photos_again_leaves_them_alone() {
+        for hash_index in [false, true] {
+            let dir = TestDir::new("organize-copy-again");
+            dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+            dir.write("in/IMG-20210704-WA0001.xmp", b"sidecar");
+            let options = OrganizeOptions {
+                mode: TransferMode::Copy,
+                hash_index: hash_index.then(|| dir.join("index.jsonl")),
+                ..Default::default()
+            };
+
+            let first = organize_dir(&dir, &options);
+            let second = organize_dir(&dir, &options);
+
+            assert_eq!(first.photos_moved, 1);
+            assert_eq!(first.companions_moved, 1);
+            assert_eq!(second.photos_moved, 0);
+            assert_eq!(second.photos_noop, 2);
+            assert_eq!(second.duplicate_photos_moved, 0);
+            assert_eq!(count_files(&dir.join("out")), 2);
+            assert_eq!(count_files(&dir.join("dup")), 0);
+            assert_eq!(count_files(&dir.join("in")), 2);
+        }
+    }
+
+    #[test]
+    fn moving_a_copy_of_an_organized_photo_is_a_duplicate() {
+        let dir = TestDir::new("organize-move-again");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        let options = OrganizeOptions::default();
+
+        organize_dir(&dir, &options);
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        let second = organize_dir(&dir, &options);
+
+        assert_eq!(second.duplicate_photos_moved, 1);
+        assert_eq!(count_files(&dir.join("out")), 1);
+        assert_eq!(count_files(&dir.join("dup")), 1);
+        assert_eq!(count_files(&dir.join("in")), 0);
+    }
+
+    #[test]
+    fn companions_are_counted_apart_from_photos() {
+        let dir = TestDir::new("organize-companions");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        dir.write("in/IMG-20210704-WA0001.xmp", b"sidecar");
+        dir.write("in/IMG-20210704-WA0001.aae", b"edits");
+        dir.write("in/IMG-20210705-WA0001.jpg", &jpeg("another photo"));
+        let companions_moved = AtomicU64::new(0);
+
+        let result = organize(
+            &dir.join("in"),
+            &dir.join("out"),
+            &dir.join("dup"),
+            &OrganizeOptions::default(),
+            |event| {
+                if let OrganizeEvent::CompanionMoved { .. } = event {
+                    increment(&companions_moved);
+                }
+                true
+            },
+        )
+        .unwrap();
+
+        assert_eq!(result.photos_moved, 2);
+        assert_eq!(result.dated_by.values().sum::<u64>(), 2);
+        assert_eq!(result.companions_moved, 2);
+        assert_eq!(companions_moved.load(Ordering::SeqCst), 2);
+        assert_eq!(count_files(&dir.join("out")), 4);
+
+        // the companions of a duplicate are not counted as duplicates either
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        dir.write("in/IMG-20210704-WA0001.xmp", b"sidecar");
+        let result = organize_dir(&dir, &OrganizeOptions::default());
+
+        assert_eq!(result.duplicate_photos_moved, 1);
+        assert_eq!(result.companions_moved, 1);
+        assert_eq!(count_files(&dir.join("dup")), 2);
+    }
+
+    #[test]
+    fn a_plan_leaves_the_files_alone_and_reports_what_a_run_does() {
+        let write_photos = |dir: &TestDir| {
+            dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo 1"));
+            dir.write("in/IMG-20210704-WA0001.xmp", b"sidecar");
+            // the same date as photo 1, so it gets the next conflict number
+            dir.write("in/IMG-20210704-WA0002.jpg", &jpeg("photo 2"));
+            // the same contents as photo 1, so it is a duplicate
+            dir.write("in/copies/IMG-20210704-WA0003.jpg", &jpeg("photo 1"));
+            dir.write("in/notes.txt", b"not a photo");
+        };
+        let plan_dir = TestDir::new("organize-plan");
+        write_photos(&plan_dir);
+        let run_dir = TestDir::new("organize-run");
+        write_photos(&run_dir);
+        let files = plan_dir.files();
+
+        let (plan_result, plan_events) = organize_events(
+            &plan_dir,
+            &OrganizeOptions {
+                plan: true,
+                ..Default::default()
+            },
+        );
+        let (run_result, run_events) = organize_events(&run_dir, &OrganizeOptions::default());
+
+        assert_eq!(plan_dir.files(), files);
+        assert_eq!(plan_events, run_events);
+        assert_eq!(plan_result.photos_moved, 2);
+        assert_eq!(plan_result.photos_moved, run_result.photos_moved);
+        assert_eq!(plan_result.companions_moved, run_result.companions_moved);
+        assert_eq!(plan_result.duplicate_photos_moved, 1);
+        assert_eq!(plan_result.files_skipped, run_result.files_skipped);
+
+        // every destination of the plan is where the run put a file
+        let destinations: Vec<&str> = plan_events
+            .iter()
+            .filter_map(|event| event.split(" -> ").nth(1))
+            .map(|to| to.split(" (").next().unwrap())
+            .collect();
+        assert_eq!(destinations.len(), 4);
+        let run_files = run_dir.files();
+        assert!(destinations
+            .iter()
+            .all(|to| run_files.iter().any(|file| file == to)));
+    }
+
+    #[test]
+    fn any_number_of_threads_organizes_the_same_way() {
+        let organize_with_threads = |threads: usize| {
+            let dir = TestDir::new("organize-threads");
+            for day in 1..=3 {
+                for number in 1..=4 {
+                    let name = format!("IMG-202107{:02}-WA{:04}", day, number);
+                    let photo_dir = format!("in/2021/07/{:02}", day);
+                    dir.write(&format!("{}/{}.jpg", photo_dir, name), &jpeg(&name));
+                    // a copy of the first photo of the day in a deeper directory
+                    if number == 1 {
+                        dir.write(&format!("{}/copies/{}.jpg", photo_dir, name), &jpeg(&name));
+                    }
+                }
+                dir.write(
+                    &format!("in/2021/07/{:02}/IMG-202107{:02}-WA0002.xmp", day, day),
+                    b"sidecar",
+                );
+            }
+            dir.write("in/2021/.photisoignore", b"*.tmp\n");
+            dir.write("in/2021/07/01/photo.tmp", &jpeg("ignored"));
+            dir.write("in/notes.txt", b"not a photo");
+
+            let events = std::sync::Mutex::new(Vec::new());
+            let result = organize(
+                &dir.join("in"),
+                &dir.join("out"),
+                &dir.join("dup"),
+                &OrganizeOptions {
+                    threads,
+                    ..Default::default()
+                },
+                |event| {
+                    let description = match &event {
+                        OrganizeEvent::DirStarted { dir: started } => {
+                            Some(format!("dir {}", dir.relative(started)))
+                        }
+                        OrganizeEvent::DirFinished => Some("dir finished".to_string()),
+                        _ => describe_event(&dir, &event),
+                    };
+                    // the reasons name the test directory, which differs between the runs
+                    let description = description.map(|description| {
+                        description.replace(&*dir.join("").to_string_lossy(), "")
+                    });
+                    events.lock().unwrap().extend(description);
+                    true
+                },
+            )
+            .unwrap();
+
+            (result, events.into_inner().unwrap(), dir.files())
+        };
+
+        let (result, events, files) = organize_with_threads(1);
+        let (threaded_result, threaded_events, threaded_files) = organize_with_threads(4);
+
+        assert_eq!(result.photos_moved, 12);
+        assert_eq!(result.duplicate_photos_moved, 3);
+        assert_eq!(result.companions_moved, 3);
+        assert_eq!(result.dirs, 9);
+        assert_eq!(threaded_events, events);
+        assert_eq!(threaded_files, files);
+        assert_eq!(threaded_result.files, result.files);
+        assert_eq!(threaded_result.files_skipped, result.files_skipped);
+    }
+
+    #[test]
+    fn organizing_in_place_keeps_the_organized_copy_of_a_photo() {
+        let dir = TestDir::new("organize-in-place");
+        dir.write(
+            "in/2021/07/2021-07-04 00-00-00-000000000.jpg",
+            &jpeg("photo"),
+        );
+        dir.write("in/unsorted/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        let options = OrganizeOptions {
+            hash_index: Some(dir.join("index.jsonl")),
+            ..Default::default()
+        };
+
+        let result = organize(
+            &dir.join("in"),
+            &dir.join("in"),
+            &dir.join("dup"),
+            &options,
+            |_| true,
+        )
+        .unwrap();
+
+        assert_eq!(result.photos_noop, 1);
+        assert_eq!(result.duplicate_photos_moved, 1);
+        assert!(dir
+            .join("in/2021/07/2021-07-04 00-00-00-000000000.jpg")
+            .is_file());
+        assert!(!dir.join("in/unsorted/IMG-20210704-WA0001.jpg").exists());
+    }
+
+    #[test]
+    fn a_changed_photo_is_hashed_again() {
+        let dir = TestDir::new("organize-index-stale");
+        let organized_path = dir.write(
+            "out/2021/07/2021-07-04 00-00-00-000000000.jpg",
+            &jpeg("photo"),
+        );
+        fs::create_dir_all(dir.join("in")).unwrap();
+        let options = OrganizeOptions {
+            hash_index: Some(dir.join("index.jsonl")),
+            ..Default::default()
+        };
+        organize_dir(&dir, &options);
+
+        // the indexed photo is replaced, so its entry no longer holds
+        fs::write(&organized_path, jpeg("another photo")).unwrap();
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        let (result, events) = organize_events(&dir, &options);
+
+        assert_eq!(result.duplicate_photos_moved, 0);
+        assert_eq!(
+            events,
+            ["moved in/IMG-20210704-WA0001.jpg -> out/2021/07/2021-07-04 00-00-00-000000000 001.jpg (file name)"]
+        );
+    }
+
+    #[test]
+    fn the_hash_index_is_reused_by_later_runs() {
+        let dir = TestDir::new("organize-index-reuse");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        let options = OrganizeOptions {
+            hash_index: Some(dir.join("index.jsonl")),
+            ..Default::default()
+        };
+        organize_dir(&dir, &options);
+
+        // contents of the same size and modified time are not hashed again, so the photo keeps its indexed hash
+        let organized_path = dir.join("out/2021/07/2021-07-04 00-00-00-000000000.jpg");
+        let modified = fs::metadata(&organized_path).unwrap().modified().unwrap();
+        fs::write(&organized_path, jpeg("PHOTO")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&organized_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        // a copy with another date is only found through the index
+        dir.write("in/IMG-20220101-WA0001.jpg", &jpeg("photo"));
+        let (result, events) = organize_events(&dir, &options);
+
+        assert_eq!(result.duplicate_photos_moved, 1);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("duplicate in/IMG-20220101-WA0001.jpg -> dup/"));
+    }
+
+    #[test]
+    fn files_are_known_by_their_contents_and_their_extensions_fixed() {
+        let dir = TestDir::new("organize-fix-extensions");
+        dir.write("in/IMG-20210704-WA0001.png", &jpeg("photo"));
+        // an MPEG-4 video may be named .mov, which is not fixed
+        dir.write(
+            "in/IMG-20210704-WA0002.mov",
+            b"\0\0\0\x20ftypisom\0\0\x02\0",
+        );
+        dir.write("in/IMG-20210704-WA0003.jpg", b"not a photo");
+        dir.write("in/IMG-20210704-WA0003.xmp", b"sidecar");
+        let options = OrganizeOptions {
+            fix_extensions: true,
+            ..Default::default()
+        };
+
+        let (result, events) = organize_events(&dir, &options);
+
+        assert_eq!(result.extensions_fixed, 1);
+        assert_eq!(
+            events,
+            [
+                "extension in/IMG-20210704-WA0001.png -> .jpg",
+                "moved in/IMG-20210704-WA0001.png -> out/2021/07/2021-07-04 00-00-00-000000000.jpg (file name)",
+                "moved in/IMG-20210704-WA0002.mov -> out/2021/07/2021-07-04 00-00-00-000000000.mov (file name)",
+                "skipped in/IMG-20210704-WA0003.jpg (File is not a photo.)",
+                "skipped in/IMG-20210704-WA0003.xmp (Sidecar file does not have a photo.)",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_hash_index_holds_the_photos_known_by_their_contents() {
+        let dir = TestDir::new("organize-index-contents");
+        dir.write("out/2021/07/photo.dat", &jpeg("photo"));
+        dir.write("out/2021/07/notes.jpg", b"not a photo");
+        fs::create_dir_all(dir.join("in")).unwrap();
+        let options = OrganizeOptions {
+            hash_index: Some(dir.join("index.jsonl")),
+            ..Default::default()
+        };
+
+        organize_dir(&dir, &options);
+
+        let index = HashIndex::load(&dir.join("index.jsonl")).unwrap();
+        let photo_hash = get_file_hash(&dir.join("out/2021/07/photo.dat")).unwrap();
+        let notes_hash = get_file_hash(&dir.join("out/2021/07/notes.jpg")).unwrap();
+        assert_eq!(index.find_hash(&photo_hash).count(), 1);
+        assert_eq!(index.find_hash(&notes_hash).count(), 0);
+    }
+
+    #[test]
+    fn a_raw_photo_moves_with_its_jpeg_and_sidecar() {
+        let dir = TestDir::new("organize-group");
+        dir.write("in/IMG-20210704-WA0001.cr2", b"II*\0\x10\0\0\0CR\x02\0raw");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("jpeg"));
+        dir.write("in/IMG-20210704-WA0001.xmp", b"sidecar");
+
+        let (result, events) = organize_events(&dir, &OrganizeOptions::default());
+
+        assert_eq!(result.photos_moved, 1);
+        assert_eq!(result.companions_moved, 2);
+        assert_eq!(
+            events,
+            [
+                "moved in/IMG-20210704-WA0001.cr2 -> out/2021/07/2021-07-04 00-00-00-000000000.cr2 (file name)",
+                "companion in/IMG-20210704-WA0001.jpg -> out/2021/07/2021-07-04 00-00-00-000000000.jpg",
+                "companion in/IMG-20210704-WA0001.xmp -> out/2021/07/2021-07-04 00-00-00-000000000.xmp",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_group_shares_its_conflict_number_and_its_duplicate() {
+        let dir = TestDir::new("organize-group-conflict");
+        dir.write(
+            "out/2021/07/2021-07-04 00-00-00-000000000.jpg",
+            &jpeg("other photo"),
+        );
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        dir.write("in/IMG-20210704-WA0001.xmp", b"sidecar");
+        dir.write("in/copies/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+        dir.write("in/copies/IMG-20210704-WA0001.jpg.xmp", b"sidecar");
+
+        let (result, events) = organize_events(&dir, &OrganizeOptions::default());
+
+        assert_eq!(result.photos_moved, 1);
+        assert_eq!(result.duplicate_photos_moved, 1);
+        assert_eq!(result.companions_moved, 2);
+        let duplicate =
+            "dup/2021/07/3FBEE07DF86C3F1D34A5C04C66C5CACC585C91D7FC9E901E4A2B505C44E84953.jpg";
+        assert_eq!(
+            events,
+            [
+                "moved in/IMG-20210704-WA0001.jpg -> out/2021/07/2021-07-04 00-00-00-000000000 001.jpg (file name)"
+                    .to_string(),
+                "companion in/IMG-20210704-WA0001.xmp -> out/2021/07/2021-07-04 00-00-00-000000000 001.xmp"
+                    .to_string(),
+                format!("duplicate in/copies/IMG-20210704-WA0001.jpg -> {}", duplicate),
+                format!("companion in/copies/IMG-20210704-WA0001.jpg.xmp -> {}.xmp", duplicate),
+            ]
+        );
+    }
+
+    #[test]
+    fn copying_deletes_the_originals_only_when_asked() {
+        for delete_source in [false, true] {
+            let dir = TestDir::new("organize-copy-delete");
+            dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+            let options = OrganizeOptions {
+                mode: TransferMode::Copy,
+                delete_source,
+                ..Default::default()
+            };
+
+            let result = organize_dir(&dir, &options);
+
+            assert_eq!(result.photos_moved, 1);
+            let mut files = vec!["out/2021/07/2021-07-04 00-00-00-000000000.jpg"];
+            if !delete_source {
+                files.insert(0, "in/IMG-20210704-WA0001.jpg");
+            }
+            assert_eq!(dir.files(), files);
+            assert_eq!(
+                fs::read(dir.join(files[files.len() - 1])).unwrap(),
+                jpeg("photo")
+            );
+        }
+    }
+
+    #[test]
+    fn links_fall_back_to_copies_with_an_event() {
+        // whether the file system of the test directories supports reflinks decides if there is a fallback
+        let probe_dir = TestDir::new("organize-link-probe");
+        let probe = probe_dir.write("a", b"probe");
+        let reflinks_fall_back =
+            link_file(TransferMode::Reflink, &probe, &probe_dir.join("b"), None)
+                .unwrap()
+                .is_some();
+
+        for mode in [TransferMode::HardLink, TransferMode::Reflink] {
+            let dir = TestDir::new("organize-link");
+            dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo"));
+            let options = OrganizeOptions {
+                mode,
+                ..Default::default()
+            };
+
+            let (result, events) = organize_events(&dir, &options);
+
+            let mut expected = vec![
+                "moved in/IMG-20210704-WA0001.jpg -> out/2021/07/2021-07-04 00-00-00-000000000.jpg (file name)",
+            ];
+            if mode == TransferMode::Reflink && reflinks_fall_back {
+                expected.insert(0, "fallback in/IMG-20210704-WA0001.jpg");
+            }
+            assert_eq!(events, expected, "{:?}", mode);
+            assert_eq!(result.photos_moved, 1);
+            assert_eq!(
+                dir.files(),
+                [
+                    "in/IMG-20210704-WA0001.jpg",
+                    "out/2021/07/2021-07-04 00-00-00-000000000.jpg"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn organizing_a_symlink_view_again_refreshes_it() {
+        let dir = TestDir::new("organize-symlink-refresh");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo 1"));
+        dir.write("in/IMG-20210705-WA0001.jpg", &jpeg("photo 2"));
+        let options = OrganizeOptions {
+            mode: TransferMode::Symlink,
+            ..Default::default()
+        };
+        organize_dir(&dir, &options);
+
+        // one photo is deleted and another is added
+        fs::remove_file(dir.join("in/IMG-20210704-WA0001.jpg")).unwrap();
+        dir.write("in/IMG-20210706-WA0001.jpg", &jpeg("photo 3"));
+        let (result, events) = organize_events(&dir, &options);
+
+        assert_eq!(result.links_removed, 1);
+        assert_eq!(result.photos_noop, 1);
+        assert_eq!(result.photos_moved, 1);
+        assert_eq!(
+            events,
+            [
+                "unlinked out/2021/07/2021-07-04 00-00-00-000000000.jpg",
+                "noop in/IMG-20210705-WA0001.jpg",
+                "moved in/IMG-20210706-WA0001.jpg -> out/2021/07/2021-07-06 00-00-00-000000000.jpg (file name)",
+            ]
+        );
+        assert_eq!(
+            dir.files(),
+            [
+                "in/IMG-20210705-WA0001.jpg",
+                "in/IMG-20210706-WA0001.jpg",
+                "out/2021/07/2021-07-05 00-00-00-000000000.jpg",
+                "out/2021/07/2021-07-06 00-00-00-000000000.jpg",
+            ]
+        );
+        assert_eq!(
+            fs::read(dir.join("out/2021/07/2021-07-06 00-00-00-000000000.jpg")).unwrap(),
+            jpeg("photo 3")
+        );
+    }
+
+    #[test]
+    fn several_sources_are_organized_in_order_with_their_own_counts() {
+        let dir = TestDir::new("organize-sources");
+        dir.write("phone/IMG-20210704-WA0001.jpg", &jpeg("photo 1"));
+        dir.write("phone/IMG-20210704-WA0002.jpg", &jpeg("photo 2"));
+        // a source inside another source is organized on its own
+        dir.write("phone/backup/IMG-20210705-WA0001.jpg", &jpeg("photo 3"));
+        dir.write("card/IMG-20210704-WA0003.jpg", &jpeg("photo 1"));
+        // the card is not organized recursively
+        dir.write("card/DCIM/IMG-20210706-WA0001.jpg", &jpeg("photo 4"));
+        let sources = [
+            OrganizeSource::new(&dir.join("phone")),
+            OrganizeSource {
+                recursive: false,
+                ..OrganizeSource::new(&dir.join("card"))
+            },
+            OrganizeSource::new(&dir.join("phone/backup")),
+        ];
+
+        let events = std::sync::Mutex::new(Vec::new());
+        let result = organize_sources(
+            &sources,
+            &dir.join("out"),
+            &dir.join("dup"),
+            &OrganizeOptions::default(),
+            |event| {
+                let description = match &event {
+                    OrganizeEvent::SourceStarted { source } => {
+                        Some(format!("source {}", dir.relative(source)))
+                    }
+                    _ => describe_event(&dir, &event),
+                };
+                events.lock().unwrap().extend(description);
+                true
+            },
+        )
+        .unwrap();
+
+        let counts: Vec<(String, u64, u64, u64)> = result
+            .sources
+            .iter()
+            .map(|source| {
+                (
+                    dir.relative(&source.source),
+                    source.result.files,
+                    source.result.photos_moved,
+                    source.result.duplicate_photos_moved,
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("phone".to_string(), 2, 2, 0),
+                ("card".to_string(), 1, 0, 1),
+                ("phone/backup".to_string(), 1, 1, 0),
+            ]
+        );
+        assert_eq!(result.photos_moved, 3);
+        assert_eq!(result.duplicate_photos_moved, 1);
+        assert_eq!(
+            events.into_inner().unwrap(),
+            [
+                "source phone",
+                "moved phone/IMG-20210704-WA0001.jpg -> out/2021/07/2021-07-04 00-00-00-000000000.jpg (file name)",
+                "moved phone/IMG-20210704-WA0002.jpg -> out/2021/07/2021-07-04 00-00-00-000000000 001.jpg (file name)",
+                "skipped dir phone/backup (Directory is another unorganized directory.)",
+                "source card",
+                "duplicate card/IMG-20210704-WA0003.jpg -> dup/2021/07/FD06D1208EDE59FDD44481E8187657424A50CF8DAE482325C2A2272BE8078D1D.jpg",
+                "source phone/backup",
+                "moved phone/backup/IMG-20210705-WA0001.jpg -> out/2021/07/2021-07-05 00-00-00-000000000.jpg (file name)",
+            ]
+        );
+    }
+
+    #[test]
+    fn files_are_skipped_by_globs_and_ignore_files_with_their_reason() {
+        let dir = TestDir::new("organize-globs");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo 1"));
+        dir.write("in/2021/IMG-20210704-WA0002.jpg", &jpeg("photo 2"));
+        dir.write("in/2021/IMG-20210704-WA0003.png", &jpeg("photo 3"));
+        dir.write("in/2021/IMG-20210704-WA0004.jpg", &jpeg("photo 4"));
+        dir.write("in/2021/private/IMG-20210704-WA0005.jpg", &jpeg("photo 5"));
+        dir.write("in/2021/old/IMG-20210704-WA0006.jpg", &jpeg("photo 6"));
+        dir.write("in/2021/.photisoignore", b"*-WA0004.jpg\nold/\n");
+        let sources = [OrganizeSource {
+            include: vec!["2021/".to_string()],
+            exclude: vec!["**/private/".to_string()],
+            ..OrganizeSource::new(&dir.join("in"))
+        }];
+        let options = OrganizeOptions {
+            exclude: vec!["**/*.png".to_string()],
+            ..Default::default()
+        };
+
+        let events = std::sync::Mutex::new(Vec::new());
+        let result = organize_sources(
+            &sources,
+            &dir.join("out"),
+            &dir.join("dup"),
+            &options,
+            |event| {
+                // the reasons name the ignore file by its full path
+                let description = describe_event(&dir, &event)
+                    .map(|description| description.replace(&*dir.join("").to_string_lossy(), ""));
+                events.lock().unwrap().extend(description);
+                true
+            },
+        )
+        .unwrap();
+
+        assert_eq!(result.photos_moved, 1);
+        assert_eq!(result.dirs_skipped, 2);
+        assert_eq!(
+            events.into_inner().unwrap(),
+            [
+                "skipped in/IMG-20210704-WA0001.jpg (File is not in a directory that matches an include pattern.)",
+                "skipped in/2021/.photisoignore (File is an ignore file.)",
+                "moved in/2021/IMG-20210704-WA0002.jpg -> out/2021/07/2021-07-04 00-00-00-000000000.jpg (file name)",
+                "skipped in/2021/IMG-20210704-WA0003.png (File matches the exclude pattern '**/*.png'.)",
+                "skipped in/2021/IMG-20210704-WA0004.jpg (File is ignored by '*-WA0004.jpg' in \"in/2021/.photisoignore\".)",
+                "skipped dir in/2021/old (Directory is ignored by 'old/' in \"in/2021/.photisoignore\".)",
+                "skipped dir in/2021/private (Directory matches the exclude pattern '**/private/'.)",
+            ]
+        );
+    }
+    #[test]
+    fn protected_files_and_directories_are_left_alone() {
+        let dir = TestDir::new("organize-protection");
+        dir.write("in/IMG-20210704-WA0001.jpg", &jpeg("photo 1"));
+        dir.write("in/IMG-20210704-WA0002!.jpg", &jpeg("photo 2"));
+        let read_only_path = dir.write("in/IMG-20210704-WA0003.jpg", &jpeg("photo 3"));
+        let xattr_path = dir.write("in/IMG-20210704-WA0004.jpg", &jpeg("photo 4"));
+        dir.write("in/kept/.photiso-keep", b"");
+        dir.write("in/kept/IMG-20210704-WA0005.jpg", &jpeg("photo 5"));
+
+        // the permission bits decide, so this holds even for root
+        let mut permissions = fs::metadata(&read_only_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&read_only_path, permissions).unwrap();
+
+        // file systems without user extended attributes cannot protect files by them
+        #[cfg(target_os = "linux")]
+        let has_xattr = {
+            use std::{ffi::CString, os::unix::ffi::OsStrExt};
+            let c_path = CString::new(xattr_path.as_os_str().as_bytes()).unwrap();
+            let c_name = CString::new("user.photiso.keep").unwrap();
+            // safety: both strings are nul terminated, and the empty value is not read
+            let result =
+                unsafe { libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null(), 0, 0) };
+            result == 0
+        };
+        #[cfg(not(target_os = "linux"))]
+        let has_xattr = false;
+
+        let options = OrganizeOptions {
+            protection: ProtectionRules {
+                xattrs: vec!["user.photiso.keep".to_string()],
+                read_only: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let (_, events) = organize_events(&dir, &options);
+
+        let mut expected = vec![
+            "moved in/IMG-20210704-WA0001.jpg -> out/2021/07/2021-07-04 00-00-00-000000000.jpg (file name)",
+            "skipped in/IMG-20210704-WA0002!.jpg (File is protected: its name contains '!'.)",
+            "skipped in/IMG-20210704-WA0003.jpg (File is protected: it is read-only.)",
+            "skipped in/IMG-20210704-WA0004.jpg (File is protected: it has the extended attribute 'user.photiso.keep'.)",
+            "skipped dir in/kept (Directory is protected: it contains '.photiso-keep'.)",
+        ];
+        if !has_xattr {
+            expected[3] = "moved in/IMG-20210704-WA0004.jpg -> out/2021/07/2021-07-04 00-00-00-000000000 001.jpg (file name)";
+        }
+        assert_eq!(events, expected);
+        assert!(read_only_path.exists());
+        assert_eq!(xattr_path.exists(), has_xattr);
+        assert!(dir.join("in/kept/IMG-20210704-WA0005.jpg").exists());
+    }
 }
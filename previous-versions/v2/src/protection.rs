@@ -0,0 +1,99 @@
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+
+/// Rules that protect files from being organized. A protected file is left alone and reported as skipped.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ProtectionRules {
+    /// A file whose name (without its extension) contains one of these is protected.
+    pub stem_markers: Vec<String>,
+    /// A directory that contains a file with one of these names is protected, with everything in it.
+    pub marker_files: Vec<String>,
+    /// A file with one of these extended attributes (e.g. `user.photiso.keep`) is protected.
+    /// Extended attributes are only read on Linux.
+    pub xattrs: Vec<String>,
+    /// When true, a file that is read-only is protected.
+    pub read_only: bool,
+}
+
+impl Default for ProtectionRules {
+    fn default() -> Self {
+        ProtectionRules {
+            stem_markers: vec!["!".to_string()],
+            marker_files: vec![".photiso-keep".to_string()],
+            xattrs: Vec::new(),
+            read_only: false,
+        }
+    }
+}
+
+impl ProtectionRules {
+    /// Gets why a file is protected (by its name, extended attributes, or read-only bit), if it is.
+    /// The marker files of its directory are not checked.
+    pub fn get_file_reason(&self, file_path: &Path) -> io::Result<Option<String>> {
+        // names that are not UTF-8 are compared as well as they can be, rather than failing
+        let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+        if let Some(marker) = self
+            .stem_markers
+            .iter()
+            .find(|marker| !marker.is_empty() && stem.contains(marker.as_str()))
+        {
+            return Ok(Some(format!(
+                "File is protected: its name contains '{}'.",
+                marker
+            )));
+        }
+
+        for xattr in &self.xattrs {
+            if has_xattr(file_path, xattr)? {
+                return Ok(Some(format!(
+                    "File is protected: it has the extended attribute '{}'.",
+                    xattr
+                )));
+            }
+        }
+
+        if self.read_only && fs::symlink_metadata(file_path)?.permissions().readonly() {
+            return Ok(Some("File is protected: it is read-only.".to_string()));
+        }
+
+        Ok(None)
+    }
+
+    /// Gets the marker file that protects a directory, if it has one.
+    pub fn find_marker_file(&self, dir: &Path) -> Option<&str> {
+        self.marker_files
+            .iter()
+            .find(|marker_file| !marker_file.is_empty() && dir.join(marker_file).is_file())
+            .map(|marker_file| marker_file.as_str())
+    }
+}
+
+/// Checks if a file (not the target of a symbolic link) has an extended attribute.
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+fn has_xattr(path: &Path, name: &str) -> io::Result<bool> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+
+    // safety: both strings are nul terminated, and a size of 0 only asks for the size of the value
+    let size =
+        unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size >= 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[doc(hidden)]
+fn has_xattr(_path: &Path, _name: &str) -> io::Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,334 @@
+use crate::iso_bmff::*;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// The user type of the Canon CR3 `uuid` box that holds the CMT metadata boxes.
+const CR3_UUID: [u8; 16] = [
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+
+/// The largest metadata block that is read into memory.
+const MAX_METADATA_LEN: u64 = 16 * 1024 * 1024;
+
+/// Reads the EXIF information of RAW formats that are not plain TIFF files.
+///
+/// TIFF-based RAW files (CR2, NEF, ARW, DNG) are read by the exif crate directly. This reads:
+///
+/// * CR3 - the CMT1 (IFD0) and CMT2 (EXIF IFD) boxes of the Canon `uuid` box.
+/// * ORF - a TIFF file with an Olympus signature in place of the TIFF signature.
+/// * RAF - the JPEG preview embedded after the Fujifilm header.
+///
+/// The most specific EXIF information is first. Files in other formats have no EXIF information.
+pub fn read_raw_exif<R>(reader: &mut R) -> anyhow::Result<Vec<exif::Exif>>
+where
+    R: Read + Seek,
+{
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut head = [0u8; 16];
+    if len < 16 {
+        return Ok(Vec::new());
+    }
+    reader.read_exact(&mut head)?;
+
+    let exifreader = exif::Reader::new();
+    let mut exifs = Vec::new();
+
+    if &head[4..12] == b"ftypcrx " {
+        for raw_exif in find_cr3_exif(reader, len)? {
+            if let Ok(exif) = exifreader.read_raw(raw_exif) {
+                exifs.push(exif);
+            }
+        }
+    } else if &head[0..4] == b"IIRO" || &head[0..4] == b"IIRS" || &head[0..4] == b"MMOR" {
+        let mut raw_exif = Vec::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut raw_exif)?;
+
+        // replace the Olympus signature with the TIFF signature
+        if raw_exif[0] == b'I' {
+            raw_exif[2..4].copy_from_slice(&[0x2a, 0x00]);
+        } else {
+            raw_exif[2..4].copy_from_slice(&[0x00, 0x2a]);
+        }
+
+        if let Ok(exif) = exifreader.read_raw(raw_exif) {
+            exifs.push(exif);
+        }
+    } else if &head[0..16] == b"FUJIFILMCCD-RAW " {
+        if let Some(jpeg) = find_raf_jpeg(reader, len)? {
+            if let Ok(exif) = exifreader.read_from_container(&mut Cursor::new(jpeg)) {
+                exifs.push(exif);
+            }
+        }
+    }
+
+    Ok(exifs)
+}
+
+/// Finds the CMT2 (EXIF IFD) and CMT1 (IFD0) TIFF data in a CR3 file.
+#[doc(hidden)]
+fn find_cr3_exif<R>(reader: &mut R, len: u64) -> anyhow::Result<Vec<Vec<u8>>>
+where
+    R: Read + Seek,
+{
+    let mut cmt1 = None;
+    let mut cmt2 = None;
+
+    let mut position = 0;
+    while let Some(header) = read_box_header(reader, position, len)? {
+        if &header.box_type == b"moov" {
+            let mut child_position = header.payload_start;
+            while let Some(child) = read_box_header(reader, child_position, header.end)? {
+                if &child.box_type == b"uuid" {
+                    let mut uuid = [0u8; 16];
+                    reader.read_exact(&mut uuid)?;
+                    if uuid == CR3_UUID {
+                        let mut metadata_position = child.payload_start + 16;
+                        while let Some(metadata) =
+                            read_box_header(reader, metadata_position, child.end)?
+                        {
+                            match &metadata.box_type {
+                                b"CMT1" => {
+                                    cmt1 = read_box_payload(reader, &metadata, MAX_METADATA_LEN)?
+                                }
+                                b"CMT2" => {
+                                    cmt2 = read_box_payload(reader, &metadata, MAX_METADATA_LEN)?
+                                }
+                                _ => {}
+                            }
+                            metadata_position = metadata.end;
+                        }
+                    }
+                }
+                child_position = child.end;
+            }
+        }
+        position = header.end;
+    }
+
+    let mut raw_exifs = Vec::new();
+    if let Some(cmt2) = cmt2.and_then(|cmt2| wrap_exif_ifd(&cmt2)) {
+        raw_exifs.push(cmt2);
+    }
+    if let Some(cmt1) = cmt1 {
+        raw_exifs.push(cmt1);
+    }
+
+    Ok(raw_exifs)
+}
+
+/// CMT2 is a TIFF whose first IFD is an EXIF IFD. The exif crate only finds EXIF tags through
+/// the EXIF IFD pointer of IFD0, so this appends an IFD0 that points to the original first IFD.
+#[doc(hidden)]
+fn wrap_exif_ifd(tiff: &[u8]) -> Option<Vec<u8>> {
+    if tiff.len() < 8 {
+        return None;
+    }
+
+    let little_endian = match &tiff[0..4] {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+
+    let write_u16 = |value: u16| {
+        if little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    };
+    let write_u32 = |value: u32| {
+        if little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    };
+
+    let exif_ifd_offset = [tiff[4], tiff[5], tiff[6], tiff[7]];
+
+    // IFDs start on a word boundary
+    let mut wrapped = tiff.to_vec();
+    if wrapped.len() % 2 == 1 {
+        wrapped.push(0);
+    }
+    let ifd0_offset = wrapped.len() as u32;
+
+    // one entry: ExifIFDPointer (0x8769), LONG (4), count 1, the original first IFD
+    wrapped.extend_from_slice(&write_u16(1));
+    wrapped.extend_from_slice(&write_u16(0x8769));
+    wrapped.extend_from_slice(&write_u16(4));
+    wrapped.extend_from_slice(&write_u32(1));
+    wrapped.extend_from_slice(&exif_ifd_offset);
+    wrapped.extend_from_slice(&write_u32(0));
+
+    wrapped[4..8].copy_from_slice(&write_u32(ifd0_offset));
+
+    Some(wrapped)
+}
+
+/// Finds the JPEG preview in a RAF file. Its offset and length follow the RAF header.
+#[doc(hidden)]
+fn find_raf_jpeg<R>(reader: &mut R, len: u64) -> anyhow::Result<Option<Vec<u8>>>
+where
+    R: Read + Seek,
+{
+    if len < 92 {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::Start(84))?;
+    let mut location = [0u8; 8];
+    reader.read_exact(&mut location)?;
+
+    let offset = u32::from_be_bytes([location[0], location[1], location[2], location[3]]) as u64;
+    let jpeg_len = u32::from_be_bytes([location[4], location[5], location[6], location[7]]) as u64;
+
+    if offset + jpeg_len > len || jpeg_len > MAX_METADATA_LEN {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut jpeg = vec![0u8; jpeg_len as usize];
+    reader.read_exact(&mut jpeg)?;
+
+    Ok(Some(jpeg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_exif::*;
+    use exif::{In, Tag};
+
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn read(file: &[u8]) -> Vec<exif::Exif> {
+        read_raw_exif(&mut Cursor::new(file)).unwrap()
+    }
+
+    /// The ASCII value of a tag in the first EXIF information that has it.
+    fn ascii_value(exifs: &[exif::Exif], tag: Tag) -> Option<String> {
+        exifs
+            .iter()
+            .find_map(|exif| match &exif.get_field(tag, In::PRIMARY)?.value {
+                exif::Value::Ascii(values) => Some(String::from_utf8_lossy(values.first()?).into()),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn reads_cr3_metadata_boxes() {
+        let cmt1 = tiff_exif(true, &[(MAKE, "Canon"), (MODEL, "Canon EOS R5")], &[]);
+        let cmt2 = tiff_exif(true, &[(DATE_TIME_ORIGINAL, "2021:03:04 05:06:07")], &[]);
+        let mut uuid = CR3_UUID.to_vec();
+        uuid.extend(mp4_box(b"CMT1", &cmt1));
+        uuid.extend(mp4_box(b"CMT2", &cmt2));
+
+        let mut file = mp4_box(b"ftyp", b"crx \0\0\0\x01crx isom");
+        file.extend(mp4_box(b"moov", &mp4_box(b"uuid", &uuid)));
+        file.extend(mp4_box(b"mdat", &[0; 8]));
+
+        let exifs = read(&file);
+
+        assert_eq!(exifs.len(), 2);
+        assert_eq!(
+            ascii_value(&exifs, Tag::DateTimeOriginal).as_deref(),
+            Some("2021:03:04 05:06:07")
+        );
+        assert_eq!(ascii_value(&exifs, Tag::Make).as_deref(), Some("Canon"));
+    }
+
+    #[test]
+    fn skips_cr3_boxes_of_other_uuids() {
+        let mut uuid = [0u8; 16].to_vec();
+        uuid.extend(mp4_box(b"CMT1", &tiff_exif(true, &[(MAKE, "Canon")], &[])));
+        let mut file = mp4_box(b"ftyp", b"crx \0\0\0\x01crx isom");
+        file.extend(mp4_box(b"moov", &mp4_box(b"uuid", &uuid)));
+
+        assert!(read(&file).is_empty());
+    }
+
+    #[test]
+    fn reads_orf_files() {
+        for little_endian in [true, false] {
+            let mut file = tiff_exif(
+                little_endian,
+                &[(MAKE, "OLYMPUS")],
+                &[(DATE_TIME_ORIGINAL, "2021:03:04 05:06:07")],
+            );
+            file[0..4].copy_from_slice(if little_endian { b"IIRO" } else { b"MMOR" });
+
+            let exifs = read(&file);
+
+            assert_eq!(
+                ascii_value(&exifs, Tag::DateTimeOriginal).as_deref(),
+                Some("2021:03:04 05:06:07")
+            );
+        }
+    }
+
+    /// Builds a RAF file whose JPEG preview holds the TIFF data, at `offset` with length `len` (if given).
+    fn raf_file(tiff: &[u8], location: Option<(u32, u32)>) -> Vec<u8> {
+        let mut jpeg = b"\xff\xd8\xff\xe1".to_vec();
+        jpeg.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(tiff);
+        jpeg.extend_from_slice(b"\xff\xd9");
+
+        let mut file = b"FUJIFILMCCD-RAW 0201FF383501".to_vec();
+        file.resize(84, 0);
+        let (offset, len) = location.unwrap_or((100, jpeg.len() as u32));
+        file.extend_from_slice(&offset.to_be_bytes());
+        file.extend_from_slice(&len.to_be_bytes());
+        file.resize(100, 0);
+        file.extend(jpeg);
+        file
+    }
+
+    #[test]
+    fn reads_the_raf_jpeg_preview() {
+        let tiff = tiff_with_date_time_original("2021:03:04 05:06:07");
+
+        let exifs = read(&raf_file(&tiff, None));
+
+        assert_eq!(
+            ascii_value(&exifs, Tag::DateTimeOriginal).as_deref(),
+            Some("2021:03:04 05:06:07")
+        );
+    }
+
+    #[test]
+    fn a_raf_preview_past_the_end_is_not_read() {
+        let tiff = tiff_with_date_time_original("2021:03:04 05:06:07");
+
+        assert!(read(&raf_file(&tiff, Some((100, 100_000)))).is_empty());
+        assert!(read(&raf_file(&tiff, Some((u32::MAX, u32::MAX)))).is_empty());
+        assert!(read(&raf_file(&tiff, None)[..90]).is_empty());
+    }
+
+    #[test]
+    fn wraps_an_exif_ifd_in_an_ifd0() {
+        assert!(wrap_exif_ifd(b"II*\0").is_none());
+        assert!(wrap_exif_ifd(b"XX*\0\x08\0\0\0").is_none());
+
+        let tiff = tiff_exif(false, &[(DATE_TIME_ORIGINAL, "2021:03:04 05:06:07")], &[]);
+        let wrapped = wrap_exif_ifd(&tiff).unwrap();
+        let exif = exif::Reader::new().read_raw(wrapped).unwrap();
+
+        assert!(exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).is_some());
+    }
+
+    #[test]
+    fn other_files_have_no_raw_exif() {
+        assert!(read(b"").is_empty());
+        assert!(read(b"\xff\xd8\xff\xe0 a jpeg, not a raw").is_empty());
+    }
+}
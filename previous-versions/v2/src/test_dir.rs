@@ -0,0 +1,80 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of files for a test, which is removed when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Creates an empty directory, unique to the test (`name`) and the test run.
+    pub fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!(
+            "photiso-test-{}-{}-{}",
+            name,
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TestDir { path }
+    }
+
+    /// The path of a file in the directory.
+    pub fn join(&self, relative_path: &str) -> PathBuf {
+        self.path.join(relative_path)
+    }
+
+    /// Writes a file (and its directories) in the directory, returning its path.
+    pub fn write(&self, relative_path: &str, contents: &[u8]) -> PathBuf {
+        let path = self.join(relative_path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    /// The path of a file in the directory relative to it, with `/` separators (e.g. `out/2021/07/a.jpg`).
+    pub fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.path)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    /// Every file (or link) in the directory, relative to it, in order.
+    pub fn files(&self) -> Vec<String> {
+        fn add_files(dir: &Path, files: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() && !path.is_symlink() {
+                    add_files(&path, files);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        let mut files = Vec::new();
+        add_files(&self.path, &mut files);
+        let mut files: Vec<String> = files.iter().map(|file| self.relative(file)).collect();
+        files.sort();
+
+        files
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,85 @@
+/// The TIFF tags used by tests.
+pub const MAKE: u16 = 0x010f;
+pub const MODEL: u16 = 0x0110;
+pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+
+/// Builds a TIFF (raw EXIF) block of ASCII values: `ifd0` in the first IFD, and `exif_ifd` in an EXIF IFD
+/// that the first IFD points to (when there are any).
+pub fn tiff_exif(little_endian: bool, ifd0: &[(u16, &str)], exif_ifd: &[(u16, &str)]) -> Vec<u8> {
+    let u16_bytes = |value: u16| {
+        if little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    };
+    let u32_bytes = |value: u32| {
+        if little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    };
+
+    let ifd0_count = ifd0.len() + if exif_ifd.is_empty() { 0 } else { 1 };
+    let exif_ifd_offset = 8 + 2 + 12 * ifd0_count + 4;
+    let mut data_offset = exif_ifd_offset;
+    if !exif_ifd.is_empty() {
+        data_offset += 2 + 12 * exif_ifd.len() + 4;
+    }
+
+    let mut tiff = if little_endian {
+        b"II*\0".to_vec()
+    } else {
+        b"MM\0*".to_vec()
+    };
+    tiff.extend_from_slice(&u32_bytes(8));
+
+    let mut data = Vec::new();
+    let mut write_ifd = |tiff: &mut Vec<u8>, entries: &[(u16, &str)], exif_ifd_pointer: bool| {
+        let count = entries.len() + if exif_ifd_pointer { 1 } else { 0 };
+        tiff.extend_from_slice(&u16_bytes(count as u16));
+        for (tag, value) in entries {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            tiff.extend_from_slice(&u16_bytes(*tag));
+            tiff.extend_from_slice(&u16_bytes(ASCII));
+            tiff.extend_from_slice(&u32_bytes(value.len() as u32));
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                tiff.extend_from_slice(&value);
+            } else {
+                tiff.extend_from_slice(&u32_bytes((data_offset + data.len()) as u32));
+                data.extend_from_slice(&value);
+            }
+        }
+        if exif_ifd_pointer {
+            tiff.extend_from_slice(&u16_bytes(EXIF_IFD_POINTER));
+            tiff.extend_from_slice(&u16_bytes(LONG));
+            tiff.extend_from_slice(&u32_bytes(1));
+            tiff.extend_from_slice(&u32_bytes(exif_ifd_offset as u32));
+        }
+        tiff.extend_from_slice(&u32_bytes(0));
+    };
+
+    write_ifd(&mut tiff, ifd0, !exif_ifd.is_empty());
+    if !exif_ifd.is_empty() {
+        write_ifd(&mut tiff, exif_ifd, false);
+    }
+    tiff.extend(data);
+
+    tiff
+}
+
+/// Builds a TIFF block with the date-time the photo was taken (e.g. `2019:07:04 12:34:56`).
+pub fn tiff_with_date_time_original(date_time: &str) -> Vec<u8> {
+    tiff_exif(
+        false,
+        &[(MAKE, "Canon"), (MODEL, "Canon EOS R5")],
+        &[(DATE_TIME_ORIGINAL, date_time)],
+    )
+}
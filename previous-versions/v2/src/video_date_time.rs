@@ -0,0 +1,533 @@
+use crate::capture_date_time::*;
+use crate::iso_bmff::*;
+use chrono::TimeZone;
+use std::io::{Read, Seek, SeekFrom};
+
+#[doc(hidden)]
+pub use chrono::{DateTime, Utc};
+
+/// The largest box that is read into memory (e.g. keys, ilst). Larger boxes are skipped.
+const MAX_BOX_LEN: u64 = 1024 * 1024;
+
+/// Seconds between 1904-01-01 (the ISO-BMFF epoch) and 1970-01-01 (the Unix epoch).
+const ISO_BMFF_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Date-time information found in a video container
+#[derive(Default)]
+pub struct VideoDateTimeInfo {
+    /// The QuickTime `com.apple.quicktime.creationdate` metadata (local time where the video was taken)
+    pub metadata_created: Option<CaptureDateTime>,
+    /// The creation time of the movie header (`mvhd`), in UTC
+    pub movie_created: Option<CaptureDateTime>,
+    /// The creation time of the first track header (`tkhd`) that has one, in UTC
+    pub track_created: Option<CaptureDateTime>,
+}
+
+impl VideoDateTimeInfo {
+    /// Loads the date-times from an ISO-BMFF (mp4, mov, m4v, 3gp) or AVI container.
+    /// Files in other formats have no video date-times.
+    pub fn load<R>(reader: &mut R) -> anyhow::Result<VideoDateTimeInfo>
+    where
+        R: Read + Seek,
+    {
+        let mut info = VideoDateTimeInfo::default();
+
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut head = [0u8; 12];
+        if len < 12 {
+            return Ok(info);
+        }
+        reader.read_exact(&mut head)?;
+
+        if &head[0..4] == b"RIFF" && &head[8..12] == b"AVI " {
+            info.metadata_created = read_avi_date_time(reader, 12, len)?;
+        } else if is_iso_bmff(&head) {
+            read_iso_bmff_boxes(reader, 0, len, &mut info, &mut Vec::new())?;
+        }
+
+        Ok(info)
+    }
+}
+
+/// Tests if the file starts with a box that only ISO-BMFF containers start with.
+#[doc(hidden)]
+fn is_iso_bmff(head: &[u8; 12]) -> bool {
+    matches!(
+        &head[4..8],
+        b"ftyp" | b"moov" | b"mdat" | b"wide" | b"free" | b"skip"
+    )
+}
+
+// -------------------- ISO-BMFF (QuickTime, MP4) -------------------- //
+
+/// Walks the boxes between `start` and `end`, descending into the containers that hold dates.
+/// `keys` holds the metadata keys of the most recent `keys` box, which `ilst` values refer to.
+#[doc(hidden)]
+fn read_iso_bmff_boxes<R>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    info: &mut VideoDateTimeInfo,
+    keys: &mut Vec<String>,
+) -> anyhow::Result<()>
+where
+    R: Read + Seek,
+{
+    let mut position = start;
+    while let Some(header) = read_box_header(reader, position, end)? {
+        match &header.box_type {
+            b"moov" | b"trak" | b"udta" => {
+                read_iso_bmff_boxes(reader, header.payload_start, header.end, info, keys)?;
+            }
+            b"meta" => {
+                // an MP4 meta box has a version and flags before its children, a QuickTime meta box does not
+                let mut children_start = header.payload_start;
+                if let Some(child) = read_box_header(reader, children_start, header.end)? {
+                    if &child.box_type != b"hdlr" {
+                        children_start += 4;
+                    }
+                }
+                read_iso_bmff_boxes(reader, children_start, header.end, info, keys)?;
+            }
+            b"mvhd" => {
+                if let Some(payload) = read_box_payload(reader, &header, MAX_BOX_LEN)? {
+                    info.movie_created = info.movie_created.or(parse_header_created(&payload));
+                }
+            }
+            b"tkhd" => {
+                if let Some(payload) = read_box_payload(reader, &header, MAX_BOX_LEN)? {
+                    info.track_created = info.track_created.or(parse_header_created(&payload));
+                }
+            }
+            b"keys" => {
+                if let Some(payload) = read_box_payload(reader, &header, MAX_BOX_LEN)? {
+                    *keys = parse_keys(&payload);
+                }
+            }
+            b"ilst" => {
+                if let Some(payload) = read_box_payload(reader, &header, MAX_BOX_LEN)? {
+                    if let Some(created) = parse_ilst_creation_date(&payload, keys) {
+                        info.metadata_created = info.metadata_created.or(Some(created));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        position = header.end;
+    }
+
+    Ok(())
+}
+
+/// Parses the creation time of a `mvhd` or `tkhd` payload (seconds since 1904-01-01 UTC).
+/// A creation time of 0 means it was never set.
+#[doc(hidden)]
+fn parse_header_created(payload: &[u8]) -> Option<CaptureDateTime> {
+    let version = *payload.first()?;
+
+    let seconds = if version == 1 {
+        read_u64_checked(payload, 4)?
+    } else {
+        read_u32_checked(payload, 4)? as u64
+    };
+
+    if seconds == 0 {
+        return None;
+    }
+
+    Utc.timestamp_opt(seconds as i64 - ISO_BMFF_EPOCH_OFFSET, 0)
+        .single()
+        .map(CaptureDateTime::from_instant)
+}
+
+/// Parses the QuickTime `keys` payload into the list of key names (1-based in `ilst`).
+#[doc(hidden)]
+fn parse_keys(payload: &[u8]) -> Vec<String> {
+    let mut keys = Vec::new();
+
+    let count = match read_u32_checked(payload, 4) {
+        Some(count) => count,
+        None => return keys,
+    };
+
+    let mut position = 8;
+    for _ in 0..count {
+        let size = match read_u32_checked(payload, position) {
+            Some(size) if size >= 8 => size as usize,
+            _ => break,
+        };
+
+        match payload.get(position + 8..position + size) {
+            Some(name) => keys.push(String::from_utf8_lossy(name).to_string()),
+            None => break,
+        }
+
+        position += size;
+    }
+
+    keys
+}
+
+/// Finds the `com.apple.quicktime.creationdate` value in the QuickTime `ilst` payload.
+#[doc(hidden)]
+fn parse_ilst_creation_date(payload: &[u8], keys: &[String]) -> Option<CaptureDateTime> {
+    let mut position = 0;
+    while let Some(size) = read_u32_checked(payload, position) {
+        let size = size as usize;
+        if size < 8 || position + size > payload.len() {
+            break;
+        }
+
+        // items are named by their 1-based index into the keys
+        let key_index = read_u32(payload, position + 4) as usize;
+        let is_creation_date = key_index > 0
+            && keys.get(key_index - 1).map(|key| key.as_str())
+                == Some("com.apple.quicktime.creationdate");
+
+        if is_creation_date {
+            // the item holds a 'data' box: size, 'data', type, locale, value
+            let item = &payload[position + 8..position + size];
+            if item.len() > 16 && &item[4..8] == b"data" {
+                let data_size = (read_u32(item, 0) as usize).min(item.len());
+                let value = String::from_utf8_lossy(&item[16..data_size]);
+                return parse_creation_date(value.trim());
+            }
+        }
+
+        position += size;
+    }
+
+    None
+}
+
+/// Parses a QuickTime creation date such as `2019-07-04T12:34:56-0700`: the local time, and its offset if any.
+#[doc(hidden)]
+fn parse_creation_date(value: &str) -> Option<CaptureDateTime> {
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f%z",
+        "%Y-%m-%dT%H:%M:%S%z",
+        "%Y-%m-%dT%H:%M:%S%:z",
+    ] {
+        if let Ok(date_time) = DateTime::parse_from_str(value, format) {
+            return Some(CaptureDateTime::new(
+                date_time.naive_local(),
+                Some(*date_time.offset()),
+            ));
+        }
+    }
+
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S"] {
+        if let Ok(date_time) = chrono::NaiveDateTime::parse_from_str(value, format) {
+            return Some(CaptureDateTime::new(date_time, None));
+        }
+    }
+
+    None
+}
+
+// -------------------- AVI (RIFF) -------------------- //
+
+/// Finds the `IDIT` (digitization date) chunk in the `hdrl` list of an AVI file.
+#[doc(hidden)]
+fn read_avi_date_time<R>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+) -> anyhow::Result<Option<CaptureDateTime>>
+where
+    R: Read + Seek,
+{
+    let mut position = start;
+    while position + 8 <= end {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+
+        let chunk_id = &header[0..4];
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        let payload_start = position + 8;
+        let chunk_end = (payload_start + size).min(end);
+
+        if chunk_id == b"LIST" {
+            let mut list_type = [0u8; 4];
+            reader.read_exact(&mut list_type)?;
+            if &list_type == b"hdrl" {
+                return read_avi_date_time(reader, payload_start + 4, chunk_end);
+            }
+        } else if chunk_id == b"IDIT" && size <= MAX_BOX_LEN {
+            let mut value = vec![0u8; size as usize];
+            reader.read_exact(&mut value)?;
+            let value = String::from_utf8_lossy(&value);
+            return Ok(parse_avi_date_time(
+                value.trim_matches(|c: char| c == '\0' || c.is_whitespace()),
+            ));
+        }
+
+        // chunks are padded to an even size
+        position = payload_start + size + (size % 2);
+    }
+
+    Ok(None)
+}
+
+/// Parses an AVI date such as `MON JAN 02 03:04:05 2006` (ctime format).
+#[doc(hidden)]
+fn parse_avi_date_time(value: &str) -> Option<CaptureDateTime> {
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    for format in [
+        "%a %b %d %H:%M:%S %Y",
+        "%Y:%m:%d %H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+    ] {
+        if let Ok(date_time) = chrono::NaiveDateTime::parse_from_str(&value, format) {
+            return Some(CaptureDateTime::new(date_time, None));
+        }
+    }
+
+    None
+}
+
+// -------------------- Byte helpers -------------------- //
+
+#[doc(hidden)]
+fn read_u32(bytes: &[u8], position: usize) -> u32 {
+    read_u32_checked(bytes, position).unwrap_or(0)
+}
+
+#[doc(hidden)]
+fn read_u32_checked(bytes: &[u8], position: usize) -> Option<u32> {
+    let bytes = bytes.get(position..position + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[doc(hidden)]
+fn read_u64_checked(bytes: &[u8], position: usize) -> Option<u64> {
+    let bytes = bytes.get(position..position + 8)?;
+    let mut value = [0u8; 8];
+    value.copy_from_slice(bytes);
+    Some(u64::from_be_bytes(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, NaiveDate};
+    use std::io::Cursor;
+
+    /// Builds a box of the type with the payload.
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Builds a `mvhd` or `tkhd` box with a creation time in seconds since 1904.
+    fn header_box(box_type: &[u8; 4], version: u8, created: u64) -> Vec<u8> {
+        let mut payload = vec![version, 0, 0, 0];
+        if version == 1 {
+            payload.extend_from_slice(&created.to_be_bytes());
+            payload.extend_from_slice(&created.to_be_bytes());
+        } else {
+            payload.extend_from_slice(&(created as u32).to_be_bytes());
+            payload.extend_from_slice(&(created as u32).to_be_bytes());
+        }
+        payload.extend_from_slice(&[0; 80]);
+        mp4_box(box_type, &payload)
+    }
+
+    /// Builds a QuickTime `meta` box with a `com.apple.quicktime.creationdate` value.
+    fn creation_date_box(value: &str) -> Vec<u8> {
+        let hdlr = mp4_box(b"hdlr", &[0; 24]);
+
+        let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        keys.extend(mp4_box(b"mdta", b"com.apple.quicktime.make"));
+        keys.extend(mp4_box(b"mdta", b"com.apple.quicktime.creationdate"));
+
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        data.extend_from_slice(value.as_bytes());
+        let mut ilst = mp4_box(
+            &1u32.to_be_bytes(),
+            &mp4_box(b"data", b"\0\0\0\x01\0\0\0\0Apple"),
+        );
+        ilst.extend(mp4_box(&2u32.to_be_bytes(), &mp4_box(b"data", &data)));
+
+        let mut meta = hdlr;
+        meta.extend(mp4_box(b"keys", &keys));
+        meta.extend(mp4_box(b"ilst", &ilst));
+        mp4_box(b"meta", &meta)
+    }
+
+    /// Builds an MP4 file with the boxes in its `moov` box.
+    fn mp4_file(moov_children: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = mp4_box(b"ftyp", b"isom\0\0\x02\0isomiso2mp41");
+        file.extend(mp4_box(b"moov", &moov_children.concat()));
+        file.extend(mp4_box(b"mdat", &[0; 16]));
+        file
+    }
+
+    fn load(bytes: &[u8]) -> VideoDateTimeInfo {
+        VideoDateTimeInfo::load(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    /// Seconds since 1904-01-01 UTC.
+    fn seconds_since_1904(date_time: &DateTime<Utc>) -> u64 {
+        (date_time.timestamp() + ISO_BMFF_EPOCH_OFFSET) as u64
+    }
+
+    #[test]
+    fn reads_version_0_movie_and_track_headers() {
+        let movie = Utc.with_ymd_and_hms(2019, 7, 4, 19, 34, 56).unwrap();
+        let track = Utc.with_ymd_and_hms(2019, 7, 4, 19, 35, 0).unwrap();
+        let trak = mp4_box(b"trak", &header_box(b"tkhd", 0, seconds_since_1904(&track)));
+
+        let info = load(&mp4_file(&[
+            header_box(b"mvhd", 0, seconds_since_1904(&movie)),
+            trak,
+        ]));
+
+        assert_eq!(
+            info.movie_created.and_then(|created| created.to_utc()),
+            Some(movie)
+        );
+        assert_eq!(
+            info.track_created.and_then(|created| created.to_utc()),
+            Some(track)
+        );
+        assert!(info.metadata_created.is_none());
+    }
+
+    #[test]
+    fn reads_version_1_movie_headers() {
+        // after 2040, when 32-bit creation times run out
+        let movie = Utc.with_ymd_and_hms(2045, 1, 2, 3, 4, 5).unwrap();
+
+        let info = load(&mp4_file(&[header_box(
+            b"mvhd",
+            1,
+            seconds_since_1904(&movie),
+        )]));
+
+        assert_eq!(
+            info.movie_created.and_then(|created| created.to_utc()),
+            Some(movie)
+        );
+    }
+
+    #[test]
+    fn converts_from_the_1904_epoch() {
+        let info = load(&mp4_file(&[header_box(
+            b"mvhd",
+            0,
+            ISO_BMFF_EPOCH_OFFSET as u64,
+        )]));
+
+        assert_eq!(
+            info.movie_created.and_then(|created| created.to_utc()),
+            Some(Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn a_zero_creation_time_is_not_set() {
+        let info = load(&mp4_file(&[
+            header_box(b"mvhd", 0, 0),
+            mp4_box(b"trak", &header_box(b"tkhd", 1, 0)),
+        ]));
+
+        assert!(info.movie_created.is_none());
+        assert!(info.track_created.is_none());
+    }
+
+    #[test]
+    fn reads_the_quicktime_creation_date() {
+        let info = load(&mp4_file(&[
+            header_box(b"mvhd", 0, 0),
+            creation_date_box("2019-07-04T12:34:56-0700"),
+        ]));
+
+        let created = info.metadata_created.unwrap();
+        assert_eq!(
+            created.local,
+            NaiveDate::from_ymd_opt(2019, 7, 4)
+                .unwrap()
+                .and_hms_opt(12, 34, 56)
+                .unwrap()
+        );
+        assert_eq!(created.offset, FixedOffset::west_opt(7 * 3600));
+    }
+
+    #[test]
+    fn reads_a_quicktime_creation_date_without_an_offset() {
+        let info = load(&mp4_file(&[mp4_box(
+            b"udta",
+            &creation_date_box("2019-07-04T12:34:56"),
+        )]));
+
+        let created = info.metadata_created.unwrap();
+        assert_eq!(created.local.to_string(), "2019-07-04 12:34:56");
+        assert!(created.offset.is_none());
+    }
+
+    #[test]
+    fn truncated_and_oversized_boxes_have_no_date_times() {
+        let movie = Utc.with_ymd_and_hms(2019, 7, 4, 19, 34, 56).unwrap();
+        let file = mp4_file(&[header_box(b"mvhd", 0, seconds_since_1904(&movie))]);
+
+        // cut off in the middle of the movie header
+        let info = load(&file[..40]);
+        assert!(info.movie_created.is_none());
+
+        // a movie header that claims to be larger than the file
+        let mut oversized = file.clone();
+        let mvhd_start = file.windows(4).position(|w| w == b"mvhd").unwrap() - 4;
+        oversized[mvhd_start..mvhd_start + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let info = load(&oversized);
+        assert!(info.movie_created.is_none());
+
+        // a movie header too short to hold a creation time
+        let info = load(&mp4_file(&[mp4_box(b"mvhd", &[1, 0, 0, 0, 0])]));
+        assert!(info.movie_created.is_none());
+
+        // a 64-bit box size that overflows
+        let mut huge = mp4_box(b"ftyp", b"isom");
+        huge.extend_from_slice(b"\0\0\0\x01moov\xff\xff\xff\xff\xff\xff\xff\xf0");
+        let info = load(&huge);
+        assert!(info.movie_created.is_none());
+    }
+
+    #[test]
+    fn reads_the_avi_digitization_date() {
+        let mut idit = b"IDIT".to_vec();
+        idit.extend_from_slice(&26u32.to_le_bytes());
+        idit.extend_from_slice(b"MON JAN 02 03:04:05 2006\n\0");
+        let mut hdrl = b"LIST".to_vec();
+        hdrl.extend_from_slice(&((idit.len() + 4) as u32).to_le_bytes());
+        hdrl.extend_from_slice(b"hdrl");
+        hdrl.extend(idit);
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&((hdrl.len() + 4) as u32).to_le_bytes());
+        file.extend_from_slice(b"AVI ");
+        file.extend(hdrl);
+
+        let info = load(&file);
+
+        assert_eq!(
+            info.metadata_created
+                .map(|created| created.local.to_string()),
+            Some("2006-01-02 03:04:05".to_string())
+        );
+    }
+
+    #[test]
+    fn other_files_have_no_date_times() {
+        let info = load(b"\xff\xd8\xff\xe0 not a video at all");
+
+        assert!(info.metadata_created.is_none());
+        assert!(info.movie_created.is_none());
+        assert!(info.track_created.is_none());
+    }
+}